@@ -0,0 +1,164 @@
+// Files sent with the messages: what is accepted, how it is named and the thumbnails of the images
+use std::io::Cursor;
+
+use anyhow::{Context, Result};
+use image::{ImageFormat, ImageReader, Limits};
+
+use crate::schema::Attachment;
+
+/// Files per message
+pub const MAX_ATTACHMENTS: usize = 4;
+/// 10 MiB per file, overridden by ATTACHMENT_MAX_BYTES
+pub const DEFAULT_MAX_BYTES: usize = 10 * 1024 * 1024;
+pub const THUMBNAIL_CONTENT_TYPE: &str = "image/png";
+const THUMBNAIL_SIZE: u32 = 256;
+// A small file can announce a huge picture, decoding stops past these
+const MAX_IMAGE_DIMENSION: u32 = 8192;
+const MAX_DECODE_BYTES: u64 = 256 * 1024 * 1024;
+const MAX_FILENAME_CHARS: usize = 255;
+
+/// Type of a file from its first bytes, `None` when it is not one we accept
+/// What the browser announces is not trusted: it is what the file is served as
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    match image::guess_format(bytes) {
+        Ok(ImageFormat::Png) => return Some("image/png"),
+        Ok(ImageFormat::Jpeg) => return Some("image/jpeg"),
+        Ok(ImageFormat::Gif) => return Some("image/gif"),
+        Ok(ImageFormat::WebP) => return Some("image/webp"),
+        _ => {}
+    }
+    if bytes.starts_with(b"%PDF-") {
+        return Some("application/pdf");
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !text.contains('\0') => Some("text/plain; charset=utf-8"),
+        _ => None,
+    }
+}
+
+pub fn is_image(content_type: &str) -> bool {
+    content_type.starts_with("image/")
+}
+
+/// PNG of the image shrunk to fit the thumbnail box, the ratio kept
+/// Slow on big pictures, to be run on the blocking pool
+pub fn thumbnail(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits);
+    let image = reader.decode().context("Failed to decode image")?;
+    let mut png = Vec::new();
+    image
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .context("Failed to encode thumbnail")?;
+    Ok(png)
+}
+
+/// The name of the file without its path, quotes and control characters
+pub fn sanitize_filename(filename: &str) -> String {
+    let name = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(MAX_FILENAME_CHARS)
+        .collect::<String>();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        return "file".to_string();
+    }
+    name.to_string()
+}
+
+/// Images open in the browser, everything else is downloaded under its name
+/// The ASCII `filename` is for old clients, `filename*` keeps the real name
+pub fn content_disposition(attachment: &Attachment) -> String {
+    let disposition = if is_image(&attachment.content_type) {
+        "inline"
+    } else {
+        "attachment"
+    };
+    let ascii: String = attachment
+        .filename
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    let mut encoded = String::new();
+    for byte in attachment.filename.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition, ascii, encoded
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView, RgbImage};
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_sniff_trusts_the_bytes_only() {
+        assert_eq!(sniff(&png(1, 1)), Some("image/png"));
+        assert_eq!(sniff(b"%PDF-1.7 ..."), Some("application/pdf"));
+        assert_eq!(
+            sniff("notes, café".as_bytes()),
+            Some("text/plain; charset=utf-8")
+        );
+        // An executable, or html that a browser would run
+        assert_eq!(sniff(b"\x7fELF\x02\x01\x01\0\0\0"), None);
+        assert_eq!(sniff(b"<svg onload=alert(1)>\0"), None);
+    }
+
+    #[test]
+    fn test_thumbnail_fits_the_box_and_keeps_the_ratio() {
+        let thumbnail = image::load_from_memory(&thumbnail(&png(1024, 512)).unwrap()).unwrap();
+
+        assert_eq!(thumbnail.dimensions(), (256, 128));
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("C:\\Users\\me\\cat.png"), "cat.png");
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("a\"b\r\n.txt"), "ab.txt");
+        assert_eq!(sanitize_filename(".."), "file");
+        assert_eq!(sanitize_filename(""), "file");
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        let attachment = Attachment {
+            attachment_id: Uuid::nil(),
+            filename: "café menu.pdf".to_string(),
+            content_type: "application/pdf".to_string(),
+            size: 3,
+            thumbnail: false,
+        };
+
+        assert_eq!(
+            content_disposition(&attachment),
+            "attachment; filename=\"caf_ menu.pdf\"; filename*=UTF-8''caf%C3%A9%20menu.pdf"
+        );
+    }
+}
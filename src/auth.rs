@@ -0,0 +1,172 @@
+use std::convert::Infallible;
+
+use anyhow::{Result, anyhow};
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+};
+use axum::{
+    extract::{FromRequestParts, OptionalFromRequestParts},
+    http::{StatusCode, request::Parts},
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::{
+    CookieJar,
+    cookie::{Cookie, SameSite},
+};
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::{AppState, schema::UserSession};
+
+/// Hash a plain text password into a PHC string (algorithm, params and salt included)
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+/// Hash of no one's password, checked when the username is unknown so that the login takes
+/// as long as for a real account. Same parameters as `hash_password`
+pub const DUMMY_PASSWORD_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$TN0LKQRr8rbwcKNyMvLG0g$Y0IrgOwzTBVC1e589kD6Eyxlk8FJFavMPtFHEYeF5FA";
+
+/// Check a plain text password against a stored PHC string
+/// A malformed hash is treated as a mismatch so that it never lets anyone in
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(e) => {
+            tracing::warn!("Stored password hash is malformed: {}", e);
+            false
+        }
+    }
+}
+
+/// Name of the cookie holding the session token
+pub const SESSION_COOKIE: &str = "session_id";
+
+/// Open a new server side session for the user and hand its token back as a cookie
+pub async fn start_session(state: &AppState, jar: CookieJar, user_id: Uuid) -> Result<CookieJar> {
+    let session = state.db.create_session(user_id, state.session_ttl).await?;
+
+    // No Max-Age: the browser drops it on close and the server decides when it expires
+    let mut cookie = Cookie::new(SESSION_COOKIE, session.session_id.to_string());
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Lax);
+    Ok(jar.add(cookie))
+}
+
+/// Revoke the session of the request, if any, and clear its cookie
+pub async fn end_session(state: &AppState, jar: CookieJar) -> Result<CookieJar> {
+    if let Some(session_id) = jar
+        .get(SESSION_COOKIE)
+        .and_then(|cookie| Uuid::parse_str(cookie.value()).ok())
+    {
+        state.db.delete_session(session_id).await?;
+    }
+
+    let mut cookie = Cookie::new(SESSION_COOKIE, "");
+    cookie.set_path("/");
+    cookie.make_removal();
+    Ok(jar.add(cookie))
+}
+
+/// The user behind a valid session cookie
+/// Use it as an extractor to require a login, or as `Option<CurrentUser>` to only peek at it
+#[derive(Clone, Copy, Debug)]
+pub struct CurrentUser {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+}
+
+impl CurrentUser {
+    async fn from_cookie(parts: &Parts, state: &AppState) -> Option<Self> {
+        let jar = CookieJar::from_headers(&parts.headers);
+        let session_id = Uuid::parse_str(jar.get(SESSION_COOKIE)?.value()).ok()?;
+        Self::from_session(state, session_id).await
+    }
+
+    /// Resolve a session id, `None` once it is unknown, revoked or expired
+    pub async fn from_session(state: &AppState, session_id: Uuid) -> Option<Self> {
+        let session = valid_session(state, session_id).await?;
+
+        // Sliding expiry: an active session is pushed back once half of its lifetime is used
+        if session.expires_at - state.session_ttl / 2 <= Utc::now()
+            && let Err(e) = state.db.refresh_session(&session, state.session_ttl).await
+        {
+            tracing::warn!("Failed to refresh session {}: {:#}", session_id, e);
+        }
+
+        Some(CurrentUser {
+            user_id: session.user_id,
+            session_id,
+        })
+    }
+
+    /// Whether the session still holds, without pushing its expiry back
+    /// For the checks of the long lived connections: an idle open tab must not keep it alive
+    pub async fn is_session_active(state: &AppState, session_id: Uuid) -> bool {
+        valid_session(state, session_id).await.is_some()
+    }
+}
+
+/// The session behind the id, `None` once it is unknown, revoked or expired
+async fn valid_session(state: &AppState, session_id: Uuid) -> Option<UserSession> {
+    let session = match state.db.get_session(session_id).await {
+        Ok(session) => session,
+        Err(e) => {
+            tracing::debug!("Rejected session {}: {:#}", session_id, e);
+            return None;
+        }
+    };
+
+    // Scylla TTL purges the row eventually, but not at the exact second
+    if session.expires_at <= Utc::now() {
+        return None;
+    }
+    Some(session)
+}
+
+impl FromRequestParts<AppState> for CurrentUser {
+    type Rejection = AuthRejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Self::from_cookie(parts, state).await.ok_or(AuthRejection {
+            htmx: parts.headers.contains_key("hx-request"),
+        })
+    }
+}
+
+impl OptionalFromRequestParts<AppState> for CurrentUser {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(Self::from_cookie(parts, state).await)
+    }
+}
+
+/// Missing, unknown or expired session
+pub struct AuthRejection {
+    htmx: bool,
+}
+
+impl IntoResponse for AuthRejection {
+    fn into_response(self) -> Response {
+        if self.htmx {
+            // Send the browser back to the login page instead of swapping an error in
+            return (StatusCode::UNAUTHORIZED, [("HX-Redirect", "/")]).into_response();
+        }
+        (StatusCode::UNAUTHORIZED, "Unauthorized").into_response()
+    }
+}
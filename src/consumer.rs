@@ -1,5 +1,6 @@
 use crate::db::Db;
 use anyhow::{Context, Result};
+use chrono::Utc;
 use dashmap::DashMap;
 use futures_util::StreamExt;
 use metrics::{counter, gauge, histogram};
@@ -8,14 +9,30 @@ use rdkafka::{
     ClientConfig, Offset,
     consumer::{Consumer, StreamConsumer},
 };
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 use tokio::time::Instant;
 use tokio::time::{Duration, interval};
 use tokio_stream::StreamExt as _;
 use tracing::Instrument;
 use uuid::Uuid;
 
-use crate::{ConnectionMap, db::ScyllaDb};
+use crate::{
+    ConnectionMap,
+    db::ScyllaDb,
+    event::ChatEvent,
+    membership::MembersCache,
+    mention,
+    schema::{
+        Mention, MessageReactions, PandaMessage, ReactionChange, ReadReceipt, SYSTEM_SENDER_ID,
+        Typing,
+    },
+    storage::{Storage, attachment_key, thumbnail_key},
+    typing::TYPING_TTL,
+    websocket::Delivery,
+};
 
 pub struct MessageConsumer {
     consumer: Arc<StreamConsumer>,
@@ -90,10 +107,12 @@ impl MessageConsumer {
         &self,
         db: Arc<ScyllaDb>,
         connections: ConnectionMap,
+        members_cache: Arc<MembersCache>,
+        storage: Arc<dyn Storage>,
     ) -> Result<()> {
         let stream = self.consumer.stream();
-        let members_cache: Arc<DashMap<Uuid, (Vec<Uuid>, Instant)>> = Arc::new(DashMap::new());
-        const CACHE_TTL: Duration = Duration::from_secs(1);
+        let last_touched: Arc<DashMap<Uuid, Instant>> = Arc::new(DashMap::new());
+        const ACTIVITY_RESOLUTION: Duration = Duration::from_secs(1);
         const POOL_NUMBER: usize = 20;
 
         let chunked_stream = stream.chunks_timeout(50, Duration::from_millis(50));
@@ -103,10 +122,15 @@ impl MessageConsumer {
                 let db = db.clone();
                 let connections = connections.clone();
                 let members_cache = members_cache.clone();
+                let storage = storage.clone();
+                let last_touched = last_touched.clone();
 
                 async move {
                     let start_total = Instant::now();
-                    let mut valid_messages = Vec::with_capacity(chunk.len());
+                    let mut valid_messages: Vec<PandaMessage> = Vec::with_capacity(chunk.len());
+                    let mut typing_signals = Vec::new();
+                    let mut receipts = Vec::new();
+                    let mut reaction_changes = Vec::new();
 
                     for message_result in chunk {
                         let processed = (|| {
@@ -122,19 +146,33 @@ impl MessageConsumer {
                             );
                             let _ = parent_span.enter();
 
-                            let chat_message = {
+                            let event = {
                                 let _ = tracing::info_span!("deserialize").entered();
-                                serde_json::from_str::<crate::schema::PandaMessage>(payload)?
+                                ChatEvent::decode(payload)?
                             };
-                            Ok::<_, anyhow::Error>(chat_message)
+                            Ok::<_, anyhow::Error>(event)
                         })();
 
                         match processed {
-                            Ok(msg) => valid_messages.push(msg),
+                            Ok(Some(ChatEvent::Typing(typing))) => typing_signals.push(typing),
+                            Ok(Some(ChatEvent::ReadReceipt(receipt))) => receipts.push(receipt),
+                            Ok(Some(ChatEvent::ReactionAdded(change))) => {
+                                reaction_changes.push((change, true))
+                            }
+                            Ok(Some(ChatEvent::ReactionRemoved(change))) => {
+                                reaction_changes.push((change, false))
+                            }
+                            Ok(Some(event)) => valid_messages.extend(event.into_message()),
+                            // Produced by a newer node, already logged by decode
+                            Ok(None) => counter!("consumer_events_skipped_total").increment(1),
                             Err(e) => tracing::error!("Skipping invalid message: {:?}", e),
                         }
                     }
 
+                    relay_typing(typing_signals, &db, &connections, &members_cache).await;
+                    relay_receipts(receipts, &connections).await;
+                    apply_reactions(reaction_changes, &db, &connections, &members_cache).await;
+
                     if valid_messages.is_empty() {
                         return;
                     }
@@ -143,43 +181,71 @@ impl MessageConsumer {
                         counter!("consumer_messages_processed_total")
                             .increment(valid_messages.len() as u64);
 
+                        resolve_mentions(&mut valid_messages, &db, &members_cache).await;
+
+                        // The batch removes the rows of their attachments, list them before
+                        let deleted_files = files_of_deleted(&valid_messages, &db).await;
+
                         // --- MEASUREMENT 1: Database Insert (Batch) ---
                         let start_db = Instant::now();
 
-                        db.insert_batch_message(&valid_messages)
+                        let rejected_edits = db
+                            .insert_batch_message(&valid_messages)
                             .await
                             .context("Failed to insert message batch into DB")?;
+                        purge_files(deleted_files, storage.as_ref()).await;
+                        // An edit of a message deleted meanwhile is not delivered, the search
+                        // indexer drops it as well
+                        valid_messages.retain(|m| {
+                            m.deleted
+                                || m.edited_at.is_none()
+                                || !rejected_edits.contains(&m.message_id)
+                        });
 
                         histogram!("consumer_db_duration_seconds")
                             .record(start_db.elapsed().as_secs_f64());
 
+                        // Editing or deleting an old message does not make its chat active
+                        let active_chats: HashSet<Uuid> = valid_messages
+                            .iter()
+                            .filter(|m| !m.is_change())
+                            .map(|m| m.chat_id)
+                            .collect();
+
                         // --- MEASUREMENT 2: Broadcasting ---
                         let start_broadcast = Instant::now();
-                        for chat_message in valid_messages {
+                        for mut chat_message in valid_messages {
                             let chat_id = chat_message.chat_id;
-                            let mut members_to_use = None;
 
                             async {
-                                if let Some(entry) = members_cache.get(&chat_id) {
-                                    let (members, timestamp) = entry.value();
-                                    if timestamp.elapsed() < CACHE_TTL {
-                                        members_to_use = Some(members.clone());
-                                    }
+                                // Membership changes are announced by a system message, this is
+                                // how every node learns that its cached members are stale
+                                if chat_message.sender_id == SYSTEM_SENDER_ID {
+                                    members_cache.invalidate(chat_id);
                                 }
-
-                                if members_to_use.is_none() {
-                                    let members = db.get_members_of_chat(chat_id).await?;
-                                    members_cache
-                                        .insert(chat_id, (members.clone(), Instant::now()));
-                                    members_to_use = Some(members);
+                                let members = members_cache.get(db.as_ref(), chat_id).await?;
+                                // An edit replaces the whole message, its reactions and files with it
+                                if chat_message.edited_at.is_some() && !chat_message.deleted {
+                                    chat_message.reactions =
+                                        db.get_reactions(chat_id, chat_message.message_id).await?;
+                                    chat_message.attachments = db
+                                        .get_attachments(chat_id, chat_message.message_id)
+                                        .await?;
                                 }
 
-                                if let Some(members) = members_to_use {
-                                    let lock = connections.read().await;
-                                    for member_id in members {
-                                        if let Some(sender) = lock.get(&member_id) {
-                                            let _ = sender.try_send(chat_message.clone());
+                                let lock = connections.read().await;
+                                for member_id in members {
+                                    // Every open tab or device of the member
+                                    for connection in
+                                        lock.get(&member_id).into_iter().flat_map(|c| c.values())
+                                    {
+                                        // Replies only reach the clients with their thread open
+                                        if chat_message.parent_id.is_some_and(|thread| {
+                                            !connection.follows_thread(thread)
+                                        }) {
+                                            continue;
                                         }
+                                        connection.deliver(chat_message.clone());
                                     }
                                 }
                                 Ok::<(), anyhow::Error>(())
@@ -191,6 +257,29 @@ impl MessageConsumer {
                         histogram!("consumer_broadcast_duration_seconds")
                             .record(start_broadcast.elapsed().as_secs_f64());
 
+                        // Move the chats up in their members' dashboard, at most once per
+                        // ACTIVITY_RESOLUTION per chat so hot chats do not rewrite it every batch
+                        let activity_at = Utc::now();
+                        // Past ACTIVITY_RESOLUTION an entry changes nothing, drop it so the map
+                        // only holds the chats active lately
+                        last_touched.retain(|_, touched| touched.elapsed() < ACTIVITY_RESOLUTION);
+                        for chat_id in active_chats {
+                            let recently_touched = last_touched
+                                .get(&chat_id)
+                                .is_some_and(|touched| touched.elapsed() < ACTIVITY_RESOLUTION);
+                            if recently_touched {
+                                continue;
+                            }
+                            last_touched.insert(chat_id, Instant::now());
+                            if let Err(e) = db.touch_chat(chat_id, activity_at).await {
+                                tracing::warn!(
+                                    "Failed to update activity of chat {}: {:?}",
+                                    chat_id,
+                                    e
+                                );
+                            }
+                        }
+
                         // --- MEASUREMENT 3: Total Loop ---
                         histogram!("consumer_processing_duration_seconds")
                             .record(start_total.elapsed().as_secs_f64());
@@ -207,3 +296,226 @@ impl MessageConsumer {
         Ok(())
     }
 }
+
+/// Offset after the last message of each partition of a topic
+pub fn end_offsets(brokers: &str, topic: &str) -> Result<HashMap<i32, i64>> {
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .create()
+        .context("Failed to create consumer")?;
+    partitions_of(&consumer, topic)?
+        .into_iter()
+        .map(|partition| {
+            let (_low, high) = consumer
+                .fetch_watermarks(topic, partition, Duration::from_secs(5))
+                .context("Failed to fetch watermarks")?;
+            Ok((partition, high))
+        })
+        .collect()
+}
+
+/// Partitions of a topic, for the readers that assign them by hand instead of joining a group
+pub fn partitions_of(consumer: &StreamConsumer, topic: &str) -> Result<Vec<i32>> {
+    let metadata = consumer
+        .fetch_metadata(Some(topic), Duration::from_secs(5))
+        .context("Failed to fetch metadata")?;
+    let partitions: Vec<i32> = metadata
+        .topics()
+        .first()
+        .map(|topic| topic.partitions().iter().map(|p| p.id()).collect())
+        .unwrap_or_default();
+    if partitions.is_empty() {
+        anyhow::bail!("Topic {} has no partitions yet", topic);
+    }
+    Ok(partitions)
+}
+
+/// Turn the `@username` of new and edited messages into mentions of members of the chat
+/// Unknown users, other people and the sender themself are left as text
+async fn resolve_mentions(
+    messages: &mut [PandaMessage],
+    db: &ScyllaDb,
+    members_cache: &MembersCache,
+) {
+    // A chunk often mentions the same people, look each of them up once
+    let mut users: HashMap<String, Option<Uuid>> = HashMap::new();
+    for message in messages
+        .iter_mut()
+        .filter(|m| !m.deleted && m.sender_id != SYSTEM_SENDER_ID)
+    {
+        let usernames = mention::parse(&message.content);
+        if usernames.is_empty() {
+            continue;
+        }
+        let members = match members_cache.get(db, message.chat_id).await {
+            Ok(members) => members,
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to resolve mentions in chat {}: {:?}",
+                    message.chat_id,
+                    e
+                );
+                continue;
+            }
+        };
+        for username in usernames {
+            if !users.contains_key(username) {
+                match db.find_user_by_username(username).await {
+                    Ok(user) => {
+                        users.insert(username.to_string(), user.map(|user| user.user_id));
+                    }
+                    // Not cached, the next message mentioning them tries again
+                    Err(e) => {
+                        tracing::warn!("Failed to look up mentioned user {}: {:?}", username, e);
+                        continue;
+                    }
+                }
+            }
+            let Some(Some(user_id)) = users.get(username).copied() else {
+                continue;
+            };
+            if user_id != message.sender_id && members.contains(&user_id) {
+                message.mentions.push(Mention {
+                    user_id,
+                    username: username.to_string(),
+                });
+            }
+        }
+        counter!("consumer_mentions_total").increment(message.mentions.len() as u64);
+    }
+}
+
+/// Storage keys of the attachments of the deleted messages, the ones posted in the same chunk too
+async fn files_of_deleted(messages: &[PandaMessage], db: &ScyllaDb) -> HashSet<String> {
+    let mut keys = HashSet::new();
+    for deleted in messages.iter().filter(|m| m.deleted) {
+        let mut attachments: Vec<_> = messages
+            .iter()
+            .filter(|m| m.message_id == deleted.message_id && !m.is_change())
+            .flat_map(|m| m.attachments.clone())
+            .collect();
+        match db
+            .get_attachments(deleted.chat_id, deleted.message_id)
+            .await
+        {
+            Ok(stored) => attachments.extend(stored),
+            Err(e) => tracing::warn!(
+                "Failed to list the attachments of deleted message {}: {:?}",
+                deleted.message_id,
+                e
+            ),
+        }
+        for attachment in attachments {
+            keys.insert(attachment_key(deleted.chat_id, attachment.attachment_id));
+            if attachment.thumbnail {
+                keys.insert(thumbnail_key(deleted.chat_id, attachment.attachment_id));
+            }
+        }
+    }
+    keys
+}
+
+/// Every node consumes the deletions, deleting a file already gone is not an error
+async fn purge_files(keys: HashSet<String>, storage: &dyn Storage) {
+    for key in keys {
+        match storage.delete(&key).await {
+            Ok(()) => counter!("attachments_purged_total").increment(1),
+            Err(e) => tracing::warn!("Failed to delete attachment {}: {:#}", key, e),
+        }
+    }
+}
+
+/// Typing signals skip ScyllaDB, they only reach the other members connected right now
+async fn relay_typing(
+    signals: Vec<Typing>,
+    db: &ScyllaDb,
+    connections: &ConnectionMap,
+    members_cache: &MembersCache,
+) {
+    for typing in signals {
+        // When the consumer is behind, the indicator would already be gone on the sender side
+        if Utc::now() - typing.at > TYPING_TTL {
+            counter!("consumer_typing_expired_total").increment(1);
+            continue;
+        }
+        let members = match members_cache.get(db, typing.chat_id).await {
+            Ok(members) => members,
+            Err(e) => {
+                tracing::warn!("Failed to relay typing in chat {}: {:?}", typing.chat_id, e);
+                continue;
+            }
+        };
+        let lock = connections.read().await;
+        for member_id in members.into_iter().filter(|id| *id != typing.user_id) {
+            for connection in lock.get(&member_id).into_iter().flat_map(|c| c.values()) {
+                connection.deliver(Delivery::Typing(typing.clone()));
+            }
+        }
+    }
+}
+
+/// Receipts only go to the connections of the sender of the message read
+async fn relay_receipts(receipts: Vec<ReadReceipt>, connections: &ConnectionMap) {
+    if receipts.is_empty() {
+        return;
+    }
+    let lock = connections.read().await;
+    for receipt in receipts {
+        for connection in lock
+            .get(&receipt.sender_id)
+            .into_iter()
+            .flat_map(|c| c.values())
+        {
+            connection.deliver(Delivery::ReadReceipt(receipt.clone()));
+        }
+    }
+}
+
+/// Reactions are stored in the order they came, then each message changed is sent once with
+/// all of its reactions, so the members never have to merge changes themselves
+async fn apply_reactions(
+    changes: Vec<(ReactionChange, bool)>,
+    db: &ScyllaDb,
+    connections: &ConnectionMap,
+    members_cache: &MembersCache,
+) {
+    let mut changed = Vec::new();
+    for (change, added) in changes {
+        let result = if added {
+            db.add_reaction(&change).await
+        } else {
+            db.remove_reaction(&change).await
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to apply reaction on {}: {:?}", change.message_id, e);
+            continue;
+        }
+        counter!("consumer_reactions_applied_total").increment(1);
+        if !changed.contains(&(change.chat_id, change.message_id)) {
+            changed.push((change.chat_id, change.message_id));
+        }
+    }
+
+    for (chat_id, message_id) in changed {
+        let result = async {
+            let reactions = db.get_reactions(chat_id, message_id).await?;
+            let members = members_cache.get(db, chat_id).await?;
+            let update = MessageReactions {
+                chat_id,
+                message_id,
+                reactions,
+            };
+            let lock = connections.read().await;
+            for member_id in members {
+                for connection in lock.get(&member_id).into_iter().flat_map(|c| c.values()) {
+                    connection.deliver(Delivery::Reactions(update.clone()));
+                }
+            }
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+        if let Err(e) = result {
+            tracing::warn!("Failed to broadcast reactions of {}: {:?}", message_id, e);
+        }
+    }
+}
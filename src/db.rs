@@ -1,33 +1,109 @@
 use anyhow::{Context, Result};
 use async_trait::async_trait;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt, future::try_join_all};
 use scylla::{
     client::{session::Session, session_builder::SessionBuilder},
     deserialize::row::DeserializeRow as DeserializeRowTrait,
+    response::PagingState,
     serialize::row::SerializeRow,
-    statement::batch::Batch,
-    value::CqlTimeuuid,
+    statement::{Statement, batch::Batch},
+    value::{CqlTimeuuid, Row},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
 };
 use uuid::Uuid;
 
 use crate::{
     NODE_ID,
-    schema::{Chat, PandaMessage, RawPandaMessage, User},
+    schema::{
+        Attachment, Chat, MessagePage, PandaMessage, RawPandaMessage, Reaction, ReactionChange,
+        Role, User, UserSession,
+    },
 };
 
+/// The row asked for does not exist, as opposed to the query failing
+#[derive(Debug)]
+pub struct RowNotFound;
+
+impl std::fmt::Display for RowNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Row not found")
+    }
+}
+
+impl std::error::Error for RowNotFound {}
+
+/// Whether the error comes from a missing row, however much context was added on top
+pub fn is_not_found(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<RowNotFound>())
+}
+
 #[async_trait]
 pub trait Db: Send + Sync {
-    async fn insert_batch_message(&self, messages: &[PandaMessage]) -> Result<()>;
+    /// Returns the ids of the edits dropped because their message was deleted first
+    async fn insert_batch_message(&self, messages: &[PandaMessage]) -> Result<Vec<Uuid>>;
+    // The app stores through the consumer batches, this is for one-off writes
+    #[allow(dead_code)]
     async fn insert_message(&self, message: PandaMessage) -> Result<PandaMessage>;
-    async fn create_user(&self, username: &str) -> Result<User>;
-    async fn create_chat(&self, name: &str, members: &[Uuid]) -> Result<Chat>;
+    async fn create_user(&self, username: &str, password_hash: &str) -> Result<User>;
+    async fn create_chat(&self, name: &str, members: &[Uuid], owner: Uuid) -> Result<Chat>;
     async fn get_user(&self, user_id: Uuid) -> Result<User>;
     async fn get_chat(&self, chat_id: Uuid) -> Result<Chat>;
-    async fn get_messages(&self, chat_id: Uuid) -> Result<Vec<PandaMessage>>;
+    async fn get_message(&self, chat_id: Uuid, message_id: Uuid) -> Result<PandaMessage>;
+    async fn get_messages(
+        &self,
+        chat_id: Uuid,
+        before: Option<Uuid>,
+        limit: i32,
+    ) -> Result<MessagePage>;
+    async fn get_messages_after(
+        &self,
+        chat_id: Uuid,
+        after: Uuid,
+        limit: i32,
+    ) -> Result<Vec<PandaMessage>>;
+    /// Replies to a message, oldest first
+    async fn get_thread(&self, chat_id: Uuid, parent_id: Uuid) -> Result<Vec<PandaMessage>>;
+    /// Messages still mentioning the user, newest first, paged like the history
+    async fn get_mentions(
+        &self,
+        user_id: Uuid,
+        before: Option<Uuid>,
+        limit: i32,
+    ) -> Result<MessagePage>;
     async fn get_chats_for_user(&self, user_id: Uuid) -> Result<Vec<Chat>>;
+    /// Only the ids, without the unread counts of get_chats_for_user
+    async fn get_chat_ids_for_user(&self, user_id: Uuid) -> Result<Vec<Uuid>>;
+    async fn add_reaction(&self, change: &ReactionChange) -> Result<()>;
+    async fn remove_reaction(&self, change: &ReactionChange) -> Result<()>;
+    async fn get_reactions(&self, chat_id: Uuid, message_id: Uuid) -> Result<Vec<Reaction>>;
+    async fn get_attachments(&self, chat_id: Uuid, message_id: Uuid) -> Result<Vec<Attachment>>;
+    async fn get_attachment(
+        &self,
+        chat_id: Uuid,
+        message_id: Uuid,
+        attachment_id: Uuid,
+    ) -> Result<Attachment>;
+    /// Move the read receipt of a user forward, tells whether it moved
+    async fn mark_read(&self, chat_id: Uuid, user_id: Uuid, message_id: Uuid) -> Result<bool>;
+    /// Newest message read by each member of the chat who ever opened it
+    async fn get_read_receipts(&self, chat_id: Uuid) -> Result<HashMap<Uuid, Uuid>>;
+    async fn touch_chat(&self, chat_id: Uuid, at: DateTime<Utc>) -> Result<()>;
+    async fn add_chat_member(&self, chat_id: Uuid, user_id: Uuid) -> Result<Chat>;
+    async fn remove_chat_member(&self, chat_id: Uuid, user_id: Uuid) -> Result<Chat>;
+    async fn set_chat_roles(&self, chat_id: Uuid, changes: &[(Uuid, Role)]) -> Result<Chat>;
     async fn get_user_by_username(&self, username: &str) -> Result<User>;
+    async fn get_password_hash(&self, user_id: Uuid) -> Result<String>;
     async fn get_all_users(&self) -> Result<Vec<User>>;
+    /// Empty for an unknown chat, so that checking it is a 403 like any chat of others
     async fn get_members_of_chat(&self, chat_id: Uuid) -> Result<Vec<Uuid>>;
+    async fn create_session(&self, user_id: Uuid, ttl: Duration) -> Result<UserSession>;
+    async fn get_session(&self, session_id: Uuid) -> Result<UserSession>;
+    async fn refresh_session(&self, session: &UserSession, ttl: Duration) -> Result<UserSession>;
+    async fn delete_session(&self, session_id: Uuid) -> Result<()>;
 }
 
 pub struct ScyllaDb {
@@ -54,8 +130,482 @@ impl ScyllaDb {
             .context("Failed to execute query")?
             .into_rows_result()
             .context("Failed to parse rows result")?
-            .first_row()
-            .context("Row not found")
+            .maybe_first_row()
+            .context("Failed to deserialize row")?
+            .ok_or_else(|| RowNotFound.into())
+    }
+
+    /// Write a session row that Scylla expires by itself once the TTL is over
+    async fn store_session(&self, session: &UserSession, ttl: Duration) -> Result<()> {
+        let ttl = i32::try_from(ttl.as_secs()).context("Session TTL is too large")?;
+        self.insert_data(
+            "INSERT INTO ks.sessions (session_id, user_id, created_at, expires_at) VALUES (?, ?, ?, ?) USING TTL ?",
+            (
+                session.session_id,
+                session.user_id,
+                session.created_at,
+                session.expires_at,
+                ttl,
+            ),
+        )
+        .await
+        .context("Failed to store session")
+    }
+
+    async fn fetch_chat_row(&self, chat_id: Uuid) -> Result<ChatRow> {
+        self.fetch_single(
+            "SELECT chat_id, members, name, created_at, last_activity_at, roles FROM ks.chats WHERE chat_id = ?",
+            (chat_id,),
+        )
+        .await
+        .context("Could not fetch chat")
+    }
+
+    /// Run a lightweight transaction and tell whether it was applied
+    async fn compare_and_set(&self, query: &str, values: impl SerializeRow) -> Result<bool> {
+        Ok(self
+            .session
+            .query_unpaged(query, values)
+            .await
+            .context("Failed to execute conditional update")?
+            .into_rows_result()?
+            .first_row::<Row>()?
+            .columns
+            .first()
+            .and_then(|applied| applied.as_ref()?.as_boolean())
+            .unwrap_or(false))
+    }
+
+    /// Compare-and-set the members list of a chat with a lightweight transaction, retried when
+    /// another change won the race, then mirror the new list into chats_by_user
+    /// Removed members lose their role so that coming back does not restore it
+    async fn update_members(
+        &self,
+        chat_id: Uuid,
+        change: impl Fn(&[Uuid]) -> Vec<Uuid> + Send + Sync,
+    ) -> Result<Chat> {
+        for _ in 0..CAS_ATTEMPTS {
+            let (chat_id, members, name, created_at, last_activity_at, roles) =
+                self.fetch_chat_row(chat_id).await?;
+
+            let new_members = change(&members);
+            let removed: Vec<Uuid> = members
+                .iter()
+                .copied()
+                .filter(|member| !new_members.contains(member))
+                .collect();
+            let mut roles = parse_roles(roles);
+            roles.retain(|user_id, _| !removed.contains(user_id));
+            let chat = Chat {
+                chat_id,
+                members: new_members.clone(),
+                name,
+                created_at,
+                roles,
+                unread: 0,
+            };
+            if new_members == members {
+                return Ok(chat);
+            }
+
+            // A touch moving the dashboard rows meanwhile makes this one retry, so the rows are
+            // rewritten at the activity they are at. Chats older than chats_by_user start theirs
+            let activity_at = last_activity_at.unwrap_or_else(|| truncate_to_millis(Utc::now()));
+            let applied = self
+                .compare_and_set(
+                    "UPDATE ks.chats SET members = ?, roles = roles - ?, last_activity_at = ? WHERE chat_id = ? IF members = ? AND last_activity_at = ?",
+                    (
+                        &new_members,
+                        &removed,
+                        activity_at,
+                        chat_id,
+                        &members,
+                        last_activity_at,
+                    ),
+                )
+                .await
+                .context("Failed to update members")?;
+            if !applied {
+                continue;
+            }
+
+            self.sync_chats_by_user(&chat, &members, activity_at)
+                .await?;
+            return Ok(chat);
+        }
+        Err(anyhow::anyhow!(
+            "Members of chat {} changed concurrently too many times",
+            chat_id
+        ))
+    }
+
+    /// Rewrite the dashboard rows of a chat after its members changed
+    async fn sync_chats_by_user(
+        &self,
+        chat: &Chat,
+        previous_members: &[Uuid],
+        activity_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut batch: Batch = Default::default();
+        let mut batch_values: Vec<Box<dyn SerializeRow + Send + Sync>> = Vec::new();
+
+        for removed in previous_members
+            .iter()
+            .filter(|member| !chat.members.contains(member))
+        {
+            batch.append_statement(
+                "DELETE FROM ks.chats_by_user WHERE user_id = ? AND last_activity_at = ? AND chat_id = ?",
+            );
+            batch_values.push(Box::new((*removed, activity_at, chat.chat_id)));
+        }
+        for member in &chat.members {
+            batch.append_statement(
+                "INSERT INTO ks.chats_by_user (user_id, last_activity_at, chat_id, members, name, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+            );
+            batch_values.push(Box::new((
+                *member,
+                activity_at,
+                chat.chat_id,
+                chat.members.clone(),
+                chat.name.clone(),
+                chat.created_at,
+            )));
+        }
+
+        self.session
+            .batch(&batch, batch_values)
+            .await
+            .context("Failed to sync chats_by_user")?;
+        Ok(())
+    }
+
+    /// Fill the reactions of a slice of history with one range query over the chat's reactions
+    async fn attach_reactions(&self, chat_id: Uuid, messages: &mut [PandaMessage]) -> Result<()> {
+        let ids = messages
+            .iter()
+            .map(|message| CqlTimeuuid::from(message.message_id));
+        let (Some(oldest), Some(newest)) = (ids.clone().min(), ids.max()) else {
+            return Ok(());
+        };
+        let rows = self
+            .session
+            .query_unpaged(
+                "SELECT message_id, emoji, user_id FROM ks.reactions WHERE chat_id = ? AND message_id >= ? AND message_id <= ?",
+                (chat_id, oldest, newest),
+            )
+            .await
+            .context("Failed to fetch reactions")?
+            .into_rows_result()
+            .context("Failed to parse rows result")?
+            .rows::<(CqlTimeuuid, String, Uuid)>()
+            .context("Failed to access rows iterator")?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut by_message: HashMap<Uuid, Vec<(String, Uuid)>> = HashMap::new();
+        for (message_id, emoji, user_id) in rows {
+            by_message
+                .entry(Uuid::from(message_id))
+                .or_default()
+                .push((emoji, user_id));
+        }
+        for message in messages {
+            if let Some(rows) = by_message.remove(&message.message_id) {
+                message.reactions = Reaction::aggregate(rows);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill the attachments of a slice of history or of a thread, like its reactions
+    async fn attach_attachments(&self, chat_id: Uuid, messages: &mut [PandaMessage]) -> Result<()> {
+        let ids = messages
+            .iter()
+            .map(|message| CqlTimeuuid::from(message.message_id));
+        let (Some(oldest), Some(newest)) = (ids.clone().min(), ids.max()) else {
+            return Ok(());
+        };
+        let rows = self
+            .session
+            .query_unpaged(
+                "SELECT message_id, attachment_id, filename, content_type, size, thumbnail FROM ks.attachments WHERE chat_id = ? AND message_id >= ? AND message_id <= ?",
+                (chat_id, oldest, newest),
+            )
+            .await
+            .context("Failed to fetch attachments")?
+            .into_rows_result()
+            .context("Failed to parse rows result")?
+            .rows::<(CqlTimeuuid, Uuid, String, String, i64, bool)>()
+            .context("Failed to access rows iterator")?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut by_message: HashMap<Uuid, Vec<Attachment>> = HashMap::new();
+        for (message_id, attachment_id, filename, content_type, size, thumbnail) in rows {
+            by_message
+                .entry(Uuid::from(message_id))
+                .or_default()
+                .push(Attachment {
+                    attachment_id,
+                    filename,
+                    content_type,
+                    size,
+                    thumbnail,
+                });
+        }
+        for message in messages {
+            if let Some(attachments) = by_message.remove(&message.message_id) {
+                message.attachments = attachments;
+            }
+        }
+        Ok(())
+    }
+
+    /// Count the replies to a slice of history, like its reactions, with a single range query
+    async fn attach_reply_counts(
+        &self,
+        chat_id: Uuid,
+        messages: &mut [PandaMessage],
+    ) -> Result<()> {
+        let ids = messages
+            .iter()
+            .map(|message| CqlTimeuuid::from(message.message_id));
+        let (Some(oldest), Some(newest)) = (ids.clone().min(), ids.max()) else {
+            return Ok(());
+        };
+        let rows = self
+            .session
+            .query_unpaged(
+                "SELECT parent_id FROM ks.thread_messages WHERE chat_id = ? AND parent_id >= ? AND parent_id <= ?",
+                (chat_id, oldest, newest),
+            )
+            .await
+            .context("Failed to count replies")?
+            .into_rows_result()
+            .context("Failed to parse rows result")?
+            .rows::<(CqlTimeuuid,)>()
+            .context("Failed to access rows iterator")?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut counts: HashMap<Uuid, usize> = HashMap::new();
+        for (parent_id,) in rows {
+            *counts.entry(Uuid::from(parent_id)).or_default() += 1;
+        }
+        for message in messages {
+            message.reply_count = counts.remove(&message.message_id).unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    /// A message of the mentions feed, from the history or from its thread, `None` once gone
+    async fn fetch_mentioning(
+        &self,
+        chat_id: Uuid,
+        parent_id: Option<CqlTimeuuid>,
+        message_id: CqlTimeuuid,
+    ) -> Result<Option<PandaMessage>> {
+        let result = match parent_id {
+            Some(parent_id) => {
+                self.session
+                    .query_unpaged(
+                        "SELECT chat_id, sender_id, content, message_id, edited_at, deleted, mentions FROM ks.thread_messages WHERE chat_id = ? AND parent_id = ? AND message_id = ?",
+                        (chat_id, parent_id, message_id),
+                    )
+                    .await
+            }
+            None => {
+                self.session
+                    .query_unpaged(
+                        "SELECT chat_id, sender_id, content, message_id, edited_at, deleted, mentions FROM ks.messages WHERE chat_id = ? AND message_id = ?",
+                        (chat_id, message_id),
+                    )
+                    .await
+            }
+        }
+        .context("Failed to fetch mentioning message")?;
+        let raw: Option<RawPandaMessage> = result.into_rows_result()?.maybe_first_row()?;
+        Ok(raw.map(|raw| PandaMessage {
+            parent_id: parent_id.map(Uuid::from),
+            ..raw.to_panda_message()
+        }))
+    }
+
+    /// Read receipts of a user in the given chats, in a single query
+    async fn get_read_receipts_of_user(
+        &self,
+        user_id: Uuid,
+        chat_ids: &[Uuid],
+    ) -> Result<HashMap<Uuid, CqlTimeuuid>> {
+        if chat_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        self.session
+            .query_unpaged(
+                "SELECT chat_id, last_read FROM ks.read_receipts WHERE chat_id IN ? AND user_id = ?",
+                (chat_ids.to_vec(), user_id),
+            )
+            .await
+            .context("Failed to fetch read receipts")?
+            .into_rows_result()?
+            .rows::<(Uuid, CqlTimeuuid)>()?
+            .map(|row| row.context("Failed to deserialize row"))
+            .collect()
+    }
+
+    /// Messages of the others after the read receipt of the user, counted up to MAX_UNREAD
+    async fn count_unread(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        last_read: Option<CqlTimeuuid>,
+    ) -> Result<i64> {
+        // Never opened: the whole history is unread
+        let result = match last_read {
+            Some(last_read) => {
+                self.session
+                    .query_unpaged(
+                        "SELECT sender_id, deleted FROM ks.messages WHERE chat_id = ? AND message_id > ? LIMIT ?",
+                        (chat_id, last_read, MAX_UNREAD),
+                    )
+                    .await
+            }
+            None => {
+                self.session
+                    .query_unpaged(
+                        "SELECT sender_id, deleted FROM ks.messages WHERE chat_id = ? LIMIT ?",
+                        (chat_id, MAX_UNREAD),
+                    )
+                    .await
+            }
+        }
+        .context("Failed to count unread messages")?;
+
+        let mut unread = 0;
+        for row in result.into_rows_result()?.rows::<(Uuid, Option<bool>)>()? {
+            let (sender_id, deleted) = row.context("Failed to deserialize row")?;
+            if sender_id != user_id && !deleted.unwrap_or(false) {
+                unread += 1;
+            }
+        }
+        Ok(unread)
+    }
+
+    /// `None` when nobody has this username, an error is the database failing
+    pub async fn find_user_by_username(&self, username: &str) -> Result<Option<User>> {
+        let row: Option<(Uuid,)> = self
+            .session
+            .query_unpaged(
+                "SELECT user_id FROM ks.users_by_username WHERE username = ?",
+                (username,),
+            )
+            .await
+            .context("Failed to execute query")?
+            .into_rows_result()?
+            .maybe_first_row()?;
+        match row {
+            Some((user_id,)) => self.get_user(user_id).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Dashboard rows of the chats created before chats_by_user, which have no activity yet
+    /// Their creation counts as their last activity, a chat touched meanwhile is left alone
+    /// Returns how many chats were backfilled
+    pub async fn backfill_chats_by_user(&self) -> Result<usize> {
+        let mut chats = self
+            .session
+            .query_iter(
+                "SELECT chat_id, members, name, created_at, last_activity_at FROM ks.chats",
+                (),
+            )
+            .await
+            .context("Failed to scan chats")?
+            .rows_stream::<(
+                Uuid,
+                Vec<Uuid>,
+                String,
+                DateTime<Utc>,
+                Option<DateTime<Utc>>,
+            )>()?;
+        let mut count = 0;
+        while let Some(row) = chats.next().await {
+            let (chat_id, members, name, created_at, last_activity_at) = row?;
+            if last_activity_at.is_some() {
+                continue;
+            }
+            let applied = self
+                .compare_and_set(
+                    "UPDATE ks.chats SET last_activity_at = ? WHERE chat_id = ? IF last_activity_at = null",
+                    (created_at, chat_id),
+                )
+                .await
+                .context("Failed to start chat activity")?;
+            if !applied {
+                continue;
+            }
+
+            let mut batch: Batch = Default::default();
+            let mut batch_values: Vec<Box<dyn SerializeRow + Send + Sync>> = Vec::new();
+            for member in &members {
+                batch.append_statement(
+                    "INSERT INTO ks.chats_by_user (user_id, last_activity_at, chat_id, members, name, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+                );
+                batch_values.push(Box::new((
+                    *member,
+                    created_at,
+                    chat_id,
+                    members.clone(),
+                    name.clone(),
+                    created_at,
+                )));
+            }
+            self.session
+                .batch(&batch, batch_values)
+                .await
+                .context("Failed to backfill chats_by_user")?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Every message and reply of every chat, a full scan for the reindex command
+    pub async fn stream_all_messages(
+        &self,
+    ) -> Result<impl Stream<Item = Result<PandaMessage>> + Send + use<>> {
+        let messages = self
+            .session
+            .query_iter(
+                "SELECT chat_id, sender_id, content, message_id, edited_at, deleted, mentions FROM ks.messages",
+                (),
+            )
+            .await
+            .context("Failed to scan messages")?
+            .rows_stream::<RawPandaMessage>()?
+            .map(|row| Ok(row?.to_panda_message()));
+        let replies = self
+            .session
+            .query_iter(
+                "SELECT chat_id, parent_id, sender_id, content, message_id, deleted FROM ks.thread_messages",
+                (),
+            )
+            .await
+            .context("Failed to scan thread messages")?
+            .rows_stream::<(Uuid, CqlTimeuuid, Uuid, String, CqlTimeuuid, Option<bool>)>()?
+            .map(|row| {
+                let (chat_id, parent_id, sender_id, content, message_id, deleted) = row?;
+                Ok(PandaMessage {
+                    chat_id,
+                    sender_id,
+                    content,
+                    message_id: Uuid::from(message_id),
+                    edited_at: None,
+                    deleted: deleted.unwrap_or(false),
+                    reactions: Vec::new(),
+                    mentions: Vec::new(),
+                    attachments: Vec::new(),
+                    parent_id: Some(Uuid::from(parent_id)),
+                    reply_count: 0,
+                })
+            });
+        Ok(messages.chain(replies))
     }
 
     /// Generic helper to execute an INSERT statement
@@ -70,29 +620,139 @@ impl ScyllaDb {
 
 #[async_trait]
 impl Db for ScyllaDb {
-    async fn insert_batch_message(&self, messages: &[PandaMessage]) -> Result<()> {
+    async fn insert_batch_message(&self, messages: &[PandaMessage]) -> Result<Vec<Uuid>> {
+        // Chunks are consumed concurrently, a deletion can be stored before an earlier edit of
+        // the same message. The edit only applies while the message is not deleted, a check
+        // that takes a lightweight transaction and so can not join the batch
+        let mut rejected_edits = Vec::new();
+        for msg in messages.iter().filter(|msg| !msg.deleted) {
+            let Some(edited_at) = msg.edited_at else {
+                continue;
+            };
+            let mentions: HashMap<Uuid, &str> = msg
+                .mentions
+                .iter()
+                .map(|mention| (mention.user_id, mention.username.as_str()))
+                .collect();
+            let applied = self
+                .compare_and_set(
+                    "UPDATE ks.messages SET content = ?, edited_at = ?, mentions = ? WHERE chat_id = ? AND message_id = ? IF deleted != true",
+                    (
+                        &msg.content,
+                        edited_at,
+                        mentions,
+                        msg.chat_id,
+                        CqlTimeuuid::from(msg.message_id),
+                    ),
+                )
+                .await
+                .context("Failed to edit message")?;
+            if !applied {
+                rejected_edits.push(msg.message_id);
+            }
+        }
+
         let mut batch: Batch = Default::default();
-        let mut batch_values = Vec::new();
+        let mut batch_values: Vec<Box<dyn SerializeRow + Send + Sync>> = Vec::new();
         for msg in messages {
-            batch.append_statement(
-            "INSERT INTO ks.messages (message_id, chat_id, sender_id, content) VALUES (?, ?, ?, ?)",
-        );
-            batch_values.push((
-                scylla::value::CqlTimeuuid::from(msg.message_id),
-                msg.chat_id,
-                msg.sender_id,
-                &msg.content,
-            ));
+            let message_id = CqlTimeuuid::from(msg.message_id);
+            let mentions: HashMap<Uuid, &str> = msg
+                .mentions
+                .iter()
+                .map(|mention| (mention.user_id, mention.username.as_str()))
+                .collect();
+            // Edits and deletions only touch their columns
+            if msg.deleted {
+                batch.append_statement(
+                    "UPDATE ks.messages SET content = '', deleted = true WHERE chat_id = ? AND message_id = ?",
+                );
+                batch_values.push(Box::new((msg.chat_id, message_id)));
+                // The files go with the message, the download routes stop serving them and the
+                // consumer deletes their bytes once the batch is stored
+                batch.append_statement(
+                    "DELETE FROM ks.attachments WHERE chat_id = ? AND message_id = ?",
+                );
+                batch_values.push(Box::new((msg.chat_id, message_id)));
+            } else if msg.edited_at.is_some() {
+                // Stored above, its mentions only count if it was applied
+                if rejected_edits.contains(&msg.message_id) {
+                    continue;
+                }
+            } else if let Some(parent_id) = msg.parent_id {
+                batch.append_statement(
+                    "INSERT INTO ks.thread_messages (chat_id, parent_id, message_id, sender_id, content, mentions) VALUES (?, ?, ?, ?, ?, ?)",
+                );
+                batch_values.push(Box::new((
+                    msg.chat_id,
+                    CqlTimeuuid::from(parent_id),
+                    message_id,
+                    msg.sender_id,
+                    msg.content.clone(),
+                    mentions.clone(),
+                )));
+            } else {
+                batch.append_statement(
+                    "INSERT INTO ks.messages (message_id, chat_id, sender_id, content, mentions) VALUES (?, ?, ?, ?, ?)",
+                );
+                batch_values.push(Box::new((
+                    message_id,
+                    msg.chat_id,
+                    msg.sender_id,
+                    msg.content.clone(),
+                    mentions.clone(),
+                )));
+            }
+            // Only posted messages carry their attachments, edits keep the stored ones
+            if !msg.is_change() {
+                for attachment in &msg.attachments {
+                    batch.append_statement(
+                        "INSERT INTO ks.attachments (chat_id, message_id, attachment_id, filename, content_type, size, thumbnail) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                    );
+                    batch_values.push(Box::new((
+                        msg.chat_id,
+                        message_id,
+                        attachment.attachment_id,
+                        attachment.filename.clone(),
+                        attachment.content_type.clone(),
+                        attachment.size,
+                        attachment.thumbnail,
+                    )));
+                }
+            }
+            // An edit adding a mention adds it to the feed, one removing it is filtered on read
+            for mention in &msg.mentions {
+                batch.append_statement(
+                    "INSERT INTO ks.mentions_by_user (user_id, message_id, chat_id, parent_id) VALUES (?, ?, ?, ?)",
+                );
+                batch_values.push(Box::new((
+                    mention.user_id,
+                    message_id,
+                    msg.chat_id,
+                    msg.parent_id.map(CqlTimeuuid::from),
+                )));
+            }
         }
 
-        self.session.batch(&batch, batch_values).await?;
-        Ok(())
+        if !batch_values.is_empty() {
+            self.session.batch(&batch, batch_values).await?;
+        }
+        Ok(rejected_edits)
+    }
+
+    /// A single message, stored the same way as the ones of a batch
+    async fn insert_message(&self, message: PandaMessage) -> Result<PandaMessage> {
+        self.insert_batch_message(std::slice::from_ref(&message))
+            .await?;
+        Ok(message)
     }
     // TODO : Factorise function that fetch multiple rows
     async fn get_all_users(&self) -> Result<Vec<User>> {
         let users = self
             .session
-            .query_unpaged("SELECT * FROM ks.users", &[])
+            .query_unpaged(
+                "SELECT user_id, username, created_at, updated_at FROM ks.users",
+                &[],
+            )
             .await
             .context("Failed to execute query")?
             .into_rows_result()
@@ -104,30 +764,99 @@ impl Db for ScyllaDb {
     }
 
     async fn get_members_of_chat(&self, chat_id: Uuid) -> Result<Vec<Uuid>> {
-        let (members,): (Vec<Uuid>,) = self
-            .fetch_single(
+        let row: Option<(Vec<Uuid>,)> = self
+            .session
+            .query_unpaged(
                 "SELECT members from ks.chats WHERE chat_id = ?",
                 ((chat_id),),
             )
             .await
-            .context("Could not fetch members of chat")?;
+            .context("Could not fetch members of chat")?
+            .into_rows_result()
+            .context("Failed to parse rows result")?
+            .maybe_first_row()?;
 
-        Ok(members)
+        Ok(row.map(|(members,)| members).unwrap_or_default())
     }
-    async fn get_messages(&self, chat_id: Uuid) -> Result<Vec<PandaMessage>> {
+    async fn get_messages(
+        &self,
+        chat_id: Uuid,
+        before: Option<Uuid>,
+        limit: i32,
+    ) -> Result<MessagePage> {
+        // The partition is clustered by message_id DESC: a single page is the newest `limit`
+        // messages older than the cursor, and the paging state tells if anything is left
+        let (query_result, paging_state) = match before {
+            Some(before) => {
+                let statement = Statement::new(
+                    "SELECT chat_id, sender_id, content, message_id, edited_at, deleted, mentions FROM ks.messages WHERE chat_id = ? AND message_id < ?",
+                )
+                .with_page_size(limit);
+                self.session
+                    .query_single_page(
+                        statement,
+                        (chat_id, CqlTimeuuid::from(before)),
+                        PagingState::start(),
+                    )
+                    .await
+            }
+            None => {
+                let statement = Statement::new(
+                    "SELECT chat_id, sender_id, content, message_id, edited_at, deleted, mentions FROM ks.messages WHERE chat_id = ?",
+                )
+                .with_page_size(limit);
+                self.session
+                    .query_single_page(statement, (chat_id,), PagingState::start())
+                    .await
+            }
+        }
+        .context("Failed to execute query")?;
+
         // Because Uuid can not be compared with a Timeuuid and serialize it back
-        let rows_result = self
+        let mut messages = query_result
+            .into_rows_result()
+            .context("Failed to parse rows result")?
+            .rows::<RawPandaMessage>()
+            .context("Failed to access rows iterator")?
+            .map(|row_result| {
+                row_result
+                    .map(|raw| raw.to_panda_message())
+                    .context("Failed to deserialize row")
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.attach_reactions(chat_id, &mut messages).await?;
+        self.attach_attachments(chat_id, &mut messages).await?;
+        self.attach_reply_counts(chat_id, &mut messages).await?;
+
+        let next_cursor = if paging_state.finished() {
+            None
+        } else {
+            messages.last().map(|message| message.message_id)
+        };
+
+        Ok(MessagePage {
+            messages,
+            next_cursor,
+        })
+    }
+
+    async fn get_messages_after(
+        &self,
+        chat_id: Uuid,
+        after: Uuid,
+        limit: i32,
+    ) -> Result<Vec<PandaMessage>> {
+        // Reversed clustering order: oldest first, so the caller can page with the last id
+        let mut messages = self
             .session
             .query_unpaged(
-                "SELECT chat_id, sender_id, content, message_id FROM ks.messages WHERE chat_id = ?",
-                (chat_id,),
+                "SELECT chat_id, sender_id, content, message_id, edited_at, deleted, mentions FROM ks.messages WHERE chat_id = ? AND message_id > ? ORDER BY message_id ASC LIMIT ?",
+                (chat_id, CqlTimeuuid::from(after), limit),
             )
             .await
             .context("Failed to execute query")?
             .into_rows_result()
-            .context("Failed to parse rows result")?;
-
-        let messages = rows_result
+            .context("Failed to parse rows result")?
             .rows::<RawPandaMessage>()
             .context("Failed to access rows iterator")?
             .map(|row_result| {
@@ -136,15 +865,103 @@ impl Db for ScyllaDb {
                     .context("Failed to deserialize row")
             })
             .collect::<Result<Vec<_>, _>>()?;
-
+        self.attach_reactions(chat_id, &mut messages).await?;
+        self.attach_attachments(chat_id, &mut messages).await?;
+        self.attach_reply_counts(chat_id, &mut messages).await?;
         Ok(messages)
     }
 
+    async fn get_thread(&self, chat_id: Uuid, parent_id: Uuid) -> Result<Vec<PandaMessage>> {
+        let mut replies = self
+            .session
+            .query_unpaged(
+                "SELECT chat_id, sender_id, content, message_id, edited_at, deleted, mentions FROM ks.thread_messages WHERE chat_id = ? AND parent_id = ?",
+                (chat_id, CqlTimeuuid::from(parent_id)),
+            )
+            .await
+            .context("Failed to execute query")?
+            .into_rows_result()
+            .context("Failed to parse rows result")?
+            .rows::<RawPandaMessage>()
+            .context("Failed to access rows iterator")?
+            .map(|row_result| {
+                row_result
+                    .map(|raw| PandaMessage {
+                        parent_id: Some(parent_id),
+                        ..raw.to_panda_message()
+                    })
+                    .context("Failed to deserialize row")
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.attach_attachments(chat_id, &mut replies).await?;
+        Ok(replies)
+    }
+
+    async fn get_mentions(
+        &self,
+        user_id: Uuid,
+        before: Option<Uuid>,
+        limit: i32,
+    ) -> Result<MessagePage> {
+        let (query_result, paging_state) = match before {
+            Some(before) => {
+                let statement = Statement::new(
+                    "SELECT message_id, chat_id, parent_id FROM ks.mentions_by_user WHERE user_id = ? AND message_id < ?",
+                )
+                .with_page_size(limit);
+                self.session
+                    .query_single_page(
+                        statement,
+                        (user_id, CqlTimeuuid::from(before)),
+                        PagingState::start(),
+                    )
+                    .await
+            }
+            None => {
+                let statement = Statement::new(
+                    "SELECT message_id, chat_id, parent_id FROM ks.mentions_by_user WHERE user_id = ?",
+                )
+                .with_page_size(limit);
+                self.session
+                    .query_single_page(statement, (user_id,), PagingState::start())
+                    .await
+            }
+        }
+        .context("Failed to execute query")?;
+        let rows = query_result
+            .into_rows_result()
+            .context("Failed to parse rows result")?
+            .rows::<(CqlTimeuuid, Uuid, Option<CqlTimeuuid>)>()
+            .context("Failed to access rows iterator")?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // The cursor follows the feed, even when its last messages are filtered out
+        let next_cursor = if paging_state.finished() {
+            None
+        } else {
+            rows.last()
+                .map(|(message_id, _, _)| Uuid::from(*message_id))
+        };
+        let messages = try_join_all(rows.into_iter().map(|(message_id, chat_id, parent_id)| {
+            self.fetch_mentioning(chat_id, parent_id, message_id)
+        }))
+        .await?
+        .into_iter()
+        .flatten()
+        .filter(|message| !message.deleted && message.mentions.iter().any(|m| m.user_id == user_id))
+        .collect();
+
+        Ok(MessagePage {
+            messages,
+            next_cursor,
+        })
+    }
+
     async fn get_chats_for_user(&self, user_id: Uuid) -> Result<Vec<Chat>> {
-        let chats = self
+        let chats: Vec<Chat> = self
             .session
             .query_unpaged(
-                "SELECT * FROM ks.chats WHERE members CONTAINS ? ALLOW FILTERING",
+                "SELECT chat_id, members, name, created_at FROM ks.chats_by_user WHERE user_id = ?",
                 ((user_id),),
             )
             .await
@@ -154,7 +971,325 @@ impl Db for ScyllaDb {
             .rows()
             .context("No Rows not found")?
             .collect::<Result<Vec<_>, _>>()?;
-        Ok(chats)
+
+        // Two concurrent touch_chat can leave a stale older row behind, keep the most recent one
+        let mut seen = HashSet::new();
+        let chats = chats.into_iter().filter(|chat| seen.insert(chat.chat_id));
+
+        let chats: Vec<Chat> = chats.collect();
+
+        // The receipts of every chat in one read, then one count per chat
+        let chat_ids: Vec<Uuid> = chats.iter().map(|chat| chat.chat_id).collect();
+        let receipts = self.get_read_receipts_of_user(user_id, &chat_ids).await?;
+        try_join_all(chats.into_iter().map(|mut chat| {
+            let last_read = receipts.get(&chat.chat_id).copied();
+            async move {
+                chat.unread = self.count_unread(chat.chat_id, user_id, last_read).await?;
+                Ok::<_, anyhow::Error>(chat)
+            }
+        }))
+        .await
+    }
+
+    async fn get_chat_ids_for_user(&self, user_id: Uuid) -> Result<Vec<Uuid>> {
+        let chat_ids = self
+            .session
+            .query_unpaged(
+                "SELECT chat_id FROM ks.chats_by_user WHERE user_id = ?",
+                (user_id,),
+            )
+            .await
+            .context("Failed to execute query")?
+            .into_rows_result()
+            .context("Failed to parse rows result")?
+            .rows::<(Uuid,)>()
+            .context("Failed to access rows iterator")?
+            .map(|row| row.map(|(chat_id,)| chat_id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // A chat can have a stale row left behind by touch_chat
+        let mut seen = HashSet::new();
+        Ok(chat_ids
+            .into_iter()
+            .filter(|chat_id| seen.insert(*chat_id))
+            .collect())
+    }
+
+    async fn add_reaction(&self, change: &ReactionChange) -> Result<()> {
+        // Chunks are stored concurrently, the time of the change decides between an add and a
+        // remove of the same reaction rather than the order they reach ScyllaDB
+        self.insert_data(
+            "INSERT INTO ks.reactions (chat_id, message_id, emoji, user_id) VALUES (?, ?, ?, ?) USING TIMESTAMP ?",
+            (
+                change.chat_id,
+                CqlTimeuuid::from(change.message_id),
+                &change.emoji,
+                change.user_id,
+                reaction_timestamp(change),
+            ),
+        )
+        .await
+        .context("Failed to add reaction")
+    }
+
+    async fn remove_reaction(&self, change: &ReactionChange) -> Result<()> {
+        self.insert_data(
+            "DELETE FROM ks.reactions USING TIMESTAMP ? WHERE chat_id = ? AND message_id = ? AND emoji = ? AND user_id = ?",
+            (
+                reaction_timestamp(change),
+                change.chat_id,
+                CqlTimeuuid::from(change.message_id),
+                &change.emoji,
+                change.user_id,
+            ),
+        )
+        .await
+        .context("Failed to remove reaction")
+    }
+
+    async fn get_reactions(&self, chat_id: Uuid, message_id: Uuid) -> Result<Vec<Reaction>> {
+        let rows = self
+            .session
+            .query_unpaged(
+                "SELECT emoji, user_id FROM ks.reactions WHERE chat_id = ? AND message_id = ?",
+                (chat_id, CqlTimeuuid::from(message_id)),
+            )
+            .await
+            .context("Failed to fetch reactions")?
+            .into_rows_result()
+            .context("Failed to parse rows result")?
+            .rows::<(String, Uuid)>()
+            .context("Failed to access rows iterator")?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Reaction::aggregate(rows))
+    }
+
+    async fn get_attachments(&self, chat_id: Uuid, message_id: Uuid) -> Result<Vec<Attachment>> {
+        let attachments = self
+            .session
+            .query_unpaged(
+                "SELECT attachment_id, filename, content_type, size, thumbnail FROM ks.attachments WHERE chat_id = ? AND message_id = ?",
+                (chat_id, CqlTimeuuid::from(message_id)),
+            )
+            .await
+            .context("Failed to fetch attachments")?
+            .into_rows_result()
+            .context("Failed to parse rows result")?
+            .rows::<Attachment>()
+            .context("Failed to access rows iterator")?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(attachments)
+    }
+
+    async fn get_attachment(
+        &self,
+        chat_id: Uuid,
+        message_id: Uuid,
+        attachment_id: Uuid,
+    ) -> Result<Attachment> {
+        self.fetch_single(
+            "SELECT attachment_id, filename, content_type, size, thumbnail FROM ks.attachments WHERE chat_id = ? AND message_id = ? AND attachment_id = ?",
+            (chat_id, CqlTimeuuid::from(message_id), attachment_id),
+        )
+        .await
+        .context("Could not fetch attachment")
+    }
+
+    async fn mark_read(&self, chat_id: Uuid, user_id: Uuid, message_id: Uuid) -> Result<bool> {
+        const MOVE_FORWARD: &str = "UPDATE ks.read_receipts SET last_read = ? WHERE chat_id = ? AND user_id = ? IF last_read < ?";
+        let last_read = CqlTimeuuid::from(message_id);
+        // Reading an older message again, from another tab, does not make the newer ones unread
+        // The receipt usually exists already, so this is the only LWT
+        let row = self
+            .session
+            .query_unpaged(MOVE_FORWARD, (last_read, chat_id, user_id, last_read))
+            .await
+            .context("Failed to update read receipt")?
+            .into_rows_result()?
+            .first_row::<Row>()?;
+        let applied = row
+            .columns
+            .first()
+            .and_then(|applied| applied.as_ref()?.as_boolean())
+            .unwrap_or(false);
+        // Not applied with the current value returned: it is already newer
+        let exists = row.columns.get(1).is_some_and(|current| current.is_some());
+        if applied || exists {
+            return Ok(applied);
+        }
+
+        // First read of the chat
+        let inserted = self
+            .compare_and_set(
+                "INSERT INTO ks.read_receipts (chat_id, user_id, last_read) VALUES (?, ?, ?) IF NOT EXISTS",
+                (chat_id, user_id, last_read),
+            )
+            .await
+            .context("Failed to insert read receipt")?;
+        if inserted {
+            return Ok(true);
+        }
+        // Another tab inserted it meanwhile
+        self.compare_and_set(MOVE_FORWARD, (last_read, chat_id, user_id, last_read))
+            .await
+            .context("Failed to update read receipt")
+    }
+
+    async fn get_read_receipts(&self, chat_id: Uuid) -> Result<HashMap<Uuid, Uuid>> {
+        self.session
+            .query_unpaged(
+                "SELECT user_id, last_read FROM ks.read_receipts WHERE chat_id = ?",
+                (chat_id,),
+            )
+            .await
+            .context("Failed to execute query")?
+            .into_rows_result()
+            .context("Failed to parse rows result")?
+            .rows::<(Uuid, CqlTimeuuid)>()
+            .context("Failed to access rows iterator")?
+            .map(|row| {
+                row.map(|(user_id, last_read)| (user_id, Uuid::from(last_read)))
+                    .context("Failed to deserialize row")
+            })
+            .collect()
+    }
+
+    async fn touch_chat(&self, chat_id: Uuid, at: DateTime<Utc>) -> Result<()> {
+        let at = truncate_to_millis(at);
+        // Every node touches the chats of the messages it consumes, and members change meanwhile:
+        // the rows are only moved by the touch whose LWT saw the same activity and members
+        for _ in 0..CAS_ATTEMPTS {
+            let (members, name, created_at, previous): ChatActivityRow = self
+                .fetch_single(
+                    "SELECT members, name, created_at, last_activity_at FROM ks.chats WHERE chat_id = ?",
+                    (chat_id,),
+                )
+                .await
+                .context("Could not fetch chat activity")?;
+
+            // Activity only moves forward
+            if previous.is_some_and(|previous| previous >= at) {
+                return Ok(());
+            }
+
+            // Taken before the LWT, a members change applied after it writes later and its
+            // rows win over these ones whatever order the batches land in
+            let written_at = Utc::now().timestamp_micros();
+            let applied = self
+                .compare_and_set(
+                    "UPDATE ks.chats SET last_activity_at = ? WHERE chat_id = ? IF last_activity_at = ? AND members = ?",
+                    (at, chat_id, previous, &members),
+                )
+                .await
+                .context("Failed to update chat activity")?;
+            if !applied {
+                continue;
+            }
+
+            let mut batch: Batch = Default::default();
+            batch.set_timestamp(Some(written_at));
+            let mut batch_values: Vec<Box<dyn SerializeRow + Send + Sync>> = Vec::new();
+            for member in &members {
+                if let Some(previous) = previous {
+                    batch.append_statement(
+                        "DELETE FROM ks.chats_by_user WHERE user_id = ? AND last_activity_at = ? AND chat_id = ?",
+                    );
+                    batch_values.push(Box::new((*member, previous, chat_id)));
+                }
+                batch.append_statement(
+                    "INSERT INTO ks.chats_by_user (user_id, last_activity_at, chat_id, members, name, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+                );
+                batch_values.push(Box::new((
+                    *member,
+                    at,
+                    chat_id,
+                    members.clone(),
+                    name.clone(),
+                    created_at,
+                )));
+            }
+
+            self.session
+                .batch(&batch, batch_values)
+                .await
+                .context("Failed to update chat activity")?;
+            return Ok(());
+        }
+        Err(anyhow::anyhow!(
+            "Activity of chat {} changed concurrently too many times",
+            chat_id
+        ))
+    }
+
+    async fn add_chat_member(&self, chat_id: Uuid, user_id: Uuid) -> Result<Chat> {
+        self.update_members(chat_id, |members| {
+            let mut members = members.to_vec();
+            if !members.contains(&user_id) {
+                members.push(user_id);
+            }
+            members
+        })
+        .await
+    }
+
+    async fn remove_chat_member(&self, chat_id: Uuid, user_id: Uuid) -> Result<Chat> {
+        self.update_members(chat_id, |members| {
+            members
+                .iter()
+                .copied()
+                .filter(|member| *member != user_id)
+                .collect()
+        })
+        .await
+    }
+
+    async fn set_chat_roles(&self, chat_id: Uuid, changes: &[(Uuid, Role)]) -> Result<Chat> {
+        for _ in 0..CAS_ATTEMPTS {
+            let (chat_id, members, name, created_at, _, stored_roles) =
+                self.fetch_chat_row(chat_id).await?;
+
+            let mut roles = parse_roles(stored_roles.clone());
+            for (user_id, role) in changes {
+                if !members.contains(user_id) {
+                    return Err(anyhow::anyhow!(
+                        "User {} is not a member of chat {}",
+                        user_id,
+                        chat_id
+                    ));
+                }
+                match role {
+                    Role::Member => roles.remove(user_id),
+                    role => roles.insert(*user_id, *role),
+                };
+            }
+            let new_roles: HashMap<Uuid, &str> = roles
+                .iter()
+                .map(|(user_id, role)| (*user_id, role.as_str()))
+                .collect();
+
+            // Conditioned on the members too: a role must not land on someone who just left
+            let applied = self
+                .compare_and_set(
+                    "UPDATE ks.chats SET roles = ? WHERE chat_id = ? IF members = ? AND roles = ?",
+                    (&new_roles, chat_id, &members, &stored_roles),
+                )
+                .await
+                .context("Failed to update roles")?;
+            if applied {
+                return Ok(Chat {
+                    chat_id,
+                    members,
+                    name,
+                    created_at,
+                    roles,
+                    unread: 0,
+                });
+            }
+        }
+        Err(anyhow::anyhow!(
+            "Roles of chat {} changed concurrently too many times",
+            chat_id
+        ))
     }
 
     async fn get_user_by_username(&self, username: &str) -> Result<User> {
@@ -168,38 +1303,51 @@ impl Db for ScyllaDb {
 
         self.get_user(user_id).await
     }
-    async fn insert_message(&self, message: PandaMessage) -> Result<PandaMessage> {
-        let message_id = CqlTimeuuid::from(message.message_id);
-        let chat_id = message.chat_id;
-        let sender_id = message.sender_id;
-        let content = message.content;
-        let values = (message_id, chat_id, sender_id, content.clone());
-        self.insert_data(
-            "INSERT INTO ks.messages (message_id, chat_id, sender_id, content) VALUES (?, ?, ?, ?)",
-            values,
-        )
-        .await?;
-        let message_id = Uuid::from(message_id);
-        Ok(PandaMessage {
-            message_id,
-            content,
-            chat_id,
-            sender_id,
-        })
+    async fn get_password_hash(&self, user_id: Uuid) -> Result<String> {
+        let (password_hash,): (Option<String>,) = self
+            .fetch_single(
+                "SELECT password_hash FROM ks.users WHERE user_id = ?",
+                (user_id,),
+            )
+            .await
+            .context("Could not fetch password hash")?;
+
+        // Accounts created before passwords existed have no hash and can not log in
+        password_hash.context("User has no password set")
     }
     async fn get_user(&self, user_id: Uuid) -> Result<User> {
-        self.fetch_single("SELECT * FROM ks.users WHERE user_id = ?", (user_id,))
-            .await
-            .context("Could not fetch user")
+        self.fetch_single(
+            "SELECT user_id, username, created_at, updated_at FROM ks.users WHERE user_id = ?",
+            (user_id,),
+        )
+        .await
+        .context("Could not fetch user")
     }
 
     async fn get_chat(&self, chat_id: Uuid) -> Result<Chat> {
-        self.fetch_single("SELECT * FROM ks.chats WHERE chat_id = ?", (chat_id,))
+        let (chat_id, members, name, created_at, _, roles) = self.fetch_chat_row(chat_id).await?;
+        Ok(Chat {
+            chat_id,
+            members,
+            name,
+            created_at,
+            roles: parse_roles(roles),
+            unread: 0,
+        })
+    }
+
+    async fn get_message(&self, chat_id: Uuid, message_id: Uuid) -> Result<PandaMessage> {
+        let raw: RawPandaMessage = self
+            .fetch_single(
+                "SELECT chat_id, sender_id, content, message_id, edited_at, deleted, mentions FROM ks.messages WHERE chat_id = ? AND message_id = ?",
+                (chat_id, CqlTimeuuid::from(message_id)),
+            )
             .await
-            .context("Could not fetch chat")
+            .context("Could not fetch message")?;
+        Ok(raw.to_panda_message())
     }
 
-    async fn create_user(&self, username: &str) -> Result<User> {
+    async fn create_user(&self, username: &str, password_hash: &str) -> Result<User> {
         let now = Utc::now();
         let user_id = Uuid::now_v1(&NODE_ID);
 
@@ -219,9 +1367,9 @@ impl Db for ScyllaDb {
             return Err(anyhow::anyhow!("Username '{}' already exists", username));
         }
 
-        let values = (user_id, username, now, now);
+        let values = (user_id, username, password_hash, now, now);
         self.insert_data(
-            "INSERT INTO ks.users (user_id, username, created_at, updated_at) VALUES (?, ?, ?, ?)",
+            "INSERT INTO ks.users (user_id, username, password_hash, created_at, updated_at) VALUES (?, ?, ?, ?, ?)",
             values,
         )
         .await
@@ -235,24 +1383,134 @@ impl Db for ScyllaDb {
         })
     }
 
-    async fn create_chat(&self, name: &str, members: &[Uuid]) -> Result<Chat> {
-        let now = Utc::now();
+    async fn create_chat(&self, name: &str, members: &[Uuid], owner: Uuid) -> Result<Chat> {
+        let now = truncate_to_millis(Utc::now());
         let chat_id = Uuid::now_v1(&NODE_ID);
 
-        let values = (chat_id, members, name, now);
+        // Logged batch: the chat and every member's dashboard entry are written together
+        let mut batch: Batch = Default::default();
+        let mut batch_values: Vec<Box<dyn SerializeRow + Send + Sync>> = Vec::new();
+        batch.append_statement(
+            "INSERT INTO ks.chats (chat_id, members, name, created_at, last_activity_at, roles) VALUES (?, ?, ?, ?, ?, ?)",
+        );
+        batch_values.push(Box::new((
+            chat_id,
+            members.to_vec(),
+            name.to_string(),
+            now,
+            now,
+            HashMap::from([(owner, Role::Owner.as_str())]),
+        )));
+        for member in members {
+            batch.append_statement(
+                "INSERT INTO ks.chats_by_user (user_id, last_activity_at, chat_id, members, name, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+            );
+            batch_values.push(Box::new((
+                *member,
+                now,
+                chat_id,
+                members.to_vec(),
+                name.to_string(),
+                now,
+            )));
+        }
 
-        self.insert_data(
-            "INSERT INTO ks.chats (chat_id, members, name, created_at) VALUES (?, ?, ?, ?)",
-            values,
-        )
-        .await
-        .context("Failed to create chat")?;
+        self.session
+            .batch(&batch, batch_values)
+            .await
+            .context("Failed to create chat")?;
 
         Ok(Chat {
             chat_id,
             members: members.to_vec(),
             name: name.to_string(),
             created_at: now,
+            roles: HashMap::from([(owner, Role::Owner)]),
+            unread: 0,
         })
     }
+
+    async fn create_session(&self, user_id: Uuid, ttl: Duration) -> Result<UserSession> {
+        let now = Utc::now();
+        // v4 and not v1: the id is the bearer secret, it must not be guessable
+        let session = UserSession {
+            session_id: Uuid::new_v4(),
+            user_id,
+            created_at: now,
+            expires_at: now + ttl,
+        };
+        self.store_session(&session, ttl).await?;
+        Ok(session)
+    }
+
+    async fn get_session(&self, session_id: Uuid) -> Result<UserSession> {
+        self.fetch_single(
+            "SELECT session_id, user_id, created_at, expires_at FROM ks.sessions WHERE session_id = ?",
+            (session_id,),
+        )
+        .await
+        .context("Could not fetch session")
+    }
+
+    async fn refresh_session(&self, session: &UserSession, ttl: Duration) -> Result<UserSession> {
+        let session = UserSession {
+            expires_at: Utc::now() + ttl,
+            ..session.clone()
+        };
+        self.store_session(&session, ttl).await?;
+        Ok(session)
+    }
+
+    async fn delete_session(&self, session_id: Uuid) -> Result<()> {
+        self.session
+            .query_unpaged(
+                "DELETE FROM ks.sessions WHERE session_id = ?",
+                (session_id,),
+            )
+            .await
+            .context("Failed to delete session")?;
+        Ok(())
+    }
+}
+
+// members, name, created_at, last_activity_at
+type ChatActivityRow = (Vec<Uuid>, String, DateTime<Utc>, Option<DateTime<Utc>>);
+// chat_id, members, name, created_at, last_activity_at, roles
+type ChatRow = (
+    Uuid,
+    Vec<Uuid>,
+    String,
+    DateTime<Utc>,
+    Option<DateTime<Utc>>,
+    Option<HashMap<Uuid, String>>,
+);
+
+// Lightweight transaction retries before giving up on a members or roles change
+const CAS_ATTEMPTS: usize = 5;
+/// The dashboard shows "99+" past it, no need to scan a whole history of unread messages
+const MAX_UNREAD: i32 = 100;
+
+/// Write time of a reaction change in microseconds, now for the events that do not carry it
+fn reaction_timestamp(change: &ReactionChange) -> i64 {
+    change.at.unwrap_or_else(Utc::now).timestamp_micros()
+}
+
+/// Roles as stored in ks.chats, an empty map is read back as null
+fn parse_roles(roles: Option<HashMap<Uuid, String>>) -> HashMap<Uuid, Role> {
+    roles
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(user_id, role)| match role.parse() {
+            Ok(role) => Some((user_id, role)),
+            Err(e) => {
+                tracing::warn!("Ignoring role of user {}: {:#}", user_id, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Scylla timestamps have a millisecond precision, values compared with stored ones must too
+fn truncate_to_millis(at: DateTime<Utc>) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(at.timestamp_millis()).unwrap_or(at)
 }
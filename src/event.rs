@@ -0,0 +1,320 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::schema::{PandaMessage, ReactionChange, ReadReceipt, Typing};
+
+/// Version written in the envelope of every event produced by this build
+/// Bump it when the shape of an existing event changes, adding an event does not need it
+pub const EVENT_VERSION: u16 = 1;
+
+/// Everything that goes through the chat-messages topic
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+// Named after their wire tags, the topic is not meant to only carry message events
+#[allow(clippy::enum_variant_names)]
+pub enum ChatEvent {
+    MessagePosted(PandaMessage),
+    MessageEdited {
+        chat_id: Uuid,
+        message_id: Uuid,
+        sender_id: Uuid,
+        content: String,
+        edited_at: DateTime<Utc>,
+    },
+    MessageDeleted {
+        chat_id: Uuid,
+        message_id: Uuid,
+        sender_id: Uuid,
+    },
+    ReactionAdded(ReactionChange),
+    ReactionRemoved(ReactionChange),
+    /// Ephemeral: relayed by the consumers, never written to ScyllaDB
+    Typing(Typing),
+    /// Ephemeral too, the receipt itself is written to ScyllaDB by the node that got it
+    ReadReceipt(ReadReceipt),
+}
+
+impl ChatEvent {
+    /// Partition key: the events of a message share the key of its sender, so they stay ordered
+    pub fn key(&self) -> Uuid {
+        match self {
+            ChatEvent::MessagePosted(message) => message.sender_id,
+            ChatEvent::MessageEdited { sender_id, .. }
+            | ChatEvent::MessageDeleted { sender_id, .. } => *sender_id,
+            ChatEvent::ReactionAdded(change) | ChatEvent::ReactionRemoved(change) => {
+                change.sender_id
+            }
+            ChatEvent::Typing(typing) => typing.user_id,
+            ChatEvent::ReadReceipt(receipt) => receipt.user_id,
+        }
+    }
+
+    /// The message as stored and rendered once the event is applied, `None` for the events that
+    /// are not a message, reactions have a table of their own
+    pub fn into_message(self) -> Option<PandaMessage> {
+        let message = match self {
+            ChatEvent::MessagePosted(message) => message,
+            ChatEvent::MessageEdited {
+                chat_id,
+                message_id,
+                sender_id,
+                content,
+                edited_at,
+            } => PandaMessage {
+                chat_id,
+                sender_id,
+                content,
+                message_id,
+                edited_at: Some(edited_at),
+                deleted: false,
+                reactions: Vec::new(),
+                mentions: Vec::new(),
+                attachments: Vec::new(),
+                parent_id: None,
+                reply_count: 0,
+            },
+            ChatEvent::MessageDeleted {
+                chat_id,
+                message_id,
+                sender_id,
+            } => PandaMessage {
+                chat_id,
+                sender_id,
+                content: String::new(),
+                message_id,
+                edited_at: None,
+                deleted: true,
+                reactions: Vec::new(),
+                mentions: Vec::new(),
+                attachments: Vec::new(),
+                parent_id: None,
+                reply_count: 0,
+            },
+            ChatEvent::ReactionAdded(_)
+            | ChatEvent::ReactionRemoved(_)
+            | ChatEvent::Typing(_)
+            | ChatEvent::ReadReceipt(_) => return None,
+        };
+        Some(message)
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        serde_json::to_string(&Envelope {
+            version: EVENT_VERSION,
+            event: self,
+        })
+        .context("Failed to serialize event")
+    }
+
+    /// Decode a record of the topic, `None` for an event this build does not know
+    /// Payloads without a version are bare `PandaMessage`, written before the envelope existed
+    pub fn decode(payload: &str) -> Result<Option<Self>> {
+        let value: serde_json::Value =
+            serde_json::from_str(payload).context("Payload is not JSON")?;
+        let Some(version) = value.get("version") else {
+            let message: PandaMessage =
+                serde_json::from_value(value).context("Invalid legacy message")?;
+            return Ok(Some(message.into()));
+        };
+
+        let kind = value.get("type").and_then(|kind| kind.as_str());
+        let version = version.as_u64().context("Invalid event version")?;
+        // A newer producer may have changed the shape of events this build knows by name
+        if version > u64::from(EVENT_VERSION) {
+            tracing::warn!(
+                "Skipping event {:?} of version {}, this build reads up to {}",
+                kind.unwrap_or("<none>"),
+                version,
+                EVENT_VERSION
+            );
+            return Ok(None);
+        }
+        if !kind.is_some_and(|kind| KNOWN_EVENTS.contains(&kind)) {
+            tracing::warn!(
+                "Skipping unknown event {:?} (version {})",
+                kind.unwrap_or("<none>"),
+                version
+            );
+            return Ok(None);
+        }
+        let envelope: OwnedEnvelope = serde_json::from_value(value).context("Invalid event")?;
+        Ok(Some(envelope.event))
+    }
+}
+
+impl From<PandaMessage> for ChatEvent {
+    fn from(message: PandaMessage) -> Self {
+        if message.deleted {
+            ChatEvent::MessageDeleted {
+                chat_id: message.chat_id,
+                message_id: message.message_id,
+                sender_id: message.sender_id,
+            }
+        } else if let Some(edited_at) = message.edited_at {
+            ChatEvent::MessageEdited {
+                chat_id: message.chat_id,
+                message_id: message.message_id,
+                sender_id: message.sender_id,
+                content: message.content,
+                edited_at,
+            }
+        } else {
+            ChatEvent::MessagePosted(message)
+        }
+    }
+}
+
+// Tags of the ChatEvent variants, anything else is skipped by the consumer
+const KNOWN_EVENTS: &[&str] = &[
+    "message_posted",
+    "message_edited",
+    "message_deleted",
+    "reaction_added",
+    "reaction_removed",
+    "typing",
+    "read_receipt",
+];
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u16,
+    #[serde(flatten)]
+    event: &'a ChatEvent,
+}
+
+#[derive(Deserialize)]
+struct OwnedEnvelope {
+    #[serde(flatten)]
+    event: ChatEvent,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &str) -> PandaMessage {
+        PandaMessage {
+            chat_id: Uuid::new_v4(),
+            sender_id: Uuid::new_v4(),
+            content: content.to_string(),
+            message_id: Uuid::new_v4(),
+            edited_at: None,
+            deleted: false,
+            reactions: Vec::new(),
+            mentions: Vec::new(),
+            attachments: Vec::new(),
+            parent_id: None,
+            reply_count: 0,
+        }
+    }
+
+    #[test]
+    fn test_event_round_trips_through_envelope() {
+        let original = message("hi");
+        let payload = ChatEvent::MessagePosted(original.clone()).encode().unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(value["version"], EVENT_VERSION);
+        assert_eq!(value["type"], "message_posted");
+
+        let decoded = ChatEvent::decode(&payload).unwrap().unwrap();
+        let decoded = decoded.into_message().unwrap();
+        assert_eq!(decoded.message_id, original.message_id);
+        assert_eq!(decoded.content, "hi");
+    }
+
+    #[test]
+    fn test_legacy_panda_message_is_decoded_as_posted() {
+        let original = message("from before the envelope");
+        let payload = format!(
+            r#"{{"chat_id":"{}","sender_id":"{}","content":"{}","message_id":"{}"}}"#,
+            original.chat_id, original.sender_id, original.content, original.message_id
+        );
+
+        let decoded = ChatEvent::decode(&payload).unwrap().unwrap();
+
+        assert!(matches!(&decoded, ChatEvent::MessagePosted(m) if m.content == original.content));
+    }
+
+    #[test]
+    fn test_typing_is_not_a_message() {
+        let typing = Typing {
+            chat_id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            username: "alice".to_string(),
+            at: Utc::now(),
+        };
+        let payload = ChatEvent::Typing(typing.clone()).encode().unwrap();
+
+        let decoded = ChatEvent::decode(&payload).unwrap().unwrap();
+
+        assert_eq!(decoded.key(), typing.user_id);
+        assert!(matches!(&decoded, ChatEvent::Typing(t) if t.username == "alice"));
+        assert!(decoded.into_message().is_none());
+    }
+
+    #[test]
+    fn test_read_receipt_is_not_a_message() {
+        let receipt = ReadReceipt {
+            chat_id: Uuid::new_v4(),
+            message_id: Uuid::new_v4(),
+            sender_id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            username: "bob".to_string(),
+        };
+        let payload = ChatEvent::ReadReceipt(receipt.clone()).encode().unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(value["type"], "read_receipt");
+
+        let decoded = ChatEvent::decode(&payload).unwrap().unwrap();
+        assert_eq!(decoded.key(), receipt.user_id);
+        assert!(decoded.into_message().is_none());
+    }
+
+    #[test]
+    fn test_reaction_stays_ordered_with_its_message() {
+        let original = message("react to me");
+        let change = ReactionChange {
+            chat_id: original.chat_id,
+            message_id: original.message_id,
+            sender_id: original.sender_id,
+            user_id: Uuid::new_v4(),
+            emoji: "🎉".to_string(),
+            at: Some(Utc::now()),
+        };
+        let payload = ChatEvent::ReactionAdded(change).encode().unwrap();
+
+        let decoded = ChatEvent::decode(&payload).unwrap().unwrap();
+
+        assert_eq!(decoded.key(), original.sender_id);
+        assert!(matches!(&decoded, ChatEvent::ReactionAdded(c) if c.emoji == "🎉"));
+        assert!(decoded.into_message().is_none());
+    }
+
+    #[test]
+    fn test_unknown_event_is_skipped() {
+        let payload = r#"{"version":1,"type":"hologram_sent","data":{"chat_id":"x"}}"#;
+
+        assert!(ChatEvent::decode(payload).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_known_event_of_a_newer_version_is_skipped() {
+        let payload = format!(
+            r#"{{"version":{},"type":"message_deleted","data":{{"chat_id":"x"}}}}"#,
+            EVENT_VERSION + 1
+        );
+
+        assert!(ChatEvent::decode(&payload).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_malformed_known_event_is_an_error() {
+        let payload = r#"{"version":1,"type":"message_deleted","data":{"chat_id":"x"}}"#;
+
+        assert!(ChatEvent::decode(payload).is_err());
+    }
+}
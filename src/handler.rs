@@ -1,21 +1,45 @@
-use std::str::FromStr;
+use std::{collections::HashMap, str::FromStr};
 
 use crate::{
-    AppState, NODE_ID,
-    schema::{Chat, CreatMessage, CreateChat, CreateUser, LoginPayload, PandaMessage, User},
-    websocket::handle_socket,
+    AppState, NODE_ID, attachment,
+    auth::{
+        CurrentUser, DUMMY_PASSWORD_HASH, end_session, hash_password, start_session,
+        verify_password,
+    },
+    db::is_not_found,
+    event::ChatEvent,
+    presence::PresenceView,
+    schema::{
+        AddMember, Attachment, Chat, ConnectQuery, CreatMessage, CreateChat, CreateUser,
+        EditMessage, LoginPayload, MarkRead, MessagesQuery, PandaMessage, ReactionChange,
+        ReadReceipt, Role, SYSTEM_SENDER_ID, SearchQuery, SetRole, Thread, TransferOwnership,
+        Typing, Upload, User, WireFormat,
+    },
+    search::{SearchFilter, SearchHit},
+    sse::event_stream,
+    storage::{attachment_key, thumbnail_key},
+    websocket::{JSON_SUBPROTOCOL, handle_socket},
 };
 use anyhow::{Context, anyhow};
 use axum::{
     Router,
-    extract::{Form, Json, Path, State, WebSocketUpgrade},
-    http::{HeaderMap, StatusCode},
-    response::{Html, IntoResponse, Redirect, Response},
-    routing::{get, post},
+    extract::{
+        DefaultBodyLimit, Form, Json, Multipart, Path, Query, State, WebSocketUpgrade,
+        multipart::MultipartError,
+    },
+    http::{HeaderMap, StatusCode, header},
+    response::{
+        Html, IntoResponse, Redirect, Response,
+        sse::{KeepAlive, Sse},
+    },
+    routing::{delete, get, patch, post, put},
 };
 use axum_extra::extract::CookieJar;
 use axum_prometheus::PrometheusMetricLayer;
+use bytes::{Bytes, BytesMut};
+use chrono::{NaiveTime, Utc};
 use metrics::counter;
+use scylla::value::CqlTimeuuid;
 use serde::Serialize;
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use uuid::Uuid;
@@ -24,6 +48,8 @@ pub async fn create_router(
     state: AppState,
     prometheus_layer: PrometheusMetricLayer<'static>,
 ) -> anyhow::Result<Router> {
+    // Every file of an upload at its largest, and room for the text fields
+    let upload_limit = state.max_attachment_bytes * attachment::MAX_ATTACHMENTS + 64 * 1024;
     let app = Router::new()
         // UI routes
         .route("/", get(render_index))
@@ -34,11 +60,48 @@ pub async fn create_router(
         .route("/login", post(login))
         .route("/users", post(create_user))
         .route("/chats", post(create_chat))
+        .route("/users/me/mentions", get(get_my_mentions))
+        .route("/search", get(get_search))
         .route("/users/{user_id}", get(get_user))
+        .route("/users/{user_id}/presence", get(get_presence))
         .route("/chats/{chat_id}", get(get_chat))
         .route("/chats/{chat_id}/messages", post(post_message))
         .route("/chats/{chat_id}/messages", get(get_messages))
+        .route(
+            "/chats/{chat_id}/messages/{message_id}",
+            patch(edit_message).delete(delete_message),
+        )
+        .route(
+            "/chats/{chat_id}/messages/{message_id}/thread",
+            get(get_thread),
+        )
+        .route(
+            "/chats/{chat_id}/messages/{message_id}/reactions/{emoji}",
+            put(add_reaction).delete(remove_reaction),
+        )
+        .route(
+            "/chats/{chat_id}/attachments",
+            post(upload_attachments).layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route(
+            "/chats/{chat_id}/messages/{message_id}/attachments/{attachment_id}",
+            get(download_attachment),
+        )
+        .route(
+            "/chats/{chat_id}/messages/{message_id}/attachments/{attachment_id}/thumbnail",
+            get(download_thumbnail),
+        )
+        .route("/chats/{chat_id}/members", post(add_member))
+        .route("/chats/{chat_id}/members/{user_id}", delete(remove_member))
+        .route(
+            "/chats/{chat_id}/members/{user_id}/role",
+            put(set_member_role),
+        )
+        .route("/chats/{chat_id}/owner", post(transfer_ownership))
+        .route("/chats/{chat_id}/typing", post(post_typing))
+        .route("/chats/{chat_id}/read", post(post_read))
         .route("/ws/connect/{user_id}", get(get_websocket))
+        .route("/events", get(get_events))
         // Serving static file and Prometheus
         .nest_service("/static", ServeDir::new("static"))
         .layer(TraceLayer::new_for_http())
@@ -50,52 +113,299 @@ pub async fn create_router(
 // Begin of handler definition
 // ----- **** ----
 
+// Messages per page of chat history
+const DEFAULT_PAGE_SIZE: i32 = 50;
+const MAX_PAGE_SIZE: i32 = 200;
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 100;
+// Family emojis are the longest common sequences, with their joiners
+const MAX_EMOJI_CHARS: usize = 8;
+
 async fn get_websocket(
-    ws: WebSocketUpgrade,
     State(state): State<AppState>,
+    current_user: CurrentUser,
     Path(user_id): Path<String>,
-) -> ApiResult<impl IntoResponse> {
+    Query(mut query): Query<ConnectQuery>,
+    ws: WebSocketUpgrade,
+) -> ApiResult<Response> {
     let user_id = Uuid::from_str(&user_id)?;
-    Ok(ws.on_upgrade(move |socket| handle_socket(socket, state, user_id)))
+    // A session may only subscribe to its own message stream
+    if user_id != current_user.user_id {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    if let Some(chat_id) = query.chat_id {
+        require_member(&state, chat_id, user_id).await?;
+    }
+    let ws = ws.protocols([JSON_SUBPROTOCOL]);
+    if ws.selected_protocol().is_some() {
+        query.format = WireFormat::Json;
+    }
+    Ok(ws
+        .on_upgrade(move |socket| handle_socket(socket, state, current_user, query))
+        .into_response())
+}
+
+/// Same deliveries as the websocket, for the clients that can not upgrade
+async fn get_events(
+    State(state): State<AppState>,
+    current_user: CurrentUser,
+    Query(mut query): Query<ConnectQuery>,
+    headers: HeaderMap,
+) -> ApiResult<Response> {
+    // Sent by the browser when its EventSource reconnects, the id of the last message it got
+    if let Some(last_event_id) = headers.get("last-event-id") {
+        let last_event_id = last_event_id
+            .to_str()
+            .ok()
+            .and_then(|id| Uuid::from_str(id).ok())
+            .ok_or_else(|| AppError::bad_request(anyhow!("Invalid Last-Event-ID")))?;
+        query.last_seen = Some(last_event_id);
+    }
+    if let Some(chat_id) = query.chat_id {
+        require_member(&state, chat_id, current_user.user_id).await?;
+    }
+    let stream = event_stream(state, current_user, query).await;
+    // The keep-alive comments also tell a dead connection apart, writing to it fails
+    Ok(Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response())
 }
 
 async fn login(
     State(state): State<AppState>,
+    headers: HeaderMap,
     jar: CookieJar,
     Form(payload): Form<LoginPayload>,
-) -> ApiResult<impl IntoResponse> {
-    let user = state.db.get_user_by_username(&payload.username).await?;
+) -> ApiResult<Response> {
+    // Unknown usernames and wrong passwords get the same answer, after the same argon2 work, to
+    // avoid leaking which exists
+    let (user, password_hash) = match state.db.get_user_by_username(&payload.username).await {
+        Ok(user) => match state.db.get_password_hash(user.user_id).await {
+            Ok(password_hash) => (Some(user), password_hash),
+            Err(e) => {
+                tracing::debug!("Login failed for '{}': {:#}", payload.username, e);
+                (None, DUMMY_PASSWORD_HASH.to_string())
+            }
+        },
+        Err(e) => {
+            tracing::debug!("Login failed for '{}': {:#}", payload.username, e);
+            (None, DUMMY_PASSWORD_HASH.to_string())
+        }
+    };
+
+    // Argon2 is CPU bound on purpose, keep it off the async workers
+    let password = payload.password;
+    let is_valid =
+        tokio::task::spawn_blocking(move || verify_password(&password, &password_hash)).await?;
+    let Some(user) = user.filter(|_| is_valid) else {
+        return invalid_credentials(&state, &headers);
+    };
 
-    let mut cookie = axum_extra::extract::cookie::Cookie::new("user_id", user.user_id.to_string());
-    cookie.set_path("/");
+    let jar = start_session(&state, jar, user.user_id).await?;
+    if headers.contains_key("hx-request") {
+        return Ok((jar, [("HX-Redirect", "/dashboard")]).into_response());
+    }
+    Ok((jar, Redirect::to("/")).into_response())
+}
 
-    let jar = jar.add(cookie);
-    Ok((jar, Redirect::to("/")))
+/// 401 answer of the login form, an error partial for htmx and plain text otherwise
+fn invalid_credentials(state: &AppState, headers: &HeaderMap) -> ApiResult<Response> {
+    const MESSAGE: &str = "Invalid username or password";
+    if headers.contains_key("hx-request") {
+        let rendered = render_form_error(state, MESSAGE)?;
+        return Ok((StatusCode::UNAUTHORIZED, Html(rendered)).into_response());
+    }
+    Ok((StatusCode::UNAUTHORIZED, MESSAGE).into_response())
 }
-async fn logout(State(_state): State<AppState>, jar: CookieJar) -> ApiResult<impl IntoResponse> {
-    let mut cookie = axum_extra::extract::cookie::Cookie::new("user_id", "");
-    cookie.set_path("/");
 
-    cookie.make_removal();
-    let jar = jar.add(cookie);
+fn render_form_error(state: &AppState, error: &str) -> ApiResult<String> {
+    let mut context = tera::Context::new();
+    context.insert("error", error);
+    let rendered = state
+        .tera
+        .render("partials/form_error.html", &context)
+        .map_err(|e| anyhow!("Template rendering failed: {}", e))?;
+    Ok(rendered)
+}
+async fn logout(State(state): State<AppState>, jar: CookieJar) -> ApiResult<impl IntoResponse> {
+    let jar = end_session(&state, jar).await?;
     Ok((jar, Redirect::to("/")))
 }
 async fn get_messages(
     State(state): State<AppState>,
     Path(chat_id): Path<String>,
-) -> ApiResult<JsonWithStatus<Vec<PandaMessage>>> {
+    Query(query): Query<MessagesQuery>,
+    headers: HeaderMap,
+    current_user: CurrentUser,
+) -> ApiResult<Response> {
     let chat_id = Uuid::from_str(&chat_id)?;
-    let messages = state.db.get_messages(chat_id).await?;
+    require_member(&state, chat_id, current_user.user_id).await?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let page = state.db.get_messages(chat_id, query.before, limit).await?;
+
+    // Infinite scroll of the chat view: older messages and the next "load older" trigger
+    if headers.contains_key("hx-request") {
+        let mut context = tera::Context::new();
+        context.insert("chat_id", &chat_id);
+        context.insert("messages", &page.messages);
+        context.insert("next_cursor", &page.next_cursor);
+        context.insert("user_id", &current_user.user_id);
+        context.insert("history", &true);
+        let rendered = state
+            .tera
+            .render("partials/older_messages.html", &context)
+            .map_err(|e| anyhow!("Template rendering failed: {}", e))?;
+        return Ok(Html(rendered).into_response());
+    }
+
+    Ok(JsonWithStatus {
+        data: page,
+        status: StatusCode::OK,
+    }
+    .into_response())
+}
+/// Messages mentioning the caller across their chats, muted or not
+async fn get_my_mentions(
+    State(state): State<AppState>,
+    Query(query): Query<MessagesQuery>,
+    headers: HeaderMap,
+    current_user: CurrentUser,
+) -> ApiResult<Response> {
+    let user_id = current_user.user_id;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let mut page = state.db.get_mentions(user_id, query.before, limit).await?;
+    // The feed outlives membership, hide the chats the user has left
+    let mut messages = Vec::with_capacity(page.messages.len());
+    for message in page.messages {
+        if state
+            .members_cache
+            .is_member(state.db.as_ref(), message.chat_id, user_id)
+            .await?
+        {
+            messages.push(message);
+        }
+    }
+    page.messages = messages;
+
+    if headers.contains_key("hx-request") {
+        let mut context = tera::Context::new();
+        context.insert("messages", &page.messages);
+        context.insert("next_cursor", &page.next_cursor);
+        let rendered = state
+            .tera
+            .render("partials/mentions.html", &context)
+            .map_err(|e| anyhow!("Template rendering failed: {}", e))?;
+        return Ok(Html(rendered).into_response());
+    }
+
     Ok(JsonWithStatus {
-        data: messages,
+        data: page,
         status: StatusCode::OK,
+    }
+    .into_response())
+}
+async fn get_search(
+    State(state): State<AppState>,
+    Query(query): Query<SearchQuery>,
+    headers: HeaderMap,
+    current_user: CurrentUser,
+) -> ApiResult<Response> {
+    let user_id = current_user.user_id;
+    let text = query.q.trim();
+    if text.is_empty() {
+        return Err(AppError::bad_request(anyhow!("Nothing to search for")));
+    }
+    // Only ever the chats of the caller, the index itself knows nothing of members
+    let chat_ids = match query.chat_id {
+        Some(chat_id) => {
+            require_member(&state, chat_id, user_id).await?;
+            vec![chat_id]
+        }
+        None => {
+            // chats_by_user can keep the row of a chat the user was removed from
+            let mut chat_ids = Vec::new();
+            for chat_id in state.db.get_chat_ids_for_user(user_id).await? {
+                if state
+                    .members_cache
+                    .is_member(state.db.as_ref(), chat_id, user_id)
+                    .await?
+                {
+                    chat_ids.push(chat_id);
+                }
+            }
+            chat_ids
+        }
+    };
+    let sender_id = match &query.sender {
+        Some(username) => match state.db.get_user_by_username(username).await {
+            Ok(user) => Some(user.user_id),
+            Err(e) => {
+                // Nobody by that name sent anything
+                tracing::debug!("Unknown sender '{}' in search: {:#}", username, e);
+                return render_search_hits(&state, &headers, Vec::new());
+            }
+        },
+        None => None,
+    };
+    let text = text.to_string();
+    let since = query.from.map(|day| day.and_time(NaiveTime::MIN).and_utc());
+    // The whole of the last day is included
+    let until = query
+        .to
+        .and_then(|day| day.succ_opt())
+        .map(|day| day.and_time(NaiveTime::MIN).and_utc());
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let search = state.search.clone();
+    let hits = tokio::task::spawn_blocking(move || {
+        search.search(&SearchFilter {
+            text: &text,
+            chat_ids: &chat_ids,
+            sender_id,
+            since,
+            until,
+            limit,
+        })
     })
+    .await??;
+    counter!("search_queries_total").increment(1);
+    render_search_hits(&state, &headers, hits)
+}
+
+fn render_search_hits(
+    state: &AppState,
+    headers: &HeaderMap,
+    hits: Vec<SearchHit>,
+) -> ApiResult<Response> {
+    if headers.contains_key("hx-request") {
+        let mut context = tera::Context::new();
+        context.insert("hits", &hits);
+        let rendered = state
+            .tera
+            .render("partials/search_results.html", &context)
+            .map_err(|e| anyhow!("Template rendering failed: {}", e))?;
+        return Ok(Html(rendered).into_response());
+    }
+
+    Ok(JsonWithStatus {
+        data: hits,
+        status: StatusCode::OK,
+    }
+    .into_response())
 }
 async fn render_index(
     State(state): State<AppState>,
-    jar: CookieJar,
+    current_user: Option<CurrentUser>,
 ) -> ApiResult<impl IntoResponse> {
-    if jar.get("user_id").is_some() {
+    if current_user.is_some() {
         return Ok(Redirect::to("/dashboard").into_response());
     }
     let context = tera::Context::new();
@@ -107,17 +417,15 @@ async fn render_index(
     Ok(Html(rendered).into_response())
 }
 
-async fn dashboard(State(state): State<AppState>, jar: CookieJar) -> ApiResult<impl IntoResponse> {
-    let user_id_str = match jar.get("user_id") {
-        Some(cookie) => cookie.value(),
+async fn dashboard(
+    State(state): State<AppState>,
+    current_user: Option<CurrentUser>,
+) -> ApiResult<impl IntoResponse> {
+    let current_user_id = match current_user {
+        Some(current_user) => current_user.user_id,
         None => return Ok(Redirect::to("/").into_response()),
     };
 
-    let current_user_id = match Uuid::from_str(user_id_str) {
-        Ok(id) => id,
-        Err(_) => return Ok(Redirect::to("/logout").into_response()),
-    };
-
     let chats = state.db.get_chats_for_user(current_user_id).await?;
 
     let all_users = state.db.get_all_users().await?;
@@ -125,9 +433,16 @@ async fn dashboard(State(state): State<AppState>, jar: CookieJar) -> ApiResult<i
         Some(u) => u,
         None => return Ok(Redirect::to("/logout").into_response()),
     };
-    let available_users: Vec<&User> = all_users
+    let available_users: Vec<UserWithPresence> = all_users
         .iter()
         .filter(|u| u.user_id != current_user_id)
+        .map(|user| UserWithPresence {
+            user,
+            presence: PresenceView {
+                user_id: user.user_id,
+                status: state.presence.status(user.user_id),
+            },
+        })
         .collect();
 
     let mut context = tera::Context::new();
@@ -146,15 +461,11 @@ async fn dashboard(State(state): State<AppState>, jar: CookieJar) -> ApiResult<i
 async fn create_chat(
     State(state): State<AppState>,
     headers: HeaderMap,
-    jar: CookieJar,
+    current_user: CurrentUser,
     // axum_extra form support multiple values
     axum_extra::extract::Form(payload): axum_extra::extract::Form<CreateChat>,
 ) -> ApiResult<Response> {
-    let user_id = jar
-        .get("user_id")
-        .ok_or(anyhow!("No user_id cookie found"))?
-        .value();
-    let user_id = Uuid::from_str(user_id)?;
+    let user_id = current_user.user_id;
 
     let mut members = payload.members.clone();
 
@@ -162,7 +473,11 @@ async fn create_chat(
         members.push(user_id);
     }
 
-    let chat: Chat = state.db.create_chat(&payload.name, &members).await?;
+    // The creator owns the chat
+    let chat: Chat = state
+        .db
+        .create_chat(&payload.name, &members, user_id)
+        .await?;
 
     if headers.contains_key("hx-request") {
         let mut context = tera::Context::new();
@@ -185,17 +500,39 @@ async fn post_message(
     State(state): State<AppState>,
     Path(chat_id): Path<String>,
     headers: HeaderMap,
-    jar: CookieJar,
+    current_user: CurrentUser,
     Form(create_message): Form<CreatMessage>,
 ) -> ApiResult<impl IntoResponse> {
-    let sender_id = jar
-        .get("user_id")
-        .ok_or(anyhow!("Failed to get user_id from cookie"))?
-        .value_trimmed();
-
-    let content = create_message.content;
-    let sender_id = Uuid::parse_str(sender_id)?;
     let chat_id = Uuid::parse_str(&chat_id)?;
+    send_chat_message(
+        &state,
+        chat_id,
+        current_user.user_id,
+        create_message.content,
+        create_message.parent_id,
+        Vec::new(),
+    )
+    .await?;
+    if headers.contains_key("hx-request") {
+        return Ok(StatusCode::OK.into_response());
+    }
+    Ok(StatusCode::OK.into_response())
+}
+/// Post a message as a member of the chat, whether it comes from HTTP or the websocket
+/// With a parent it is a reply in the thread of that message
+/// Attachments must already be in the storage
+pub async fn send_chat_message(
+    state: &AppState,
+    chat_id: Uuid,
+    sender_id: Uuid,
+    content: String,
+    parent_id: Option<Uuid>,
+    attachments: Vec<Attachment>,
+) -> ApiResult<Uuid> {
+    require_member(state, chat_id, sender_id).await?;
+    if let Some(parent_id) = parent_id {
+        require_thread_parent(state, chat_id, parent_id).await?;
+    }
     let message_id = Uuid::now_v1(&NODE_ID);
     state
         .producer
@@ -204,6 +541,13 @@ async fn post_message(
             content,
             chat_id,
             message_id,
+            edited_at: None,
+            deleted: false,
+            reactions: Vec::new(),
+            mentions: Vec::new(),
+            attachments,
+            parent_id,
+            reply_count: 0,
         })
         .await?;
 
@@ -212,415 +556,4870 @@ async fn post_message(
             "chat_id" => chat_id.to_string()
     )
     .increment(1);
-    if headers.contains_key("hx-request") {
-        return Ok(StatusCode::OK.into_response());
-    }
-    Ok(StatusCode::OK.into_response())
+    Ok(message_id)
 }
-async fn create_user(
+
+/// A file of an upload, checked but not stored yet
+struct UploadedFile {
+    attachment: Attachment,
+    bytes: Bytes,
+}
+
+/// Post a message with files: multipart `file` fields, and the `content` and `parent_id` of
+/// the message form
+async fn upload_attachments(
     State(state): State<AppState>,
+    Path(chat_id): Path<String>,
     headers: HeaderMap,
-    jar: CookieJar,
-    Form(payload): Form<CreateUser>,
+    current_user: CurrentUser,
+    mut multipart: Multipart,
 ) -> ApiResult<Response> {
-    match state.db.create_user(&payload.username).await {
-        Ok(user) => {
-            if headers.contains_key("hx-request") {
-                let jar = jar.add(axum_extra::extract::cookie::Cookie::new(
-                    "user_id",
-                    user.user_id.to_string(),
-                ));
-                // HTMX redirect via header or just redirect
-                return Ok((jar, Redirect::to("/dashboard")).into_response());
+    let chat_id = Uuid::parse_str(&chat_id)?;
+    // Before reading the body, strangers do not get to upload anything
+    require_member(&state, chat_id, current_user.user_id).await?;
+
+    let mut content = String::new();
+    let mut parent_id = None;
+    let mut files = Vec::new();
+    while let Some(mut field) = multipart.next_field().await.map_err(multipart_error)? {
+        match field.name() {
+            Some("content") => content = field.text().await.map_err(multipart_error)?,
+            Some("parent_id") => {
+                let text = field.text().await.map_err(multipart_error)?;
+                if !text.is_empty() {
+                    parent_id =
+                        Some(Uuid::parse_str(&text).map_err(|e| {
+                            AppError::bad_request(anyhow!("Invalid parent_id: {}", e))
+                        })?);
+                }
             }
-            Ok(JsonWithStatus {
-                status: StatusCode::CREATED,
-                data: user,
+            Some("file") => {
+                // Browsers send an empty file field when nothing was picked
+                let filename = match field.file_name() {
+                    Some(filename) if !filename.is_empty() => filename.to_string(),
+                    _ => continue,
+                };
+                if files.len() == attachment::MAX_ATTACHMENTS {
+                    return Err(AppError::bad_request(anyhow!(
+                        "At most {} files per message",
+                        attachment::MAX_ATTACHMENTS
+                    )));
+                }
+                let mut bytes = BytesMut::new();
+                while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+                    if bytes.len() + chunk.len() > state.max_attachment_bytes {
+                        return Err(AppError::payload_too_large(anyhow!(
+                            "{} is larger than {} bytes",
+                            filename,
+                            state.max_attachment_bytes
+                        )));
+                    }
+                    bytes.extend_from_slice(&chunk);
+                }
+                let content_type = attachment::sniff(&bytes).ok_or_else(|| {
+                    AppError::unsupported_media_type(anyhow!(
+                        "{} is not an accepted type",
+                        filename
+                    ))
+                })?;
+                files.push(UploadedFile {
+                    attachment: Attachment {
+                        attachment_id: Uuid::new_v4(),
+                        filename: attachment::sanitize_filename(&filename),
+                        content_type: content_type.to_string(),
+                        size: bytes.len() as i64,
+                        thumbnail: attachment::is_image(content_type),
+                    },
+                    bytes: bytes.freeze(),
+                });
             }
-            .into_response())
+            _ => {}
+        }
+    }
+    if files.is_empty() && content.trim().is_empty() {
+        return Err(AppError::bad_request(anyhow!("Nothing to send")));
+    }
+
+    let mut stored = Vec::new();
+    let result = async {
+        for file in &files {
+            store_file(&state, chat_id, file, &mut stored).await?;
         }
+        let attachments = files.iter().map(|file| file.attachment.clone()).collect();
+        send_chat_message(
+            &state,
+            chat_id,
+            current_user.user_id,
+            content,
+            parent_id,
+            attachments,
+        )
+        .await
+    }
+    .await;
+    let message_id = match result {
+        Ok(message_id) => message_id,
         Err(e) => {
-            if headers.contains_key("hx-request") {
-                // Return error message div
-                return Ok(Html(format!(
-                    "<div class='error'>Error creating user: {}</div>",
-                    e
-                ))
-                .into_response());
+            // Nothing points to the files of a message that was not sent
+            for key in stored {
+                if let Err(e) = state.storage.delete(&key).await {
+                    tracing::warn!("Failed to clean up attachment {}: {:#}", key, e);
+                }
             }
-            Err(e.into())
+            return Err(e);
         }
+    };
+    counter!("attachments_uploaded_total").increment(files.len() as u64);
+
+    if headers.contains_key("hx-request") {
+        return Ok(StatusCode::OK.into_response());
     }
-}
-async fn get_user(
-    State(state): State<AppState>,
-    Path(user_id): Path<String>,
-) -> ApiResult<JsonWithStatus<User>> {
-    let user_id = Uuid::from_str(&user_id).context("Failed to parse user_id from str to UUID")?;
-    let user = state.db.get_user(user_id).await?;
     Ok(JsonWithStatus {
-        status: StatusCode::OK,
-        data: user,
-    })
+        status: StatusCode::CREATED,
+        data: Upload {
+            message_id,
+            attachments: files.into_iter().map(|file| file.attachment).collect(),
+        },
+    }
+    .into_response())
 }
-async fn get_chat(
-    State(state): State<AppState>,
-    Path(chat_id): Path<String>,
-) -> ApiResult<JsonWithStatus<Chat>> {
-    let chat_id = Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
-    let chat = state.db.get_chat(chat_id).await?;
-    Ok(JsonWithStatus {
-        status: StatusCode::OK,
-        data: chat,
-    })
+
+/// Write a file and its thumbnail, remembering the keys written for the clean up
+async fn store_file(
+    state: &AppState,
+    chat_id: Uuid,
+    file: &UploadedFile,
+    stored: &mut Vec<String>,
+) -> ApiResult<()> {
+    let attachment_id = file.attachment.attachment_id;
+    if file.attachment.thumbnail {
+        let bytes = file.bytes.clone();
+        let thumbnail = tokio::task::spawn_blocking(move || attachment::thumbnail(&bytes))
+            .await?
+            .map_err(|e| AppError::unsupported_media_type(e.context("Unreadable image")))?;
+        let key = thumbnail_key(chat_id, attachment_id);
+        state
+            .storage
+            .put(
+                &key,
+                Bytes::from(thumbnail),
+                attachment::THUMBNAIL_CONTENT_TYPE,
+            )
+            .await?;
+        stored.push(key);
+    }
+    let key = attachment_key(chat_id, attachment_id);
+    state
+        .storage
+        .put(&key, file.bytes.clone(), &file.attachment.content_type)
+        .await?;
+    stored.push(key);
+    Ok(())
 }
 
-async fn render_chat(
+/// Too big or malformed bodies keep the status given by axum
+fn multipart_error(error: MultipartError) -> AppError {
+    AppError {
+        status: error.status(),
+        error: error.into(),
+    }
+}
+
+async fn download_attachment(
     State(state): State<AppState>,
-    Path(chat_id): Path<String>,
-    jar: CookieJar,
-) -> ApiResult<Html<String>> {
-    let user_id_str = jar
-        .get("user_id")
-        .ok_or(anyhow!("No user_id cookie found"))?
-        .value();
-    let current_user_id = Uuid::from_str(user_id_str)?;
+    Path((chat_id, message_id, attachment_id)): Path<(String, String, String)>,
+    current_user: CurrentUser,
+) -> ApiResult<Response> {
+    serve_attachment(
+        &state,
+        (chat_id, message_id, attachment_id),
+        current_user,
+        false,
+    )
+    .await
+}
 
-    let chat_id_uuid =
-        Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
-    let chat = state.db.get_chat(chat_id_uuid).await?;
-    let messages = state.db.get_messages(chat_id_uuid).await?;
+async fn download_thumbnail(
+    State(state): State<AppState>,
+    Path((chat_id, message_id, attachment_id)): Path<(String, String, String)>,
+    current_user: CurrentUser,
+) -> ApiResult<Response> {
+    serve_attachment(
+        &state,
+        (chat_id, message_id, attachment_id),
+        current_user,
+        true,
+    )
+    .await
+}
 
-    let mut context = tera::Context::new();
-    context.insert("chat", &chat);
-    context.insert("messages", &messages);
-    context.insert("user_id", &current_user_id);
+/// Only the members of the chat get the files of its messages, gone with the message
+async fn serve_attachment(
+    state: &AppState,
+    (chat_id, message_id, attachment_id): (String, String, String),
+    current_user: CurrentUser,
+    thumbnail: bool,
+) -> ApiResult<Response> {
+    let chat_id = Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
+    let message_id =
+        Uuid::from_str(&message_id).context("Failed to parse message_id from str to UUID")?;
+    let attachment_id =
+        Uuid::from_str(&attachment_id).context("Failed to parse attachment_id from str to UUID")?;
+    require_member(state, chat_id, current_user.user_id).await?;
+    let attachment = state
+        .db
+        .get_attachment(chat_id, message_id, attachment_id)
+        .await
+        .map_err(AppError::not_found_if_missing)?;
+    let (key, content_type, disposition) = if thumbnail {
+        if !attachment.thumbnail {
+            return Err(AppError::not_found(anyhow!(
+                "Attachment {} has no thumbnail",
+                attachment_id
+            )));
+        }
+        (
+            thumbnail_key(chat_id, attachment_id),
+            attachment::THUMBNAIL_CONTENT_TYPE.to_string(),
+            "inline".to_string(),
+        )
+    } else {
+        (
+            attachment_key(chat_id, attachment_id),
+            attachment.content_type.clone(),
+            attachment::content_disposition(&attachment),
+        )
+    };
+    let bytes =
+        state.storage.get(&key).await?.ok_or_else(|| {
+            AppError::not_found(anyhow!("Attachment {} is not in the storage", key))
+        })?;
 
-    let rendered = state
-        .tera
-        .render("chat.html", &context)
-        .map_err(|e| anyhow!("Template rendering failed: {}", e))?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_DISPOSITION, disposition),
+            // Served as sniffed on upload, never as what the browser guesses
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            // An attachment never changes, but it is not for shared caches
+            (header::CACHE_CONTROL, "private, max-age=86400".to_string()),
+        ],
+        bytes,
+    )
+        .into_response())
+}
 
-    Ok(Html(rendered))
+/// Threads start from a message of the history that still exists, replies have no thread
+async fn require_thread_parent(
+    state: &AppState,
+    chat_id: Uuid,
+    parent_id: Uuid,
+) -> ApiResult<PandaMessage> {
+    let parent = state
+        .db
+        .get_message(chat_id, parent_id)
+        .await
+        .map_err(AppError::not_found_if_missing)?;
+    if parent.deleted {
+        return Err(AppError::not_found(anyhow!(
+            "Message {} was deleted",
+            parent_id
+        )));
+    }
+    Ok(parent)
 }
 
-// ----- **** ----
-// // End of handler definition
-// ----- **** ----
+async fn get_thread(
+    State(state): State<AppState>,
+    Path((chat_id, message_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    current_user: CurrentUser,
+) -> ApiResult<Response> {
+    let chat_id = Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
+    let message_id =
+        Uuid::from_str(&message_id).context("Failed to parse message_id from str to UUID")?;
+    require_member(&state, chat_id, current_user.user_id).await?;
+    let parent = require_thread_parent(&state, chat_id, message_id).await?;
+    let replies = state.db.get_thread(chat_id, message_id).await?;
 
-pub struct JsonWithStatus<T> {
-    pub status: StatusCode,
-    pub data: T,
-}
+    if headers.contains_key("hx-request") {
+        let mut context = tera::Context::new();
+        context.insert("parent", &parent);
+        context.insert("replies", &replies);
+        context.insert("user_id", &current_user.user_id);
+        context.insert("history", &true);
+        let rendered = state
+            .tera
+            .render("partials/thread.html", &context)
+            .map_err(|e| anyhow!("Template rendering failed: {}", e))?;
+        return Ok(Html(rendered).into_response());
+    }
 
-impl<T> IntoResponse for JsonWithStatus<T>
-where
-    T: Serialize,
-{
-    fn into_response(self) -> Response {
-        (self.status, Json(self.data)).into_response()
+    Ok(JsonWithStatus {
+        status: StatusCode::OK,
+        data: Thread { parent, replies },
     }
+    .into_response())
 }
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        tracing::error!("Application error: {:#}", self.0);
-        (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
-    }
+
+async fn post_typing(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    current_user: CurrentUser,
+) -> ApiResult<StatusCode> {
+    let chat_id = Uuid::parse_str(&chat_id)?;
+    signal_typing(&state, chat_id, current_user.user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+/// Tell the other members someone is typing, whether it comes from HTTP or the websocket
+/// Signals in the throttle window are accepted but not relayed
+pub async fn signal_typing(state: &AppState, chat_id: Uuid, user_id: Uuid) -> ApiResult<()> {
+    require_member(state, chat_id, user_id).await?;
+    if !state.typing_throttle.allow(chat_id, user_id) {
+        return Ok(());
     }
+    // Typing fans out without reading the users table, apart from the first signal of a member
+    let username = match state.typing_throttle.username(user_id) {
+        Some(username) => username,
+        None => {
+            let user = state.db.get_user(user_id).await?;
+            state
+                .typing_throttle
+                .remember_username(user_id, &user.username);
+            user.username
+        }
+    };
+    state
+        .producer
+        .send_event(ChatEvent::Typing(Typing {
+            chat_id,
+            user_id,
+            username,
+            at: Utc::now(),
+        }))
+        .await?;
+    counter!("typing_signals_total").increment(1);
+    Ok(())
 }
-pub struct AppError(anyhow::Error);
 
-pub type ApiResult<T> = Result<T, AppError>;
-
-#[cfg(test)]
-mod tests {
-    use std::{collections::HashMap, sync::Arc};
+async fn post_read(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    current_user: CurrentUser,
+    Form(payload): Form<MarkRead>,
+) -> ApiResult<StatusCode> {
+    let chat_id = Uuid::parse_str(&chat_id)?;
+    mark_chat_read(&state, chat_id, current_user.user_id, payload.message_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Mark a chat read up to a message, or up to its newest one
+pub async fn mark_chat_read(
+    state: &AppState,
+    chat_id: Uuid,
+    user_id: Uuid,
+    message_id: Option<Uuid>,
+) -> ApiResult<()> {
+    require_member(state, chat_id, user_id).await?;
+    let message = match message_id {
+        Some(message_id) => state
+            .db
+            .get_message(chat_id, message_id)
+            .await
+            .map_err(AppError::not_found_if_missing)?,
+        None => match state
+            .db
+            .get_messages(chat_id, None, 1)
+            .await?
+            .messages
+            .pop()
+        {
+            Some(message) => message,
+            // Nothing to read yet
+            None => return Ok(()),
+        },
+    };
+    read_up_to(state, user_id, &message).await?;
+    Ok(())
+}
+
+/// Move the read receipt of a member, and tell the sender of the message when it moved
+async fn read_up_to(state: &AppState, user_id: Uuid, message: &PandaMessage) -> anyhow::Result<()> {
+    let moved = state
+        .db
+        .mark_read(message.chat_id, user_id, message.message_id)
+        .await?;
+    let own = message.sender_id == user_id || message.sender_id == SYSTEM_SENDER_ID;
+    if !moved || own || message.deleted {
+        return Ok(());
+    }
+    let user = state.db.get_user(user_id).await?;
+    state
+        .producer
+        .send_event(ChatEvent::ReadReceipt(ReadReceipt {
+            chat_id: message.chat_id,
+            message_id: message.message_id,
+            sender_id: message.sender_id,
+            user_id,
+            username: user.username,
+        }))
+        .await?;
+    counter!("read_receipts_sent_total").increment(1);
+    Ok(())
+}
+
+async fn edit_message(
+    State(state): State<AppState>,
+    Path((chat_id, message_id)): Path<(String, String)>,
+    current_user: CurrentUser,
+    Form(payload): Form<EditMessage>,
+) -> ApiResult<StatusCode> {
+    let chat_id = Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
+    let message_id =
+        Uuid::from_str(&message_id).context("Failed to parse message_id from str to UUID")?;
+    let message = require_message_moderator(&state, chat_id, message_id, current_user).await?;
+
+    // Same id and sender: the edit lands on the partition of the message it changes
+    state
+        .producer
+        .send_event(ChatEvent::MessageEdited {
+            chat_id,
+            message_id,
+            sender_id: message.sender_id,
+            content: payload.content,
+            edited_at: Utc::now(),
+        })
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+async fn delete_message(
+    State(state): State<AppState>,
+    Path((chat_id, message_id)): Path<(String, String)>,
+    current_user: CurrentUser,
+) -> ApiResult<StatusCode> {
+    let chat_id = Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
+    let message_id =
+        Uuid::from_str(&message_id).context("Failed to parse message_id from str to UUID")?;
+    let message = require_message_moderator(&state, chat_id, message_id, current_user).await?;
+
+    state
+        .producer
+        .send_event(ChatEvent::MessageDeleted {
+            chat_id,
+            message_id,
+            sender_id: message.sender_id,
+        })
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+async fn add_reaction(
+    State(state): State<AppState>,
+    Path((chat_id, message_id, emoji)): Path<(String, String, String)>,
+    current_user: CurrentUser,
+) -> ApiResult<StatusCode> {
+    let change = reaction_change(&state, &chat_id, &message_id, emoji, current_user).await?;
+    state
+        .producer
+        .send_event(ChatEvent::ReactionAdded(change))
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_reaction(
+    State(state): State<AppState>,
+    Path((chat_id, message_id, emoji)): Path<(String, String, String)>,
+    current_user: CurrentUser,
+) -> ApiResult<StatusCode> {
+    let change = reaction_change(&state, &chat_id, &message_id, emoji, current_user).await?;
+    state
+        .producer
+        .send_event(ChatEvent::ReactionRemoved(change))
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Any member can react to a message that still exists, with a single emoji
+async fn reaction_change(
+    state: &AppState,
+    chat_id: &str,
+    message_id: &str,
+    emoji: String,
+    current_user: CurrentUser,
+) -> ApiResult<ReactionChange> {
+    let chat_id = Uuid::from_str(chat_id).context("Failed to parse chat_id from str to UUID")?;
+    let message_id =
+        Uuid::from_str(message_id).context("Failed to parse message_id from str to UUID")?;
+    if !is_emoji(&emoji) {
+        return Err(AppError::bad_request(anyhow!(
+            "'{}' is not an emoji",
+            emoji
+        )));
+    }
+    require_member(state, chat_id, current_user.user_id).await?;
+    let message = state
+        .db
+        .get_message(chat_id, message_id)
+        .await
+        .map_err(AppError::not_found_if_missing)?;
+    if message.deleted {
+        return Err(AppError::not_found(anyhow!(
+            "Message {} was deleted",
+            message_id
+        )));
+    }
+    Ok(ReactionChange {
+        chat_id,
+        message_id,
+        sender_id: message.sender_id,
+        user_id: current_user.user_id,
+        emoji,
+        at: Some(Utc::now()),
+    })
+}
+
+/// A few chars from the emoji blocks, with the joiners, variation selectors and tags that build
+/// sequences out of them. Accented letters or CJK are not emojis, keycaps are not accepted either
+fn is_emoji(emoji: &str) -> bool {
+    let chars = emoji.chars().count();
+    (1..=MAX_EMOJI_CHARS).contains(&chars)
+        && emoji.chars().any(is_pictographic)
+        && emoji.chars().all(|c| {
+            is_pictographic(c) || matches!(c, '\u{200D}' | '\u{FE0F}' | '\u{E0020}'..='\u{E007F}')
+        })
+}
+
+/// The Unicode blocks emojis come from, skin tones and flags included
+fn is_pictographic(c: char) -> bool {
+    matches!(c,
+        '\u{00A9}' | '\u{00AE}' | '\u{203C}' | '\u{2049}' | '\u{2122}' | '\u{2139}'
+        | '\u{2194}'..='\u{21AA}'
+        | '\u{2300}'..='\u{23FF}'
+        | '\u{24C2}'
+        | '\u{25AA}'..='\u{25FE}'
+        | '\u{2600}'..='\u{27BF}'
+        | '\u{2934}' | '\u{2935}'
+        | '\u{2B05}'..='\u{2B55}'
+        | '\u{3030}' | '\u{303D}' | '\u{3297}' | '\u{3299}'
+        | '\u{1F000}'..='\u{1FAFF}')
+}
+
+/// A message can be changed by its sender, or by an admin of the chat, as long as it still exists
+async fn require_message_moderator(
+    state: &AppState,
+    chat_id: Uuid,
+    message_id: Uuid,
+    current_user: CurrentUser,
+) -> ApiResult<PandaMessage> {
+    require_member(state, chat_id, current_user.user_id).await?;
+    let message = state
+        .db
+        .get_message(chat_id, message_id)
+        .await
+        .map_err(AppError::not_found_if_missing)?;
+    if message.deleted {
+        return Err(AppError::not_found(anyhow!(
+            "Message {} was deleted",
+            message_id
+        )));
+    }
+    // System messages are a record of what happened, nobody rewrites them
+    if message.sender_id == SYSTEM_SENDER_ID {
+        return Err(AppError::forbidden(anyhow!(
+            "System message {} can not be changed",
+            message_id
+        )));
+    }
+
+    if message.sender_id != current_user.user_id {
+        require_role(state, chat_id, current_user.user_id, Role::Admin).await?;
+    }
+    Ok(message)
+}
+
+async fn create_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    jar: CookieJar,
+    Form(payload): Form<CreateUser>,
+) -> ApiResult<Response> {
+    let password = payload.password;
+    let password_hash = tokio::task::spawn_blocking(move || hash_password(&password)).await??;
+    match state
+        .db
+        .create_user(&payload.username, &password_hash)
+        .await
+    {
+        Ok(user) => {
+            if headers.contains_key("hx-request") {
+                let jar = start_session(&state, jar, user.user_id).await?;
+                // HTMX redirect via header so the whole page navigates, not the error target
+                return Ok((jar, [("HX-Redirect", "/dashboard")]).into_response());
+            }
+            Ok(JsonWithStatus {
+                status: StatusCode::CREATED,
+                data: user,
+            }
+            .into_response())
+        }
+        Err(e) => {
+            if headers.contains_key("hx-request") {
+                // Return error message partial
+                let rendered = render_form_error(&state, &format!("Error creating user: {}", e))?;
+                return Ok(Html(rendered).into_response());
+            }
+            Err(e.into())
+        }
+    }
+}
+async fn get_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> ApiResult<JsonWithStatus<User>> {
+    let user_id = Uuid::from_str(&user_id).context("Failed to parse user_id from str to UUID")?;
+    let user = state.db.get_user(user_id).await?;
+    Ok(JsonWithStatus {
+        status: StatusCode::OK,
+        data: user,
+    })
+}
+/// JSON for the API clients, the dot of the dashboard for htmx
+async fn get_presence(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    _current_user: CurrentUser,
+    Path(user_id): Path<String>,
+) -> ApiResult<Response> {
+    let user_id = Uuid::from_str(&user_id).context("Failed to parse user_id from str to UUID")?;
+    let presence = PresenceView {
+        user_id,
+        status: state.presence.status(user_id),
+    };
+    if headers.contains_key("hx-request") {
+        let mut context = tera::Context::new();
+        context.insert("presence", &presence);
+        let rendered = state
+            .tera
+            .render("partials/presence.html", &context)
+            .map_err(|e| anyhow!("Template rendering failed: {}", e))?;
+        return Ok(Html(rendered).into_response());
+    }
+    Ok(Json(presence).into_response())
+}
+
+async fn get_chat(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    current_user: CurrentUser,
+) -> ApiResult<JsonWithStatus<Chat>> {
+    let chat_id = Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
+    require_member(&state, chat_id, current_user.user_id).await?;
+    let chat = state.db.get_chat(chat_id).await?;
+    Ok(JsonWithStatus {
+        status: StatusCode::OK,
+        data: chat,
+    })
+}
+
+async fn render_chat(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    current_user: CurrentUser,
+) -> ApiResult<Html<String>> {
+    let current_user_id = current_user.user_id;
+
+    let chat_id_uuid =
+        Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
+    require_member(&state, chat_id_uuid, current_user_id).await?;
+    let chat = state.db.get_chat(chat_id_uuid).await?;
+    let page = state
+        .db
+        .get_messages(chat_id_uuid, None, DEFAULT_PAGE_SIZE)
+        .await?;
+    let all_users = state.db.get_all_users().await?;
+
+    // Opening the chat reads it, failing to record it should not keep the user out
+    if let Some(newest) = page.messages.first()
+        && let Err(e) = read_up_to(&state, current_user_id, newest).await
+    {
+        tracing::warn!("Failed to mark chat {} read: {:#}", chat_id_uuid, e);
+    }
+    let receipts = state.db.get_read_receipts(chat_id_uuid).await?;
+
+    let mut context = members_context(&chat, &all_users, current_user_id);
+    context.insert("chat", &chat);
+    context.insert("chat_id", &chat.chat_id);
+    context.insert("messages", &page.messages);
+    context.insert(
+        "seen_by",
+        &seen_by(&page.messages, &receipts, &all_users, current_user_id),
+    );
+    context.insert("next_cursor", &page.next_cursor);
+    context.insert("user_id", &current_user_id);
+    context.insert("history", &true);
+
+    let rendered = state
+        .tera
+        .render("chat.html", &context)
+        .map_err(|e| anyhow!("Template rendering failed: {}", e))?;
+
+    Ok(Html(rendered))
+}
+
+async fn add_member(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    headers: HeaderMap,
+    current_user: CurrentUser,
+    Form(payload): Form<AddMember>,
+) -> ApiResult<Response> {
+    let chat_id = Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
+    let chat = require_role(&state, chat_id, current_user.user_id, Role::Admin).await?;
+    let added = state
+        .db
+        .get_user(payload.user_id)
+        .await
+        .map_err(AppError::not_found_if_missing)?;
+    // Adding them again changes nothing, nor is it announced
+    if chat.members.contains(&added.user_id) {
+        return members_response(&state, &headers, &chat, current_user.user_id).await;
+    }
+
+    let chat = state.db.add_chat_member(chat_id, added.user_id).await?;
+    state.members_cache.invalidate(chat_id);
+
+    let actor = state.db.get_user(current_user.user_id).await?;
+    announce(
+        &state,
+        chat_id,
+        format!("{} added {}", actor.username, added.username),
+    )
+    .await?;
+
+    members_response(&state, &headers, &chat, current_user.user_id).await
+}
+
+async fn remove_member(
+    State(state): State<AppState>,
+    Path((chat_id, user_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    current_user: CurrentUser,
+) -> ApiResult<Response> {
+    let chat_id = Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
+    let user_id = Uuid::from_str(&user_id).context("Failed to parse user_id from str to UUID")?;
+    let is_leaving = user_id == current_user.user_id;
+    let chat = require_role(&state, chat_id, current_user.user_id, Role::Member).await?;
+    let actor_role = chat.role_of(current_user.user_id).unwrap_or(Role::Member);
+    let target_role = chat.role_of(user_id).ok_or_else(|| {
+        AppError::not_found(anyhow!(
+            "User {} is not a member of chat {}",
+            user_id,
+            chat_id
+        ))
+    })?;
+
+    if is_leaving && actor_role == Role::Owner {
+        return Err(AppError::conflict(anyhow!(
+            "The owner of chat {} must transfer ownership before leaving",
+            chat_id
+        )));
+    }
+    // Anyone may leave, removing someone else takes an admin above that member
+    if !is_leaving && (actor_role < Role::Admin || actor_role <= target_role) {
+        return Err(AppError::forbidden(anyhow!(
+            "User {} ({}) may not remove {} ({}) from chat {}",
+            current_user.user_id,
+            actor_role,
+            user_id,
+            target_role,
+            chat_id
+        )));
+    }
+
+    let removed = state
+        .db
+        .get_user(user_id)
+        .await
+        .map_err(AppError::not_found_if_missing)?;
+
+    let chat = state.db.remove_chat_member(chat_id, user_id).await?;
+    state.members_cache.invalidate(chat_id);
+
+    let content = if is_leaving {
+        format!("{} left the chat", removed.username)
+    } else {
+        let actor = state.db.get_user(current_user.user_id).await?;
+        format!("{} removed {}", actor.username, removed.username)
+    };
+    announce(&state, chat_id, content).await?;
+
+    if is_leaving && headers.contains_key("hx-request") {
+        return Ok([("HX-Redirect", "/dashboard")].into_response());
+    }
+    members_response(&state, &headers, &chat, current_user.user_id).await
+}
+
+async fn set_member_role(
+    State(state): State<AppState>,
+    Path((chat_id, user_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    current_user: CurrentUser,
+    Form(payload): Form<SetRole>,
+) -> ApiResult<Response> {
+    let chat_id = Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
+    let user_id = Uuid::from_str(&user_id).context("Failed to parse user_id from str to UUID")?;
+    let chat = require_role(&state, chat_id, current_user.user_id, Role::Owner).await?;
+    if payload.role == Role::Owner {
+        return Err(AppError::bad_request(anyhow!(
+            "Ownership is handed over with a transfer, not a role change"
+        )));
+    }
+    if chat.role_of(user_id).is_none_or(|role| role == Role::Owner) {
+        return Err(AppError::bad_request(anyhow!(
+            "User {} can not be given a role in chat {}",
+            user_id,
+            chat_id
+        )));
+    }
+
+    let chat = state
+        .db
+        .set_chat_roles(chat_id, &[(user_id, payload.role)])
+        .await?;
+
+    let actor = state.db.get_user(current_user.user_id).await?;
+    let target = state.db.get_user(user_id).await?;
+    announce(
+        &state,
+        chat_id,
+        format!(
+            "{} made {} {}",
+            actor.username, target.username, payload.role
+        ),
+    )
+    .await?;
+
+    members_response(&state, &headers, &chat, current_user.user_id).await
+}
+
+async fn transfer_ownership(
+    State(state): State<AppState>,
+    Path(chat_id): Path<String>,
+    headers: HeaderMap,
+    current_user: CurrentUser,
+    Form(payload): Form<TransferOwnership>,
+) -> ApiResult<Response> {
+    let chat_id = Uuid::from_str(&chat_id).context("Failed to parse chat_id from str to UUID")?;
+    let chat = require_role(&state, chat_id, current_user.user_id, Role::Owner).await?;
+    if payload.user_id == current_user.user_id || chat.role_of(payload.user_id).is_none() {
+        return Err(AppError::bad_request(anyhow!(
+            "Ownership of chat {} can only go to another member",
+            chat_id
+        )));
+    }
+
+    // The previous owner stays on as an admin
+    let chat = state
+        .db
+        .set_chat_roles(
+            chat_id,
+            &[
+                (payload.user_id, Role::Owner),
+                (current_user.user_id, Role::Admin),
+            ],
+        )
+        .await?;
+
+    let actor = state.db.get_user(current_user.user_id).await?;
+    let target = state.db.get_user(payload.user_id).await?;
+    announce(
+        &state,
+        chat_id,
+        format!(
+            "{} transferred ownership to {}",
+            actor.username, target.username
+        ),
+    )
+    .await?;
+
+    members_response(&state, &headers, &chat, current_user.user_id).await
+}
+
+/// Post a system message in the chat, delivered live to every member like any other message
+async fn announce(state: &AppState, chat_id: Uuid, content: String) -> ApiResult<()> {
+    state
+        .producer
+        .send_message(PandaMessage {
+            chat_id,
+            sender_id: SYSTEM_SENDER_ID,
+            content,
+            message_id: Uuid::now_v1(&NODE_ID),
+            edited_at: None,
+            deleted: false,
+            reactions: Vec::new(),
+            mentions: Vec::new(),
+            attachments: Vec::new(),
+            parent_id: None,
+            reply_count: 0,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Answer of a membership change, the refreshed members panel for htmx and the chat otherwise
+async fn members_response(
+    state: &AppState,
+    headers: &HeaderMap,
+    chat: &Chat,
+    current_user_id: Uuid,
+) -> ApiResult<Response> {
+    if headers.contains_key("hx-request") {
+        let all_users = state.db.get_all_users().await?;
+        let mut context = members_context(chat, &all_users, current_user_id);
+        context.insert("chat", chat);
+        context.insert("user_id", &current_user_id);
+        let rendered = state
+            .tera
+            .render("partials/chat_members.html", &context)
+            .map_err(|e| anyhow!("Template rendering failed: {}", e))?;
+        return Ok(Html(rendered).into_response());
+    }
+
+    Ok(JsonWithStatus {
+        status: StatusCode::OK,
+        data: chat.clone(),
+    }
+    .into_response())
+}
+
+#[derive(Serialize)]
+struct UserWithPresence<'a> {
+    #[serde(flatten)]
+    user: &'a User,
+    presence: PresenceView,
+}
+
+#[derive(Serialize)]
+struct MemberView<'a> {
+    #[serde(flatten)]
+    user: &'a User,
+    role: Role,
+    /// Whether the viewer may remove this member
+    removable: bool,
+}
+
+/// Who read the viewer's messages of the page, each reader under the newest one they reached
+fn seen_by(
+    messages: &[PandaMessage],
+    receipts: &HashMap<Uuid, Uuid>,
+    users: &[User],
+    viewer_id: Uuid,
+) -> HashMap<Uuid, Vec<String>> {
+    let mut seen_by: HashMap<Uuid, Vec<String>> = HashMap::new();
+    for user in users.iter().filter(|user| user.user_id != viewer_id) {
+        let Some(last_read) = receipts.get(&user.user_id) else {
+            continue;
+        };
+        // Timeuuids only compare by time as CqlTimeuuid, messages are newest first
+        let last_read = CqlTimeuuid::from(*last_read);
+        let reached = messages.iter().find(|message| {
+            message.sender_id == viewer_id
+                && !message.deleted
+                && CqlTimeuuid::from(message.message_id) <= last_read
+        });
+        if let Some(message) = reached {
+            seen_by
+                .entry(message.message_id)
+                .or_default()
+                .push(user.username.clone());
+        }
+    }
+    seen_by
+}
+
+/// Members of the chat with their role and the users that could still be added, for the members
+/// panel, along with the role of the viewer to only show the actions they may take
+fn members_context(chat: &Chat, all_users: &[User], viewer_id: Uuid) -> tera::Context {
+    let (members, available_users): (Vec<&User>, Vec<&User>) = all_users
+        .iter()
+        .partition(|user| chat.members.contains(&user.user_id));
+    let my_role = chat.role_of(viewer_id).unwrap_or(Role::Member);
+    let members: Vec<MemberView> = members
+        .into_iter()
+        .map(|user| {
+            let role = chat.role_of(user.user_id).unwrap_or(Role::Member);
+            MemberView {
+                user,
+                role,
+                removable: user.user_id != viewer_id && my_role >= Role::Admin && my_role > role,
+            }
+        })
+        .collect();
+    let mut context = tera::Context::new();
+    context.insert("members", &members);
+    context.insert("available_users", &available_users);
+    context.insert("my_role", &my_role);
+    context
+}
+
+/// Members changing a chat must hold at least `required`, the role is read fresh from the db
+/// rather than the members cache since it grants more than reading
+async fn require_role(
+    state: &AppState,
+    chat_id: Uuid,
+    user_id: Uuid,
+    required: Role,
+) -> ApiResult<Chat> {
+    let chat = state
+        .db
+        .get_chat(chat_id)
+        .await
+        .map_err(AppError::not_found_if_missing)?;
+    match chat.role_of(user_id) {
+        Some(role) if role >= required => Ok(chat),
+        role => Err(AppError::forbidden(anyhow!(
+            "User {} ({:?}) needs to be {} in chat {}",
+            user_id,
+            role,
+            required,
+            chat_id
+        ))),
+    }
+}
+
+/// Only members of a chat may read or write in it
+pub async fn require_member(state: &AppState, chat_id: Uuid, user_id: Uuid) -> ApiResult<()> {
+    let is_member = state
+        .members_cache
+        .is_member(state.db.as_ref(), chat_id, user_id)
+        .await?;
+    if !is_member {
+        return Err(AppError::forbidden(anyhow!(
+            "User {} is not a member of chat {}",
+            user_id,
+            chat_id
+        )));
+    }
+    Ok(())
+}
+
+// ----- **** ----
+// // End of handler definition
+// ----- **** ----
+
+pub struct JsonWithStatus<T> {
+    pub status: StatusCode,
+    pub data: T,
+}
+
+impl<T> IntoResponse for JsonWithStatus<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        (self.status, Json(self.data)).into_response()
+    }
+}
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if self.status.is_server_error() {
+            tracing::error!("Application error: {:#}", self.error);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+        }
+        // Client errors are expected, only the status is exposed
+        tracing::debug!("Request rejected ({}): {:#}", self.status, self.error);
+        let reason = self.status.canonical_reason().unwrap_or_default();
+        (self.status, reason).into_response()
+    }
+}
+
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            error: err.into(),
+        }
+    }
+}
+pub struct AppError {
+    status: StatusCode,
+    error: anyhow::Error,
+}
+
+impl AppError {
+    pub fn forbidden(error: anyhow::Error) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            error,
+        }
+    }
+
+    pub fn not_found(error: anyhow::Error) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            error,
+        }
+    }
+
+    /// A missing row is a 404, any other failure of the database stays a 500
+    pub fn not_found_if_missing(error: anyhow::Error) -> Self {
+        if is_not_found(&error) {
+            Self::not_found(error)
+        } else {
+            error.into()
+        }
+    }
+
+    pub fn bad_request(error: anyhow::Error) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            error,
+        }
+    }
+
+    pub fn conflict(error: anyhow::Error) -> Self {
+        Self {
+            status: StatusCode::CONFLICT,
+            error,
+        }
+    }
+
+    pub fn payload_too_large(error: anyhow::Error) -> Self {
+        Self {
+            status: StatusCode::PAYLOAD_TOO_LARGE,
+            error,
+        }
+    }
+
+    pub fn unsupported_media_type(error: anyhow::Error) -> Self {
+        Self {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            error,
+        }
+    }
+}
+
+pub type ApiResult<T> = Result<T, AppError>;
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use axum::{
+        Router,
+        body::{Body, to_bytes},
+        http::{self, Request, StatusCode},
+        response::Response,
+        routing::{delete, get, patch, post, put},
+    };
+    use futures_util::{SinkExt, StreamExt};
+    use mockall::mock;
+    use serde::{Serialize, de::DeserializeOwned};
+    use tera::Tera;
+    use tokio::sync::RwLock;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        AppState, ConnectionMap,
+        auth::SESSION_COOKIE,
+        db::{Db, RowNotFound},
+        membership::MembersCache,
+        mention,
+        presence::{PresenceTracker, Status},
+        producer::MockProducer,
+        schema::{Mention, MessagePage, MessageReactions, Reaction, Role, UserSession},
+        search::SearchIndex,
+        storage::MockStorage,
+        websocket::{Delivery, Heartbeat, SlowClientPolicy},
+    };
+
+    const TEST_SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+    // --- MOCK DEFINITIONS ---
+    mock! {
+        pub Db {}
+        #[async_trait]
+        impl Db for Db {
+            async fn create_user(&self, username: &str, password_hash: &str) -> Result<User>;
+            async fn create_chat(&self, name: &str, members: &[Uuid], owner: Uuid) -> Result<Chat>;
+            async fn get_user(&self, user_id: Uuid) -> Result<User>;
+            async fn get_chat(&self, chat_id: Uuid) -> Result<Chat>;
+            async fn get_message(&self, chat_id: Uuid, message_id: Uuid) -> Result<PandaMessage>;
+            async fn get_messages(&self, chat_id: Uuid, before: Option<Uuid>, limit: i32) -> Result<MessagePage>;
+            async fn get_messages_after(&self, chat_id: Uuid, after: Uuid, limit: i32) -> Result<Vec<PandaMessage>>;
+            async fn get_thread(&self, chat_id: Uuid, parent_id: Uuid) -> Result<Vec<PandaMessage>>;
+            async fn get_mentions(&self, user_id: Uuid, before: Option<Uuid>, limit: i32) -> Result<MessagePage>;
+            async fn get_chats_for_user(&self, user_id: Uuid) -> Result<Vec<Chat>>;
+            async fn get_chat_ids_for_user(&self, user_id: Uuid) -> Result<Vec<Uuid>>;
+            async fn add_reaction(&self, change: &ReactionChange) -> Result<()>;
+            async fn remove_reaction(&self, change: &ReactionChange) -> Result<()>;
+            async fn get_reactions(&self, chat_id: Uuid, message_id: Uuid) -> Result<Vec<Reaction>>;
+            async fn get_attachments(&self, chat_id: Uuid, message_id: Uuid) -> Result<Vec<Attachment>>;
+            async fn get_attachment(&self, chat_id: Uuid, message_id: Uuid, attachment_id: Uuid) -> Result<Attachment>;
+            async fn mark_read(&self, chat_id: Uuid, user_id: Uuid, message_id: Uuid) -> Result<bool>;
+            async fn get_read_receipts(&self, chat_id: Uuid) -> Result<HashMap<Uuid, Uuid>>;
+            async fn touch_chat(&self, chat_id: Uuid, at: chrono::DateTime<Utc>) -> Result<()>;
+            async fn add_chat_member(&self, chat_id: Uuid, user_id: Uuid) -> Result<Chat>;
+            async fn remove_chat_member(&self, chat_id: Uuid, user_id: Uuid) -> Result<Chat>;
+            async fn set_chat_roles(&self, chat_id: Uuid, changes: &[(Uuid, Role)]) -> Result<Chat>;
+            async fn get_user_by_username(&self, username: &str) -> Result<User>;
+            async fn get_password_hash(&self, user_id: Uuid) -> Result<String>;
+            async fn get_all_users(&self) -> Result<Vec<User>>;
+            async fn get_members_of_chat(&self, chat_id: Uuid) -> Result<Vec<Uuid>>;
+            async fn insert_batch_message(&self, messages: &[PandaMessage]) -> Result<Vec<Uuid>>;
+            async fn insert_message(&self, message: PandaMessage) -> Result<PandaMessage>;
+            async fn create_session(&self, user_id: Uuid, ttl: Duration) -> Result<UserSession>;
+            async fn get_session(&self, session_id: Uuid) -> Result<UserSession>;
+            async fn refresh_session(&self, session: &UserSession, ttl: Duration) -> Result<UserSession>;
+            async fn delete_session(&self, session_id: Uuid) -> Result<()>;
+        }
+    }
+
+    // --- TESTS ---
+
+    #[tokio::test]
+    async fn test_create_user() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let now = Utc::now();
+        let expected_user = User {
+            user_id,
+            username: "test_user".to_string(),
+            created_at: now,
+            updated_at: now,
+        };
+
+        // Setup Expectations
+        let user_clone = expected_user.clone();
+        mock_db
+            .expect_create_user()
+            .withf(|username, password_hash| {
+                username == "test_user" && verify_password("secret", password_hash)
+            })
+            .times(1)
+            .returning(move |_, _| Ok(user_clone.clone()));
+
+        // Setup App
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/users", post(create_user))
+            .with_state(state);
+
+        // Execute
+        // CHANGE: Use build_form_request instead of build_json_request because the handler
+        // uses Form extractor.
+        let req = build_form_request(
+            "/users",
+            "username=test_user&password=secret".to_string(),
+            None,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        // Assert
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let user: User = deserialize_body(response).await;
+        assert_eq!(user, expected_user);
+    }
+
+    #[tokio::test]
+    async fn test_create_chat() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let now = Utc::now();
+
+        let member1 = new_uuid();
+        let member2 = new_uuid();
+        let current_user_id = new_uuid(); // Use a fixed ID for the session
+
+        // The handler adds the current user (from the session) to the members list.
+        // So we expect the DB to receive [member1, member2, current_user_id]
+        let mut expected_members = vec![member1, member2];
+        expected_members.push(current_user_id);
+
+        let expected_chat = Chat {
+            chat_id,
+            name: "test_chat".to_string(),
+            members: expected_members.clone(),
+            created_at: now,
+            roles: HashMap::from([(current_user_id, Role::Owner)]),
+            unread: 0,
+        };
+
+        // Setup Expectations
+        let chat_clone = expected_chat.clone();
+        let members_check = expected_members.clone();
+
+        mock_db
+            .expect_create_chat()
+            .withf(move |name, m, owner| {
+                name == "test_chat" && m == members_check && *owner == current_user_id
+            })
+            .times(1)
+            .returning(move |_, _, _| Ok(chat_clone.clone()));
+
+        let cookie = expect_valid_session(&mut mock_db, current_user_id);
+
+        // Setup App
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats", post(create_chat))
+            .with_state(state);
+
+        // Execute
+        let body_str = format!("name=test_chat&members={}&members={}", member1, member2);
+        let req = build_form_request("/chats", body_str, Some(&cookie));
+
+        let response = app.oneshot(req).await.unwrap();
+
+        // Assert
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let chat: Chat = deserialize_body(response).await;
+        assert_eq!(chat, expected_chat);
+    }
+
+    #[tokio::test]
+    async fn test_get_user() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let now = Utc::now();
+        let expected_user = User {
+            user_id,
+            username: "test_user".to_string(),
+            created_at: now,
+            updated_at: now,
+        };
+
+        // Setup Expectations
+        let user_clone = expected_user.clone();
+        mock_db
+            .expect_get_user()
+            .withf(move |id| *id == user_id)
+            .times(1)
+            .returning(move |_| Ok(user_clone.clone()));
+
+        // Setup App
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/users/{user_id}", get(get_user))
+            .with_state(state);
+
+        // Execute
+        let req = build_get_request(&format!("/users/{}", user_id));
+        let response = app.oneshot(req).await.unwrap();
+
+        // Assert
+        assert_eq!(response.status(), StatusCode::OK);
+        let user: User = deserialize_body(response).await;
+        assert_eq!(user, expected_user);
+    }
+
+    #[tokio::test]
+    async fn test_get_chat() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let now = Utc::now();
+        let members = vec![new_uuid(), new_uuid()];
+        let expected_chat = Chat {
+            chat_id,
+            name: "test_chat".to_string(),
+            members: members.clone(),
+            created_at: now,
+            roles: HashMap::new(),
+            unread: 0,
+        };
+
+        // Setup Expectations
+        let chat_clone = expected_chat.clone();
+        mock_db
+            .expect_get_chat()
+            .withf(move |id| *id == chat_id)
+            .times(1)
+            .returning(move |_| Ok(chat_clone.clone()));
+        let cookie = expect_valid_session(&mut mock_db, members[0]);
+        expect_members(&mut mock_db, chat_id, members.clone());
+
+        // Setup App
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}", get(get_chat))
+            .with_state(state);
+
+        // Execute
+        let req = build_get_request_with_cookie(&format!("/chats/{}", chat_id), &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        // Assert
+        assert_eq!(response.status(), StatusCode::OK);
+        let chat: Chat = deserialize_body(response).await;
+        assert_eq!(chat, expected_chat);
+    }
+
+    #[tokio::test]
+    async fn test_post_message() {
+        let mut mock_producer = MockProducer::new();
+        let chat_id = new_uuid();
+        let sender_id = new_uuid();
+        let message_content = "Hello, world!".to_string();
+        let create_message = CreatMessage {
+            content: message_content.clone(),
+            parent_id: None,
+        };
+
+        // Setup Expectations
+        let content_check = create_message.content.clone();
+        mock_producer
+            .expect_send_message()
+            .withf(move |msg| msg.sender_id == sender_id && msg.content == content_check)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        // Setup App
+        // The DB is only used to resolve the session and members, the message goes to the producer
+        let mut mock_db = MockDb::new();
+        let cookie = expect_valid_session(&mut mock_db, sender_id);
+        expect_members(&mut mock_db, chat_id, vec![sender_id]);
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", post(post_message))
+            .with_state(state);
+
+        // Execute
+        // post_message expects a Form, not JSON, so we use the form helper
+        // Note: crate::CreatMessage uses "content" field
+        let body_str = format!("content={}", message_content);
+        let req = build_form_request(
+            &format!("/chats/{}/messages", chat_id),
+            body_str,
+            Some(&cookie),
+        );
+
+        let response = app.oneshot(req).await.unwrap();
+
+        // Assert
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_post_reply_in_thread() {
+        let mut mock_db = MockDb::new();
+        let sender_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, sender_id);
+        expect_members(&mut mock_db, chat_id, vec![sender_id]);
+        let parent = test_message(chat_id, new_uuid(), "thoughts?");
+        let parent_id = parent.message_id;
+        expect_message(&mut mock_db, parent);
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_message()
+            .withf(move |msg| msg.parent_id == Some(parent_id) && msg.content == "agreed")
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", post(post_message))
+            .with_state(state);
+        let req = build_form_request(
+            &format!("/chats/{}/messages", chat_id),
+            format!("content=agreed&parent_id={}", parent_id),
+            Some(&cookie),
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_reply_to_unknown_message_is_not_found() {
+        let mut mock_db = MockDb::new();
+        let sender_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, sender_id);
+        expect_members(&mut mock_db, chat_id, vec![sender_id]);
+        mock_db
+            .expect_get_message()
+            .returning(|_, _| Err(anyhow!(RowNotFound).context("Could not fetch message")));
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", post(post_message))
+            .with_state(state);
+        let req = build_form_request(
+            &format!("/chats/{}/messages", chat_id),
+            format!("content=lost&parent_id={}", new_uuid()),
+            Some(&cookie),
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_upload_image_stores_it_with_a_thumbnail_and_posts_the_message() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let puts: Arc<std::sync::Mutex<Vec<(String, String)>>> = Arc::default();
+        let mut mock_storage = MockStorage::new();
+        let recorded = puts.clone();
+        mock_storage
+            .expect_put()
+            .times(2)
+            .returning(move |key, _, content_type| {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((key.to_string(), content_type.to_string()));
+                Ok(())
+            });
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_message()
+            .withf(|msg| {
+                msg.content == "look"
+                    && msg.attachments.len() == 1
+                    && msg.attachments[0].filename == "cat.png"
+                    && msg.attachments[0].content_type == "image/png"
+                    && msg.attachments[0].thumbnail
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = AppState {
+            storage: Arc::new(mock_storage),
+            ..create_test_state(mock_db, mock_producer)
+        };
+        let app = Router::new()
+            .route("/chats/{chat_id}/attachments", post(upload_attachments))
+            .with_state(state);
+        let png = test_png();
+        let req = build_multipart_request(
+            &format!("/chats/{}/attachments", chat_id),
+            &[
+                ("content", None, b"look"),
+                ("file", Some("C:\\fakepath\\cat.png"), &png),
+            ],
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let upload: Upload = serde_json::from_str(&read_body(response).await).unwrap();
+        let attachment = &upload.attachments[0];
+        assert_eq!(attachment.size, png.len() as i64);
+        let attachment_id = attachment.attachment_id;
+        assert_eq!(
+            *puts.lock().unwrap(),
+            [
+                (
+                    thumbnail_key(chat_id, attachment_id),
+                    "image/png".to_string()
+                ),
+                (
+                    attachment_key(chat_id, attachment_id),
+                    "image/png".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_rejects_types_not_accepted() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let mut mock_storage = MockStorage::new();
+        mock_storage.expect_put().never();
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().never();
+
+        let state = AppState {
+            storage: Arc::new(mock_storage),
+            ..create_test_state(mock_db, mock_producer)
+        };
+        let app = Router::new()
+            .route("/chats/{chat_id}/attachments", post(upload_attachments))
+            .with_state(state);
+        // Announced as a picture, but it is an executable
+        let req = build_multipart_request(
+            &format!("/chats/{}/attachments", chat_id),
+            &[("file", Some("cat.png"), b"\x7fELF\x02\x01\x01\0\0\0")],
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_upload_rejects_files_over_the_limit() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let mut mock_storage = MockStorage::new();
+        mock_storage.expect_put().never();
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().never();
+
+        let state = AppState {
+            storage: Arc::new(mock_storage),
+            max_attachment_bytes: 4,
+            ..create_test_state(mock_db, mock_producer)
+        };
+        let app = Router::new()
+            .route("/chats/{chat_id}/attachments", post(upload_attachments))
+            .with_state(state);
+        let req = build_multipart_request(
+            &format!("/chats/{}/attachments", chat_id),
+            &[("file", Some("notes.txt"), b"hello")],
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_upload_removes_the_files_when_the_message_is_not_sent() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let mut mock_storage = MockStorage::new();
+        mock_storage
+            .expect_put()
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        mock_storage
+            .expect_delete()
+            .withf(move |key| key.starts_with(&chat_id.to_string()))
+            .times(1)
+            .returning(|_| Ok(()));
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_message()
+            .returning(|_| Err(anyhow!("broker down")));
+
+        let state = AppState {
+            storage: Arc::new(mock_storage),
+            ..create_test_state(mock_db, mock_producer)
+        };
+        let app = Router::new()
+            .route("/chats/{chat_id}/attachments", post(upload_attachments))
+            .with_state(state);
+        let req = build_multipart_request(
+            &format!("/chats/{}/attachments", chat_id),
+            &[("file", Some("notes.txt"), b"hello")],
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_download_attachment_serves_it_as_stored() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let message_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let attachment = test_attachment("application/pdf");
+        let attachment_id = attachment.attachment_id;
+        mock_db
+            .expect_get_attachment()
+            .withf(move |c, m, a| *c == chat_id && *m == message_id && *a == attachment_id)
+            .returning(move |_, _, _| Ok(attachment.clone()));
+        let mut mock_storage = MockStorage::new();
+        mock_storage
+            .expect_get()
+            .withf(move |key| key == attachment_key(chat_id, attachment_id))
+            .returning(|_| Ok(Some(Bytes::from_static(b"%PDF-1.7"))));
+
+        let state = AppState {
+            storage: Arc::new(mock_storage),
+            ..create_test_state(mock_db, MockProducer::new())
+        };
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/messages/{message_id}/attachments/{attachment_id}",
+                get(download_attachment),
+            )
+            .with_state(state);
+        let req = build_get_request_with_cookie(
+            &format!(
+                "/chats/{}/messages/{}/attachments/{}",
+                chat_id, message_id, attachment_id
+            ),
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[http::header::CONTENT_TYPE], "application/pdf");
+        assert!(
+            headers[http::header::CONTENT_DISPOSITION]
+                .to_str()
+                .unwrap()
+                .starts_with("attachment; filename=\"report.pdf\"")
+        );
+        assert_eq!(headers[http::header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(read_body(response).await, "%PDF-1.7");
+    }
+
+    #[tokio::test]
+    async fn test_download_attachment_is_for_members_only() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![new_uuid()]);
+        mock_db.expect_get_attachment().never();
+        let mut mock_storage = MockStorage::new();
+        mock_storage.expect_get().never();
+
+        let state = AppState {
+            storage: Arc::new(mock_storage),
+            ..create_test_state(mock_db, MockProducer::new())
+        };
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/messages/{message_id}/attachments/{attachment_id}",
+                get(download_attachment),
+            )
+            .with_state(state);
+        let req = build_get_request_with_cookie(
+            &format!(
+                "/chats/{}/messages/{}/attachments/{}",
+                chat_id,
+                new_uuid(),
+                new_uuid()
+            ),
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_thumbnail_of_a_file_that_is_not_an_image_is_not_found() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let attachment = test_attachment("application/pdf");
+        let attachment_id = attachment.attachment_id;
+        mock_db
+            .expect_get_attachment()
+            .returning(move |_, _, _| Ok(attachment.clone()));
+        let mut mock_storage = MockStorage::new();
+        mock_storage.expect_get().never();
+
+        let state = AppState {
+            storage: Arc::new(mock_storage),
+            ..create_test_state(mock_db, MockProducer::new())
+        };
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/messages/{message_id}/attachments/{attachment_id}/thumbnail",
+                get(download_thumbnail),
+            )
+            .with_state(state);
+        let req = build_get_request_with_cookie(
+            &format!(
+                "/chats/{}/messages/{}/attachments/{}/thumbnail",
+                chat_id,
+                new_uuid(),
+                attachment_id
+            ),
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_thread_returns_parent_and_replies() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let parent = test_message(chat_id, user_id, "thoughts?");
+        let parent_id = parent.message_id;
+        expect_message(&mut mock_db, parent);
+        mock_db
+            .expect_get_thread()
+            .withf(move |c, p| *c == chat_id && *p == parent_id)
+            .times(1)
+            .returning(move |chat_id, parent_id| {
+                Ok(["first", "second"]
+                    .into_iter()
+                    .map(|content| PandaMessage {
+                        parent_id: Some(parent_id),
+                        ..test_message(chat_id, new_uuid(), content)
+                    })
+                    .collect())
+            });
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/messages/{message_id}/thread",
+                get(get_thread),
+            )
+            .with_state(state);
+        let req = build_get_request_with_cookie(
+            &format!("/chats/{}/messages/{}/thread", chat_id, parent_id),
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let thread: Thread = serde_json::from_str(&read_body(response).await).unwrap();
+        assert_eq!(thread.parent.message_id, parent_id);
+        let replies: Vec<&str> = thread.replies.iter().map(|r| r.content.as_str()).collect();
+        assert_eq!(replies, ["first", "second"]);
+        assert!(
+            thread
+                .replies
+                .iter()
+                .all(|r| r.parent_id == Some(parent_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_thread_htmx_follows_it_and_history_shows_reply_counts() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let parent = PandaMessage {
+            reply_count: 2,
+            ..test_message(chat_id, user_id, "thoughts?")
+        };
+        let parent_id = parent.message_id;
+        expect_message(&mut mock_db, parent.clone());
+        mock_db.expect_get_thread().returning(|_, _| Ok(Vec::new()));
+        mock_db.expect_get_messages().returning(move |_, _, _| {
+            Ok(MessagePage {
+                messages: vec![parent.clone()],
+                next_cursor: None,
+            })
+        });
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", get(get_messages))
+            .route(
+                "/chats/{chat_id}/messages/{message_id}/thread",
+                get(get_thread),
+            )
+            .with_state(state);
+
+        let mut req = build_get_request_with_cookie(
+            &format!("/chats/{}/messages/{}/thread", chat_id, parent_id),
+            &cookie,
+        );
+        req.headers_mut()
+            .insert("hx-request", http::HeaderValue::from_static("true"));
+        let body = read_body(app.clone().oneshot(req).await.unwrap()).await;
+        assert!(body.contains(&format!("id=\"replies-{}\"", parent_id)));
+        assert!(body.contains(&format!(
+            r#"hx-vals='{{"type": "thread", "thread_id": "{}"}}'"#,
+            parent_id
+        )));
+
+        let mut req =
+            build_get_request_with_cookie(&format!("/chats/{}/messages", chat_id), &cookie);
+        req.headers_mut()
+            .insert("hx-request", http::HeaderValue::from_static("true"));
+        let body = read_body(app.oneshot(req).await.unwrap()).await;
+        assert!(body.contains("2 replies"));
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_without_cookie_redirects() {
+        let mock_db = MockDb::new();
+        // Expect no DB calls since the handler should redirect immediately if cookie is missing
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/dashboard", get(dashboard))
+            .with_state(state);
+
+        let req = build_get_request("/dashboard");
+        let response = app.oneshot(req).await.unwrap();
+
+        assert!(
+            response.status().is_redirection(),
+            "Expected redirection (3xx) when accessing dashboard without cookie, but got status: {}",
+            response.status()
+        );
+
+        let location = response
+            .headers()
+            .get(http::header::LOCATION)
+            .expect("Missing Location header")
+            .to_str()
+            .expect("Invalid Location header value");
+
+        // Accepts redirect to root (login) or logout endpoint
+        assert!(
+            location == "/" || location == "/logout",
+            "Expected redirect to '/' or '/logout', got: {}",
+            location
+        );
+    }
+    #[tokio::test]
+    async fn test_login_with_valid_password_starts_session() {
+        let mut mock_db = MockDb::new();
+        let user = test_user("test_user");
+        let user_id = user.user_id;
+        let password_hash = hash_password("secret").unwrap();
+
+        mock_db
+            .expect_get_user_by_username()
+            .withf(|username| username == "test_user")
+            .times(1)
+            .returning(move |_| Ok(user.clone()));
+        mock_db
+            .expect_get_password_hash()
+            .withf(move |id| *id == user_id)
+            .times(1)
+            .returning(move |_| Ok(password_hash.clone()));
+        let session = test_session(user_id, Utc::now() + TEST_SESSION_TTL);
+        let session_id = session.session_id;
+        mock_db
+            .expect_create_session()
+            .withf(move |id, ttl| *id == user_id && *ttl == TEST_SESSION_TTL)
+            .times(1)
+            .returning(move |_, _| Ok(session.clone()));
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new().route("/login", post(login)).with_state(state);
+
+        let req = build_form_request(
+            "/login",
+            "username=test_user&password=secret".to_string(),
+            None,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert!(response.status().is_redirection());
+        let cookie = response
+            .headers()
+            .get(http::header::SET_COOKIE)
+            .expect("Missing Set-Cookie header")
+            .to_str()
+            .unwrap();
+        assert!(cookie.starts_with(&format!("{}={}", SESSION_COOKIE, session_id)));
+        assert!(cookie.contains("HttpOnly"));
+    }
+
+    #[tokio::test]
+    async fn test_login_with_wrong_password_is_unauthorized() {
+        let mut mock_db = MockDb::new();
+        let user = test_user("test_user");
+        let password_hash = hash_password("secret").unwrap();
+
+        mock_db
+            .expect_get_user_by_username()
+            .times(1)
+            .returning(move |_| Ok(user.clone()));
+        mock_db
+            .expect_get_password_hash()
+            .times(1)
+            .returning(move |_| Ok(password_hash.clone()));
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new().route("/login", post(login)).with_state(state);
+
+        let req = build_form_request(
+            "/login",
+            "username=test_user&password=wrong".to_string(),
+            None,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().get(http::header::SET_COOKIE).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_login_with_unknown_user_renders_htmx_error() {
+        let mut mock_db = MockDb::new();
+        mock_db
+            .expect_get_user_by_username()
+            .times(1)
+            .returning(|_| Err(anyhow::anyhow!("User not found")));
+        // The password is never looked at when the user does not exist
+        mock_db.expect_get_password_hash().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new().route("/login", post(login)).with_state(state);
+
+        let mut req =
+            build_form_request("/login", "username=ghost&password=secret".to_string(), None);
+        req.headers_mut()
+            .insert("hx-request", http::HeaderValue::from_static("true"));
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body = read_body(response).await;
+        assert!(body.contains("Invalid username or password"));
+    }
+    #[tokio::test]
+    async fn test_post_message_with_forged_user_id_cookie_is_unauthorized() {
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().never();
+
+        // The legacy raw user_id cookie is not a session and must not be trusted
+        let state = create_test_state(MockDb::new(), mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", post(post_message))
+            .with_state(state);
+
+        let cookie = format!("user_id={}", new_uuid());
+        let req = build_form_request(
+            &format!("/chats/{}/messages", new_uuid()),
+            "content=hi".to_string(),
+            Some(&cookie),
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_expired_session_is_unauthorized() {
+        let mut mock_db = MockDb::new();
+        let session = test_session(new_uuid(), Utc::now() - Duration::from_secs(1));
+        let cookie = format!("{}={}", SESSION_COOKIE, session.session_id);
+        mock_db
+            .expect_get_session()
+            .times(1)
+            .returning(move |_| Ok(session.clone()));
+        mock_db.expect_refresh_session().never();
+
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", post(post_message))
+            .with_state(state);
+
+        let req = build_form_request(
+            &format!("/chats/{}/messages", new_uuid()),
+            "content=hi".to_string(),
+            Some(&cookie),
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_session_past_half_life_is_refreshed() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let session = test_session(user_id, Utc::now() + TEST_SESSION_TTL / 4);
+        let session_id = session.session_id;
+        let cookie = format!("{}={}", SESSION_COOKIE, session_id);
+        let refreshed = test_session(user_id, Utc::now() + TEST_SESSION_TTL);
+        mock_db
+            .expect_get_session()
+            .times(1)
+            .returning(move |_| Ok(session.clone()));
+        mock_db
+            .expect_refresh_session()
+            .withf(move |session, ttl| session.session_id == session_id && *ttl == TEST_SESSION_TTL)
+            .times(1)
+            .returning(move |_, _| Ok(refreshed.clone()));
+
+        let chat_id = new_uuid();
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_message()
+            .withf(move |msg| msg.sender_id == user_id)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", post(post_message))
+            .with_state(state);
+
+        let req = build_form_request(
+            &format!("/chats/{}/messages", chat_id),
+            "content=hi".to_string(),
+            Some(&cookie),
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_session_check_of_open_connections_does_not_refresh() {
+        let mut mock_db = MockDb::new();
+        let session = test_session(new_uuid(), Utc::now() + TEST_SESSION_TTL / 4);
+        let session_id = session.session_id;
+        mock_db
+            .expect_get_session()
+            .times(1)
+            .returning(move |_| Ok(session.clone()));
+        mock_db.expect_refresh_session().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+
+        assert!(CurrentUser::is_session_active(&state, session_id).await);
+    }
+
+    #[tokio::test]
+    async fn test_logout_revokes_session() {
+        let mut mock_db = MockDb::new();
+        let session_id = new_uuid();
+        mock_db
+            .expect_delete_session()
+            .withf(move |id| *id == session_id)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/logout", get(logout))
+            .with_state(state);
+
+        let req = Request::builder()
+            .method(http::Method::GET)
+            .uri("/logout")
+            .header(
+                http::header::COOKIE,
+                format!("{}={}", SESSION_COOKIE, session_id),
+            )
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+
+        assert!(response.status().is_redirection());
+        let cookie = response
+            .headers()
+            .get(http::header::SET_COOKIE)
+            .expect("Missing Set-Cookie header")
+            .to_str()
+            .unwrap();
+        assert!(cookie.starts_with(&format!("{}=;", SESSION_COOKIE)));
+    }
+    #[tokio::test]
+    async fn test_websocket_without_session_is_unauthorized() {
+        let state = create_test_state(MockDb::new(), MockProducer::new());
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+
+        let status =
+            websocket_handshake_status(addr, &format!("/ws/connect/{}", new_uuid()), None).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_for_another_user_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let cookie = expect_valid_session(&mut mock_db, new_uuid());
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+
+        // Valid session, but asking for someone else's stream
+        let path = format!("/ws/connect/{}", new_uuid());
+        let status = websocket_handshake_status(addr, &path, Some(&cookie)).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_with_own_session_is_upgraded() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+
+        let path = format!("/ws/connect/{}", user_id);
+        let status = websocket_handshake_status(addr, &path, Some(&cookie)).await;
+
+        assert_eq!(status, StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_send_frame_posts_message() {
+        let mut mock_db = MockDb::new();
+        let (user_id, chat_id) = (new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+
+        let (sent, mut sent_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_message()
+            .withf(move |m| m.chat_id == chat_id && m.sender_id == user_id)
+            .times(1)
+            .returning(move |m| {
+                sent.send(m.content).unwrap();
+                Ok(())
+            });
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let mut socket =
+            connect_websocket(addr, &format!("/ws/connect/{}", user_id), &cookie).await;
+
+        let frame = format!(
+            r#"{{"type":"send","chat_id":"{}","content":"over the socket","HEADERS":{{}}}}"#,
+            chat_id
+        );
+        socket.send(WsMessage::text(frame)).await.unwrap();
+
+        let content = tokio::time::timeout(Duration::from_secs(2), sent_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(content, "over the socket");
+    }
+
+    #[tokio::test]
+    async fn test_websocket_send_frame_to_foreign_chat_replies_error() {
+        let mut mock_db = MockDb::new();
+        let (user_id, chat_id) = (new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![new_uuid()]);
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let mut socket =
+            connect_websocket(addr, &format!("/ws/connect/{}", user_id), &cookie).await;
+
+        let frame = format!(
+            r#"{{"type":"send","chat_id":"{}","content":"let me in"}}"#,
+            chat_id
+        );
+        socket.send(WsMessage::text(frame)).await.unwrap();
+
+        let reply = next_text(&mut socket).await;
+        assert!(reply.contains("id=\"ws-error\""));
+        assert!(reply.contains("Forbidden"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_invalid_frame_replies_error_and_stays_open() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let mut socket =
+            connect_websocket(addr, &format!("/ws/connect/{}", user_id), &cookie).await;
+
+        for frame in ["not json", r#"{"type":"shout"}"#] {
+            socket.send(WsMessage::text(frame)).await.unwrap();
+            assert!(next_text(&mut socket).await.contains("Invalid frame"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_subscription_filters_other_chats() {
+        let mut mock_db = MockDb::new();
+        let (user_id, open_chat, other_chat) = (new_uuid(), new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, open_chat, vec![user_id]);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let connections = state.connections_map.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let mut socket =
+            connect_websocket(addr, &format!("/ws/connect/{}", user_id), &cookie).await;
+
+        let frame = format!(r#"{{"type":"subscribe","chat_id":"{}"}}"#, open_chat);
+        socket.send(WsMessage::text(frame)).await.unwrap();
+        // Frames are handled in order: once this one is answered the subscription is in place
+        socket.send(WsMessage::text("ping")).await.unwrap();
+        next_text(&mut socket).await;
+
+        let sender = connections.read().await[&user_id]
+            .values()
+            .next()
+            .unwrap()
+            .sender
+            .clone();
+        sender
+            .send(test_message(other_chat, new_uuid(), "elsewhere").into())
+            .await
+            .unwrap();
+        sender
+            .send(test_message(open_chat, new_uuid(), "right here").into())
+            .await
+            .unwrap();
+
+        let delivered = next_text(&mut socket).await;
+        assert!(delivered.contains("right here"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_follows_the_open_thread() {
+        let mut mock_db = MockDb::new();
+        let (user_id, chat_id, thread_id) = (new_uuid(), new_uuid(), Uuid::now_v1(&NODE_ID));
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let connections = state.connections_map.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let mut socket =
+            connect_websocket(addr, &format!("/ws/connect/{}", user_id), &cookie).await;
+
+        let frame = format!(r#"{{"type":"thread","thread_id":"{}"}}"#, thread_id);
+        socket.send(WsMessage::text(frame)).await.unwrap();
+        socket.send(WsMessage::text("ping")).await.unwrap();
+        next_text(&mut socket).await;
+
+        let connection = connections.read().await[&user_id]
+            .values()
+            .next()
+            .unwrap()
+            .clone();
+        assert!(connection.follows_thread(thread_id));
+        assert!(!connection.follows_thread(new_uuid()));
+
+        // Replies are appended to their thread, not to the chat
+        let reply = PandaMessage {
+            parent_id: Some(thread_id),
+            ..test_message(chat_id, new_uuid(), "in the thread")
+        };
+        connection.sender.send(reply.into()).await.unwrap();
+        let fragment = next_text(&mut socket).await;
+        assert!(fragment.contains(&format!("hx-swap-oob=\"beforeend:#replies-{}\"", thread_id)));
+        assert!(fragment.contains("in the thread"));
+
+        socket
+            .send(WsMessage::text(r#"{"type":"thread","thread_id":null}"#))
+            .await
+            .unwrap();
+        socket.send(WsMessage::text("ping")).await.unwrap();
+        next_text(&mut socket).await;
+        assert!(!connection.follows_thread(thread_id));
+    }
+
+    #[tokio::test]
+    async fn test_every_tab_of_a_user_stays_connected() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let connections = state.connections_map.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let path = format!("/ws/connect/{}", user_id);
+        let connection_count = || async {
+            connections
+                .read()
+                .await
+                .get(&user_id)
+                .map_or(0, |tabs| tabs.len())
+        };
+
+        let first_tab = connect_websocket(addr, &path, &cookie).await;
+        let mut second_tab = connect_websocket(addr, &path, &cookie).await;
+        wait_until(|| async { connection_count().await == 2 }).await;
+
+        // Closing the first tab must not unregister the second one
+        drop(first_tab);
+        wait_until(|| async { connection_count().await == 1 }).await;
+
+        let senders: Vec<_> = connections.read().await[&user_id]
+            .values()
+            .map(|connection| connection.sender.clone())
+            .collect();
+        for sender in senders {
+            sender
+                .send(test_message(new_uuid(), new_uuid(), "still here").into())
+                .await
+                .unwrap();
+        }
+        assert!(next_text(&mut second_tab).await.contains("still here"));
+
+        drop(second_tab);
+        wait_until(|| async { !connections.read().await.contains_key(&user_id) }).await;
+    }
+
+    #[tokio::test]
+    async fn test_websocket_replays_missed_messages_of_every_chat() {
+        let mut mock_db = MockDb::new();
+        let (user_id, first_chat, second_chat) = (new_uuid(), new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        let last_seen = Uuid::now_v1(&NODE_ID);
+        let missed = [
+            test_message(first_chat, new_uuid(), "first missed"),
+            test_message(second_chat, new_uuid(), "second missed"),
+        ];
+        mock_db.expect_get_chats_for_user().returning(move |_| {
+            Ok([first_chat, second_chat]
+                .into_iter()
+                .map(|chat_id| Chat {
+                    chat_id,
+                    ..test_chat(user_id, vec![user_id])
+                })
+                .collect())
+        });
+        mock_db
+            .expect_get_messages_after()
+            .withf(move |_, after, _| *after == last_seen)
+            .returning(move |chat_id, _, _| {
+                Ok(missed
+                    .iter()
+                    .filter(|m| m.chat_id == chat_id)
+                    .cloned()
+                    .collect())
+            });
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let path = format!("/ws/connect/{}?last_seen={}", user_id, last_seen);
+        let mut socket = connect_websocket(addr, &path, &cookie).await;
+
+        assert!(next_text(&mut socket).await.contains("first missed"));
+        assert!(next_text(&mut socket).await.contains("second missed"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_replay_has_no_duplicate_or_gap_at_the_boundary() {
+        let mut mock_db = MockDb::new();
+        let (user_id, chat_id) = (new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let connections = ConnectionMap::default();
+
+        let stored = test_message(chat_id, new_uuid(), "stored before connect");
+        let in_flight = test_message(chat_id, new_uuid(), "stored while replaying");
+        let live_connections = connections.clone();
+        mock_db
+            .expect_get_messages_after()
+            .returning(move |_, _, _| {
+                // The consumer broadcasts both while the replay reads the database
+                let map = live_connections.try_read().unwrap();
+                let sender = &map[&user_id].values().next().unwrap().sender;
+                sender.try_send(stored.clone().into()).unwrap();
+                sender.try_send(in_flight.clone().into()).unwrap();
+                Ok(vec![stored.clone()])
+            });
+
+        let mut state = create_test_state(mock_db, MockProducer::new());
+        state.connections_map = connections.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let path = format!(
+            "/ws/connect/{}?chat_id={}&last_seen={}",
+            user_id,
+            chat_id,
+            Uuid::now_v1(&NODE_ID)
+        );
+        let mut socket = connect_websocket(addr, &path, &cookie).await;
+
+        assert!(
+            next_text(&mut socket)
+                .await
+                .contains("stored before connect")
+        );
+        assert!(
+            next_text(&mut socket)
+                .await
+                .contains("stored while replaying")
+        );
+        let sender = connections.read().await[&user_id]
+            .values()
+            .next()
+            .unwrap()
+            .sender
+            .clone();
+        sender
+            .send(test_message(chat_id, new_uuid(), "live").into())
+            .await
+            .unwrap();
+        assert!(next_text(&mut socket).await.contains("live"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_for_foreign_chat_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let (user_id, chat_id) = (new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![new_uuid()]);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let path = format!("/ws/connect/{}?chat_id={}", user_id, chat_id);
+
+        let status = websocket_handshake_status(addr, &path, Some(&cookie)).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_json_format_from_query() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let connections = state.connections_map.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let path = format!("/ws/connect/{}?format=json", user_id);
+        let mut socket = connect_websocket(addr, &path, &cookie).await;
+        wait_until(|| async { connections.read().await.contains_key(&user_id) }).await;
+
+        let message = test_message(new_uuid(), new_uuid(), "plain data");
+        let sender = connections.read().await[&user_id]
+            .values()
+            .next()
+            .unwrap()
+            .sender
+            .clone();
+        sender.send(message.clone().into()).await.unwrap();
+
+        let frame: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+        assert_eq!(frame["type"], "message_posted");
+        assert_eq!(frame["message"]["content"], "plain data");
+        assert_eq!(
+            frame["message"]["message_id"],
+            message.message_id.to_string()
+        );
+
+        socket.send(WsMessage::text("not json")).await.unwrap();
+        let frame: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+        assert_eq!(frame["type"], "error");
+        assert!(frame["error"].as_str().unwrap().contains("Invalid frame"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_json_subprotocol_sends_event_types() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let connections = state.connections_map.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let mut socket =
+            connect_json_websocket(addr, &format!("/ws/connect/{}", user_id), &cookie).await;
+        wait_until(|| async { connections.read().await.contains_key(&user_id) }).await;
+
+        let deleted = PandaMessage {
+            deleted: true,
+            ..test_message(new_uuid(), new_uuid(), "")
+        };
+        let edited = PandaMessage {
+            edited_at: Some(Utc::now()),
+            ..test_message(new_uuid(), new_uuid(), "fixed typo")
+        };
+        let sender = connections.read().await[&user_id]
+            .values()
+            .next()
+            .unwrap()
+            .sender
+            .clone();
+        sender.send(deleted.into()).await.unwrap();
+        sender.send(edited.into()).await.unwrap();
+
+        let frame: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+        assert_eq!(frame["type"], "message_deleted");
+        let frame: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+        assert_eq!(frame["type"], "message_edited");
+        assert_eq!(frame["message"]["content"], "fixed typo");
+    }
+
+    #[tokio::test]
+    async fn test_events_stream_live_messages_and_unregister_on_disconnect() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let connections = state.connections_map.clone();
+        let app = Router::new()
+            .route("/events", get(get_events))
+            .with_state(state);
+
+        let response = app
+            .oneshot(build_get_request_with_cookie(
+                "/events?format=json",
+                &cookie,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        let mut body = response.into_body().into_data_stream();
+
+        let message = test_message(new_uuid(), new_uuid(), "over sse");
+        let connection = connections.read().await[&user_id]
+            .values()
+            .next()
+            .unwrap()
+            .clone();
+        connection.deliver(message.clone());
+
+        let event = next_sse_event(&mut body).await;
+        assert!(event.contains("event: message"));
+        assert!(event.contains(&format!("id: {}", message.message_id)));
+        assert!(event.contains(r#""type":"message_posted""#));
+        assert!(event.contains("over sse"));
+
+        // The client going away is the stream being dropped
+        drop(body);
+        wait_until(|| async { !connections.read().await.contains_key(&user_id) }).await;
+    }
+
+    #[tokio::test]
+    async fn test_events_resume_from_last_event_id() {
+        let mut mock_db = MockDb::new();
+        let (user_id, chat_id) = (new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let last_event_id = Uuid::now_v1(&NODE_ID);
+        let missed = test_message(chat_id, new_uuid(), "missed while away");
+        let missed_id = missed.message_id;
+        mock_db
+            .expect_get_messages_after()
+            .withf(move |c, after, _| *c == chat_id && *after == last_event_id)
+            .returning(move |_, _, _| Ok(vec![missed.clone()]));
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/events", get(get_events))
+            .with_state(state);
+
+        let mut request =
+            build_get_request_with_cookie(&format!("/events?chat_id={}", chat_id), &cookie);
+        request
+            .headers_mut()
+            .insert("last-event-id", last_event_id.to_string().parse().unwrap());
+        let response = app.oneshot(request).await.unwrap();
+        let mut body = response.into_body().into_data_stream();
+
+        let event = next_sse_event(&mut body).await;
+        assert!(event.contains(&format!("id: {}", missed_id)));
+        assert!(event.contains(&format!("msg-{}", missed_id)));
+        assert!(event.contains("missed while away"));
+    }
+
+    #[tokio::test]
+    async fn test_events_with_invalid_last_event_id_is_bad_request() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/events", get(get_events))
+            .with_state(state);
+
+        let mut request = build_get_request_with_cookie("/events", &cookie);
+        request
+            .headers_mut()
+            .insert("last-event-id", "not-a-uuid".parse().unwrap());
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_typing_is_relayed_once_per_throttle_window() {
+        let mut mock_db = MockDb::new();
+        let alice = test_user("alice");
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, alice.user_id);
+        expect_members(&mut mock_db, chat_id, vec![alice.user_id]);
+        expect_users(&mut mock_db, vec![alice.clone()]);
+
+        let mut mock_producer = MockProducer::new();
+        let alice_id = alice.user_id;
+        mock_producer
+            .expect_send_event()
+            .withf(move |event| {
+                matches!(event, ChatEvent::Typing(typing)
+                    if typing.chat_id == chat_id && typing.user_id == alice_id && typing.username == "alice")
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/typing", post(post_typing))
+            .with_state(state);
+
+        // Keystrokes right after the first one are accepted but not relayed
+        for _ in 0..3 {
+            let response = app
+                .clone()
+                .oneshot(build_htmx_request_with_cookie(
+                    http::Method::POST,
+                    &format!("/chats/{}/typing", chat_id),
+                    "",
+                    &cookie,
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_typing_reads_the_username_once() {
+        let mut mock_db = MockDb::new();
+        let alice = test_user("alice");
+        let (first_chat, second_chat) = (new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, alice.user_id);
+        expect_members(&mut mock_db, first_chat, vec![alice.user_id]);
+        expect_members(&mut mock_db, second_chat, vec![alice.user_id]);
+        let user = alice.clone();
+        mock_db
+            .expect_get_user()
+            .times(1)
+            .returning(move |_| Ok(user.clone()));
+
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_event()
+            .withf(|event| matches!(event, ChatEvent::Typing(typing) if typing.username == "alice"))
+            .times(2)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/typing", post(post_typing))
+            .with_state(state);
+
+        for chat_id in [first_chat, second_chat] {
+            let response = app
+                .clone()
+                .oneshot(build_htmx_request_with_cookie(
+                    http::Method::POST,
+                    &format!("/chats/{}/typing", chat_id),
+                    "",
+                    &cookie,
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_typing_in_foreign_chat_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let (user_id, chat_id) = (new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![new_uuid()]);
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_event().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/typing", post(post_typing))
+            .with_state(state);
+        let response = app
+            .oneshot(build_htmx_request_with_cookie(
+                http::Method::POST,
+                &format!("/chats/{}/typing", chat_id),
+                "",
+                &cookie,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_typing_frame_is_published_and_rendered() {
+        let mut mock_db = MockDb::new();
+        let alice = test_user("alice");
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, alice.user_id);
+        expect_members(&mut mock_db, chat_id, vec![alice.user_id]);
+        expect_users(&mut mock_db, vec![alice.clone()]);
+
+        let published = Arc::new(AtomicUsize::new(0));
+        let counter = published.clone();
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_event()
+            .withf(|event| matches!(event, ChatEvent::Typing(_)))
+            .returning(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+
+        let state = create_test_state(mock_db, mock_producer);
+        let connections = state.connections_map.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let mut socket =
+            connect_websocket(addr, &format!("/ws/connect/{}", alice.user_id), &cookie).await;
+
+        let frame = format!(r#"{{"type":"typing","chat_id":"{}"}}"#, chat_id);
+        socket.send(WsMessage::text(frame)).await.unwrap();
+        wait_until(|| async { published.load(Ordering::SeqCst) == 1 }).await;
+
+        // What the consumer hands over when bob types in the same chat
+        let bob = new_uuid();
+        let connection = connections.read().await[&alice.user_id]
+            .values()
+            .next()
+            .unwrap()
+            .clone();
+        connection.deliver(Delivery::Typing(Typing {
+            chat_id,
+            user_id: bob,
+            username: "bob".to_string(),
+            at: Utc::now(),
+        }));
+
+        let rendered = next_text(&mut socket).await;
+        assert!(rendered.contains(&format!(r#"id="typing-{}-{}""#, chat_id, bob)));
+        assert!(rendered.contains("bob is typing"));
+    }
+
+    #[tokio::test]
+    async fn test_presence_follows_websocket_connections() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let presence = state.presence.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .route("/users/{user_id}/presence", get(get_presence))
+            .with_state(state);
+        let addr = spawn_server(app.clone()).await;
+        let path = format!("/ws/connect/{}", user_id);
+        assert_eq!(presence.status(user_id), Status::Offline);
+
+        let mut first_tab = connect_websocket(addr, &path, &cookie).await;
+        wait_until(|| async { presence.status(user_id) == Status::Online }).await;
+        let response = app
+            .oneshot(build_get_request_with_cookie(
+                &format!("/users/{}/presence", user_id),
+                &cookie,
+            ))
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&read_body(response).await).unwrap();
+        assert_eq!(body["status"], "online");
+
+        // Away only once every tab is in the background
+        let second_tab = connect_websocket(addr, &path, &cookie).await;
+        let away = r#"{"type":"presence","status":"away"}"#;
+        first_tab.send(WsMessage::text(away)).await.unwrap();
+        drop(second_tab);
+        wait_until(|| async { presence.status(user_id) == Status::Away }).await;
+
+        drop(first_tab);
+        wait_until(|| async { presence.status(user_id) == Status::Offline }).await;
+    }
+
+    #[tokio::test]
+    async fn test_presence_dot_for_htmx() {
+        let mut mock_db = MockDb::new();
+        let (viewer_id, user_id) = (new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, viewer_id);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        state.presence.set_local(user_id, Status::Online);
+        let app = Router::new()
+            .route("/users/{user_id}/presence", get(get_presence))
+            .with_state(state);
+        let mut request =
+            build_get_request_with_cookie(&format!("/users/{}/presence", user_id), &cookie);
+        request
+            .headers_mut()
+            .insert("hx-request", "true".parse().unwrap());
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_body(response).await;
+        assert!(body.contains("bg-green-500"));
+        assert!(body.contains(r#"title="online""#));
+    }
+
+    /// Connects a user whose connection only buffers one message
+    async fn connect_slow_client(max_dropped: Option<u64>) -> (TestSocket, ConnectionMap, Uuid) {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let mut state = create_test_state(mock_db, MockProducer::new());
+        state.slow_clients = SlowClientPolicy {
+            buffer: 1,
+            max_dropped,
+        };
+        let connections = state.connections_map.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let socket = connect_websocket(addr, &format!("/ws/connect/{}", user_id), &cookie).await;
+        wait_until(|| async { connections.read().await.contains_key(&user_id) }).await;
+        (socket, connections, user_id)
+    }
+
+    #[tokio::test]
+    async fn test_lagging_websocket_is_asked_to_resync() {
+        let (mut socket, connections, user_id) = connect_slow_client(None).await;
+        let connection = connections.read().await[&user_id]
+            .values()
+            .next()
+            .unwrap()
+            .clone();
+
+        // Nothing is read between the deliveries, the buffer only takes the first one
+        for n in 0..5 {
+            connection.deliver(test_message(
+                new_uuid(),
+                new_uuid(),
+                &format!("burst {}", n),
+            ));
+        }
+
+        let frames = [next_text(&mut socket).await, next_text(&mut socket).await];
+        assert!(frames.iter().any(|f| f.contains("burst 0")));
+        assert!(frames.iter().any(|f| f.contains("could not be delivered")));
+        assert!(!frames.iter().any(|f| f.contains("burst 1")));
+
+        // Still connected once caught up
+        connection.deliver(test_message(new_uuid(), new_uuid(), "after resync"));
+        assert!(next_text(&mut socket).await.contains("after resync"));
+    }
+
+    #[tokio::test]
+    async fn test_lagging_websocket_resumes_from_its_last_ack() {
+        let mut mock_db = MockDb::new();
+        let (user_id, chat_id) = (new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        let acked = Uuid::now_v1(&NODE_ID);
+        mock_db
+            .expect_get_chats_for_user()
+            .returning(move |_| Ok(vec![test_chat(user_id, vec![user_id])]));
+        mock_db
+            .expect_get_messages_after()
+            .withf(move |_, after, _| *after == acked)
+            .returning(move |_, _, _| {
+                Ok(vec![test_message(chat_id, new_uuid(), "missed after ack")])
+            });
+
+        let mut state = create_test_state(mock_db, MockProducer::new());
+        state.slow_clients = SlowClientPolicy {
+            buffer: 1,
+            max_dropped: None,
+        };
+        let connections = state.connections_map.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let mut socket =
+            connect_websocket(addr, &format!("/ws/connect/{}", user_id), &cookie).await;
+        wait_until(|| async { connections.read().await.contains_key(&user_id) }).await;
+
+        // Frames are handled in order, the error reply means the ack went through
+        let ack = format!(r#"{{"type":"ack","message_id":"{}"}}"#, acked);
+        socket.send(WsMessage::text(ack)).await.unwrap();
+        socket.send(WsMessage::text("not json")).await.unwrap();
+        assert!(next_text(&mut socket).await.contains("Invalid frame"));
+
+        let connection = connections.read().await[&user_id]
+            .values()
+            .next()
+            .unwrap()
+            .clone();
+        for n in 0..5 {
+            connection.deliver(test_message(chat_id, new_uuid(), &format!("burst {}", n)));
+        }
+
+        let frames = [next_text(&mut socket).await, next_text(&mut socket).await];
+        assert!(frames.iter().any(|f| f.contains("burst 0")));
+        assert!(frames.iter().any(|f| f.contains("missed after ack")));
+        assert!(!frames.iter().any(|f| f.contains("could not be delivered")));
+    }
+
+    #[tokio::test]
+    async fn test_chronically_slow_websocket_is_disconnected() {
+        let (mut socket, connections, user_id) = connect_slow_client(Some(2)).await;
+        let connection = connections.read().await[&user_id]
+            .values()
+            .next()
+            .unwrap()
+            .clone();
+
+        for n in 0..3 {
+            connection.deliver(test_message(
+                new_uuid(),
+                new_uuid(),
+                &format!("burst {}", n),
+            ));
+        }
+
+        let close = loop {
+            let frame = tokio::time::timeout(Duration::from_secs(2), socket.next())
+                .await
+                .expect("no frame received in time");
+            match frame {
+                Some(Ok(WsMessage::Close(close))) => break close,
+                Some(Ok(_)) => continue,
+                other => panic!("connection ended without a close frame: {:?}", other),
+            }
+        };
+        assert_eq!(close.unwrap().reason, "Too slow");
+        wait_until(|| async { !connections.read().await.contains_key(&user_id) }).await;
+    }
+
+    /// Connects a user to a server pinging every 50ms, with 100ms to answer
+    async fn connect_with_fast_heartbeat() -> (TestSocket, ConnectionMap, Uuid) {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let mut state = create_test_state(mock_db, MockProducer::new());
+        state.heartbeat = Heartbeat {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(100),
+        };
+        let connections = state.connections_map.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let socket = connect_websocket(addr, &format!("/ws/connect/{}", user_id), &cookie).await;
+        wait_until(|| async { connections.read().await.contains_key(&user_id) }).await;
+        (socket, connections, user_id)
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_websocket_is_reaped() {
+        let (socket, connections, user_id) = connect_with_fast_heartbeat().await;
+
+        // The client never reads, so its pongs are never sent, like a half-open connection
+        wait_until(|| async { !connections.read().await.contains_key(&user_id) }).await;
+        drop(socket);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_answering_pings_stays_connected() {
+        let (mut socket, connections, user_id) = connect_with_fast_heartbeat().await;
+
+        // Reading answers the pings, for several pong deadlines
+        let mut pings = 0;
+        let keep_reading = tokio::time::sleep(Duration::from_millis(500));
+        tokio::pin!(keep_reading);
+        loop {
+            tokio::select! {
+                frame = socket.next() => {
+                    if let Some(Ok(WsMessage::Ping(_))) = frame {
+                        pings += 1;
+                    }
+                }
+                _ = &mut keep_reading => break,
+            }
+        }
+
+        assert!(pings >= 3);
+        assert!(connections.read().await.contains_key(&user_id));
+    }
+
+    #[tokio::test]
+    async fn test_get_messages_for_member() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id, new_uuid()]);
+        let message = test_message(chat_id, user_id, "hi");
+        let message_id = message.message_id;
+        let cursor = new_uuid();
+        mock_db
+            .expect_get_messages()
+            .withf(move |id, before, limit| *id == chat_id && before.is_none() && *limit == 50)
+            .times(1)
+            .returning(move |_, _, _| {
+                Ok(MessagePage {
+                    messages: vec![message.clone()],
+                    next_cursor: Some(cursor),
+                })
+            });
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", get(get_messages))
+            .with_state(state);
+
+        let req = build_get_request_with_cookie(&format!("/chats/{}/messages", chat_id), &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let page: MessagePage = deserialize_body(response).await;
+        assert_eq!(page.messages.len(), 1);
+        assert_eq!(page.messages[0].message_id, message_id);
+        assert_eq!(page.next_cursor, Some(cursor));
+    }
+
+    #[tokio::test]
+    async fn test_get_messages_for_non_member_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, new_uuid());
+        expect_members(&mut mock_db, chat_id, vec![new_uuid(), new_uuid()]);
+        mock_db.expect_get_messages().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", get(get_messages))
+            .with_state(state);
+
+        let req = build_get_request_with_cookie(&format!("/chats/{}/messages", chat_id), &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_get_messages_of_unknown_chat_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, new_uuid());
+        // What the database answers for a chat that does not exist
+        expect_members(&mut mock_db, chat_id, Vec::new());
+        mock_db.expect_get_messages().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", get(get_messages))
+            .with_state(state);
+
+        let req = build_get_request_with_cookie(&format!("/chats/{}/messages", chat_id), &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        // Not a 404, which would tell the chat ids that exist apart
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_get_chat_for_non_member_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, new_uuid());
+        expect_members(&mut mock_db, chat_id, vec![new_uuid()]);
+        mock_db.expect_get_chat().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}", get(get_chat))
+            .with_state(state);
+
+        let req = build_get_request_with_cookie(&format!("/chats/{}", chat_id), &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_render_chat_for_non_member_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, new_uuid());
+        expect_members(&mut mock_db, chat_id, vec![new_uuid()]);
+        mock_db.expect_get_chat().never();
+        mock_db.expect_get_messages().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/ui/chats/{chat_id}", get(render_chat))
+            .with_state(state);
+
+        let req = build_get_request_with_cookie(&format!("/ui/chats/{}", chat_id), &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_render_chat_marks_it_read_and_shows_who_saw_my_messages() {
+        let mut mock_db = MockDb::new();
+        let alice = test_user("alice");
+        let bob = test_user("bob");
+        let (alice_id, bob_id) = (alice.user_id, bob.user_id);
+        let chat = test_chat(alice_id, vec![alice_id, bob_id]);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_users(&mut mock_db, vec![alice, bob]);
+        expect_members(&mut mock_db, chat_id, vec![alice_id, bob_id]);
+        expect_chat(&mut mock_db, chat);
+        let mine = test_message(chat_id, alice_id, "are you there?");
+        let reply = test_message(chat_id, bob_id, "yes");
+        let (mine_id, reply_id) = (mine.message_id, reply.message_id);
+        mock_db.expect_get_messages().returning(move |_, _, _| {
+            Ok(MessagePage {
+                messages: vec![reply.clone(), mine.clone()],
+                next_cursor: None,
+            })
+        });
+        mock_db
+            .expect_mark_read()
+            .withf(move |c, u, m| *c == chat_id && *u == alice_id && *m == reply_id)
+            .times(1)
+            .returning(|_, _, _| Ok(true));
+        // Bob read up to alice's message, before answering it
+        mock_db
+            .expect_get_read_receipts()
+            .returning(move |_| Ok(HashMap::from([(alice_id, reply_id), (bob_id, mine_id)])));
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_event()
+            .withf(move |event| {
+                matches!(event, ChatEvent::ReadReceipt(receipt)
+                    if receipt.message_id == reply_id && receipt.sender_id == bob_id && receipt.username == "alice")
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/ui/chats/{chat_id}", get(render_chat))
+            .with_state(state);
+        let req = build_get_request_with_cookie(&format!("/ui/chats/{}", chat_id), &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_body(response).await;
+        let seen = body.find(&format!("id=\"seen-{}\"", mine_id)).unwrap();
+        assert!(body[seen..].starts_with(&format!(
+            "id=\"seen-{}\" class=\"text-xs text-gray-400 empty:hidden\" title=\"Seen by\"><span class=\"ml-1\">bob</span>",
+            mine_id
+        )));
+        // Only the viewer's messages have a receipt slot
+        assert!(!body.contains(&format!("seen-{}", reply_id)));
+    }
+
+    #[tokio::test]
+    async fn test_reading_my_own_message_sends_no_receipt() {
+        let mut mock_db = MockDb::new();
+        let alice_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_members(&mut mock_db, chat_id, vec![alice_id]);
+        let mine = test_message(chat_id, alice_id, "note to self");
+        let message_id = mine.message_id;
+        expect_message(&mut mock_db, mine);
+        mock_db
+            .expect_mark_read()
+            .withf(move |_, u, m| *u == alice_id && *m == message_id)
+            .times(1)
+            .returning(|_, _, _| Ok(true));
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_event().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/read", post(post_read))
+            .with_state(state);
+        let response = app
+            .oneshot(build_htmx_request_with_cookie(
+                http::Method::POST,
+                &format!("/chats/{}/read", chat_id),
+                &format!("message_id={}", message_id),
+                &cookie,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_reading_an_older_message_again_sends_no_receipt() {
+        let mut mock_db = MockDb::new();
+        let alice_id = new_uuid();
+        let bob_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_members(&mut mock_db, chat_id, vec![alice_id, bob_id]);
+        let newest = test_message(chat_id, bob_id, "old news");
+        mock_db.expect_get_messages().returning(move |_, _, limit| {
+            assert_eq!(limit, 1);
+            Ok(MessagePage {
+                messages: vec![newest.clone()],
+                next_cursor: None,
+            })
+        });
+        // Another tab already read further
+        mock_db.expect_mark_read().returning(|_, _, _| Ok(false));
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_event().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/read", post(post_read))
+            .with_state(state);
+        let response = app
+            .oneshot(build_htmx_request_with_cookie(
+                http::Method::POST,
+                &format!("/chats/{}/read", chat_id),
+                "",
+                &cookie,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_mark_read_for_non_member_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, new_uuid());
+        expect_members(&mut mock_db, chat_id, vec![new_uuid()]);
+        mock_db.expect_mark_read().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/read", post(post_read))
+            .with_state(state);
+        let response = app
+            .oneshot(build_htmx_request_with_cookie(
+                http::Method::POST,
+                &format!("/chats/{}/read", chat_id),
+                "",
+                &cookie,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_post_message_htmx_for_non_member_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, new_uuid());
+        expect_members(&mut mock_db, chat_id, vec![new_uuid()]);
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", post(post_message))
+            .with_state(state);
+
+        let mut req = build_form_request(
+            &format!("/chats/{}/messages", chat_id),
+            "content=hi".to_string(),
+            Some(&cookie),
+        );
+        req.headers_mut()
+            .insert("hx-request", http::HeaderValue::from_static("true"));
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_members_are_cached_between_requests() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        mock_db
+            .expect_get_members_of_chat()
+            .times(1)
+            .returning(move |_| Ok(vec![user_id]));
+        mock_db.expect_get_messages().times(2).returning(|_, _, _| {
+            Ok(MessagePage {
+                messages: vec![],
+                next_cursor: None,
+            })
+        });
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", get(get_messages))
+            .with_state(state);
+
+        for _ in 0..2 {
+            let req =
+                build_get_request_with_cookie(&format!("/chats/{}/messages", chat_id), &cookie);
+            let response = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+    #[tokio::test]
+    async fn test_get_messages_forwards_cursor_and_clamps_limit() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let user_id = new_uuid();
+        let before = Uuid::now_v1(&NODE_ID);
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        mock_db
+            .expect_get_messages()
+            .withf(move |id, b, limit| *id == chat_id && *b == Some(before) && *limit == 200)
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(MessagePage {
+                    messages: vec![],
+                    next_cursor: None,
+                })
+            });
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", get(get_messages))
+            .with_state(state);
+
+        let uri = format!("/chats/{}/messages?before={}&limit=100000", chat_id, before);
+        let req = build_get_request_with_cookie(&uri, &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let page: MessagePage = deserialize_body(response).await;
+        assert!(page.messages.is_empty());
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_replaces_the_reactions_of_a_message() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let connections = state.connections_map.clone();
+        let app = Router::new()
+            .route("/ws/connect/{user_id}", get(get_websocket))
+            .with_state(state);
+        let addr = spawn_server(app).await;
+        let mut socket =
+            connect_websocket(addr, &format!("/ws/connect/{}", user_id), &cookie).await;
+        wait_until(|| async { connections.read().await.contains_key(&user_id) }).await;
+
+        let (chat_id, message_id) = (new_uuid(), Uuid::now_v1(&NODE_ID));
+        let update = MessageReactions {
+            chat_id,
+            message_id,
+            reactions: vec![
+                Reaction {
+                    emoji: "👍".to_string(),
+                    user_ids: vec![new_uuid(), user_id],
+                },
+                Reaction {
+                    emoji: "🎉".to_string(),
+                    user_ids: vec![new_uuid()],
+                },
+            ],
+        };
+        let sender = connections.read().await[&user_id]
+            .values()
+            .next()
+            .unwrap()
+            .sender
+            .clone();
+        sender.send(Delivery::Reactions(update)).await.unwrap();
+
+        let fragment = next_text(&mut socket).await;
+        assert!(fragment.starts_with(&format!(
+            "<div id=\"reactions-{}\" class=\"flex flex-wrap items-center gap-1 text-xs\" hx-swap-oob=\"true\">",
+            message_id
+        )));
+        let reactions = format!("/chats/{}/messages/{}/reactions/", chat_id, message_id);
+        // Clicking one of ours takes it back, clicking another one joins it
+        assert!(fragment.contains(&format!("hx-delete=\"{}👍\"", reactions)));
+        assert!(fragment.contains("👍 2"));
+        assert!(fragment.contains(&format!("hx-put=\"{}🎉\"", reactions)));
+        assert!(fragment.contains("🎉 1"));
+    }
+
+    #[tokio::test]
+    async fn test_add_reaction_is_published_with_the_message_sender() {
+        let mut mock_db = MockDb::new();
+        let alice_id = new_uuid();
+        let bob_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_members(&mut mock_db, chat_id, vec![alice_id, bob_id]);
+        let message = test_message(chat_id, bob_id, "shipped it");
+        let message_id = message.message_id;
+        expect_message(&mut mock_db, message);
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_event()
+            .withf(move |event| {
+                matches!(event, ChatEvent::ReactionAdded(change)
+                    if change.message_id == message_id
+                        && change.sender_id == bob_id
+                        && change.user_id == alice_id
+                        && change.emoji == "🎉")
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/messages/{message_id}/reactions/{emoji}",
+                put(add_reaction).delete(remove_reaction),
+            )
+            .with_state(state);
+        let response = app
+            .oneshot(build_htmx_request_with_cookie(
+                http::Method::PUT,
+                // As the browser sends it
+                &format!(
+                    "/chats/{}/messages/{}/reactions/%F0%9F%8E%89",
+                    chat_id, message_id
+                ),
+                "",
+                &cookie,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_remove_reaction_is_published() {
+        let mut mock_db = MockDb::new();
+        let alice_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_members(&mut mock_db, chat_id, vec![alice_id]);
+        let message = test_message(chat_id, alice_id, "shipped it");
+        let message_id = message.message_id;
+        expect_message(&mut mock_db, message);
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_event()
+            .withf(move |event| {
+                matches!(event, ChatEvent::ReactionRemoved(change)
+                    if change.message_id == message_id && change.emoji == "👍")
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/messages/{message_id}/reactions/{emoji}",
+                put(add_reaction).delete(remove_reaction),
+            )
+            .with_state(state);
+        let response = app
+            .oneshot(build_htmx_request_with_cookie(
+                http::Method::DELETE,
+                &format!("/chats/{}/messages/{}/reactions/👍", chat_id, message_id),
+                "",
+                &cookie,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_reaction_must_be_an_emoji() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, new_uuid());
+        mock_db.expect_get_message().never();
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_event().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/messages/{message_id}/reactions/{emoji}",
+                put(add_reaction).delete(remove_reaction),
+            )
+            .with_state(state);
+        // A word, an accented letter and a CJK character
+        for emoji in ["lol", "%C3%A9", "%E4%B8%AD"] {
+            let response = app
+                .clone()
+                .oneshot(build_htmx_request_with_cookie(
+                    http::Method::PUT,
+                    &format!(
+                        "/chats/{}/messages/{}/reactions/{}",
+                        chat_id,
+                        new_uuid(),
+                        emoji
+                    ),
+                    "",
+                    &cookie,
+                ))
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reaction_to_deleted_message_is_not_found() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let message = PandaMessage {
+            deleted: true,
+            ..test_message(chat_id, user_id, "")
+        };
+        let message_id = message.message_id;
+        expect_message(&mut mock_db, message);
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_event().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/messages/{message_id}/reactions/{emoji}",
+                put(add_reaction).delete(remove_reaction),
+            )
+            .with_state(state);
+        let response = app
+            .oneshot(build_htmx_request_with_cookie(
+                http::Method::PUT,
+                &format!("/chats/{}/messages/{}/reactions/👍", chat_id, message_id),
+                "",
+                &cookie,
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_messages_htmx_renders_older_page() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        // Newest first, as returned by the clustering order
+        let messages: Vec<PandaMessage> = ["omega", "alpha"]
+            .iter()
+            .map(|content| test_message(chat_id, user_id, content))
+            .collect();
+        let cursor = messages[1].message_id;
+        mock_db
+            .expect_get_messages()
+            .times(1)
+            .returning(move |_, _, _| {
+                Ok(MessagePage {
+                    messages: messages.clone(),
+                    next_cursor: Some(cursor),
+                })
+            });
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", get(get_messages))
+            .with_state(state);
+
+        let mut req = build_get_request_with_cookie(
+            &format!("/chats/{}/messages?before={}", chat_id, new_uuid()),
+            &cookie,
+        );
+        req.headers_mut()
+            .insert("hx-request", http::HeaderValue::from_static("true"));
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_body(response).await;
+        // The next trigger comes first, then the page from oldest to newest
+        assert!(body.contains(&format!("?before={}", cursor)));
+        let older = body.find("alpha").unwrap();
+        let newer = body.find("omega").unwrap();
+        assert!(body.find("load-older").unwrap() < older && older < newer);
+        // History must not be swapped out-of-band to the bottom of the chat
+        assert!(!body.contains("hx-swap-oob"));
+    }
+
+    #[tokio::test]
+    async fn test_history_shows_thumbnails_and_file_links() {
+        let mut mock_db = MockDb::new();
+        let chat_id = new_uuid();
+        let user_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let image = test_attachment("image/png");
+        let file = test_attachment("application/pdf");
+        let message = PandaMessage {
+            attachments: vec![image.clone(), file.clone()],
+            ..test_message(chat_id, user_id, "")
+        };
+        let message_id = message.message_id;
+        mock_db.expect_get_messages().returning(move |_, _, _| {
+            Ok(MessagePage {
+                messages: vec![message.clone()],
+                next_cursor: None,
+            })
+        });
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/messages", get(get_messages))
+            .with_state(state);
+        let mut req =
+            build_get_request_with_cookie(&format!("/chats/{}/messages", chat_id), &cookie);
+        req.headers_mut()
+            .insert("hx-request", http::HeaderValue::from_static("true"));
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_body(response).await;
+        let url = format!("/chats/{}/messages/{}/attachments", chat_id, message_id);
+        assert!(body.contains(&format!(
+            r#"<img src="{}/{}/thumbnail""#,
+            url, image.attachment_id
+        )));
+        assert!(body.contains(&format!(
+            r#"<a href="{}/{}" class="underline text-sm">report.pdf (9 B)</a>"#,
+            url, file.attachment_id
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_get_my_mentions_hides_chats_the_user_left() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let sender_id = new_uuid();
+        let chat_id = new_uuid();
+        let left_chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id, sender_id]);
+        expect_members(&mut mock_db, left_chat_id, vec![sender_id]);
+        let mention = Mention {
+            user_id,
+            username: "alice".to_string(),
+        };
+        let messages = vec![
+            PandaMessage {
+                mentions: vec![mention.clone()],
+                ..test_message(chat_id, sender_id, "@alice look")
+            },
+            PandaMessage {
+                mentions: vec![mention],
+                ..test_message(left_chat_id, sender_id, "@alice still there?")
+            },
+        ];
+        let cursor = messages[1].message_id;
+        mock_db
+            .expect_get_mentions()
+            .withf(move |u, before, limit| *u == user_id && before.is_none() && *limit == 10)
+            .times(1)
+            .returning(move |_, _, _| {
+                Ok(MessagePage {
+                    messages: messages.clone(),
+                    next_cursor: Some(cursor),
+                })
+            });
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/users/me/mentions", get(get_my_mentions))
+            .with_state(state);
+        let req = build_get_request_with_cookie("/users/me/mentions?limit=10", &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let page: MessagePage = serde_json::from_str(&read_body(response).await).unwrap();
+        let contents: Vec<&str> = page.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["@alice look"]);
+        assert_eq!(page.messages[0].mentions[0].user_id, user_id);
+        // Paging goes on past the hidden message
+        assert_eq!(page.next_cursor, Some(cursor));
+    }
+
+    #[tokio::test]
+    async fn test_get_my_mentions_htmx_highlights_the_mention() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        let message = PandaMessage {
+            mentions: vec![Mention {
+                user_id,
+                username: "alice".to_string(),
+            }],
+            ..test_message(chat_id, new_uuid(), "hi @alice & @bob")
+        };
+        mock_db.expect_get_mentions().returning(move |_, _, _| {
+            Ok(MessagePage {
+                messages: vec![message.clone()],
+                next_cursor: None,
+            })
+        });
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/users/me/mentions", get(get_my_mentions))
+            .with_state(state);
+        let mut req = build_get_request_with_cookie("/users/me/mentions", &cookie);
+        req.headers_mut()
+            .insert("hx-request", http::HeaderValue::from_static("true"));
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_body(response).await;
+        assert!(body.contains(&format!("/ui/chats/{}", chat_id)));
+        assert!(body.contains(r#"">@alice</span> &amp; @bob"#));
+    }
+
+    #[tokio::test]
+    async fn test_search_only_covers_the_chats_of_the_caller() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let (chat_id, left_chat) = (new_uuid(), new_uuid());
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        // A stale dashboard row still lists the chat the user was removed from
+        mock_db
+            .expect_get_chat_ids_for_user()
+            .withf(move |u| *u == user_id)
+            .times(1)
+            .returning(move |_| Ok(vec![chat_id, left_chat]));
+        expect_members(&mut mock_db, chat_id, vec![user_id]);
+        expect_members(&mut mock_db, left_chat, vec![new_uuid()]);
+        let mine = test_message(chat_id, new_uuid(), "release notes are ready");
+        let not_mine = test_message(new_uuid(), new_uuid(), "secret release date");
+        let left = test_message(left_chat, new_uuid(), "release party after I left");
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        index_for_search(&state, &[mine.clone(), not_mine, left]);
+        let app = Router::new()
+            .route("/search", get(get_search))
+            .with_state(state);
+        let req = build_get_request_with_cookie("/search?q=release", &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let hits: Vec<SearchHit> = serde_json::from_str(&read_body(response).await).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].message_id, mine.message_id);
+        assert_eq!(hits[0].snippet, "<mark>release</mark> notes are ready");
+    }
+
+    #[tokio::test]
+    async fn test_search_in_a_chat_requires_membership() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![new_uuid()]);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        index_for_search(&state, &[test_message(chat_id, new_uuid(), "hello")]);
+        let app = Router::new()
+            .route("/search", get(get_search))
+            .with_state(state);
+        let req =
+            build_get_request_with_cookie(&format!("/search?q=hello&chat_id={}", chat_id), &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_search_rejects_an_empty_query() {
+        let mut mock_db = MockDb::new();
+        let cookie = expect_valid_session(&mut mock_db, new_uuid());
+        mock_db.expect_get_chat_ids_for_user().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/search", get(get_search))
+            .with_state(state);
+        let req = build_get_request_with_cookie("/search?q=%20%20", &cookie);
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_search_form_filters_by_sender_and_highlights() {
+        let mut mock_db = MockDb::new();
+        let user_id = new_uuid();
+        let bob_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        expect_members(&mut mock_db, chat_id, vec![user_id, bob_id]);
+        let now = Utc::now();
+        mock_db
+            .expect_get_user_by_username()
+            .withf(|username| username == "bob")
+            .times(1)
+            .returning(move |_| {
+                Ok(User {
+                    user_id: bob_id,
+                    username: "bob".to_string(),
+                    created_at: now,
+                    updated_at: now,
+                })
+            });
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        index_for_search(
+            &state,
+            &[
+                test_message(chat_id, bob_id, "the <script> is broken"),
+                test_message(chat_id, user_id, "my script works"),
+            ],
+        );
+        let app = Router::new()
+            .route("/search", get(get_search))
+            .with_state(state);
+        // Empty fields of the form are no filter
+        let mut req = build_get_request_with_cookie(
+            &format!("/search?q=script&chat_id={}&sender=bob&from=&to=", chat_id),
+            &cookie,
+        );
+        req.headers_mut()
+            .insert("hx-request", http::HeaderValue::from_static("true"));
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_body(response).await;
+        assert!(body.contains("the &lt;<mark>script</mark>&gt; is broken"));
+        assert!(!body.contains("works"));
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_lists_chats_by_recent_activity() {
+        let mut mock_db = MockDb::new();
+        let user = test_user("test_user");
+        let user_id = user.user_id;
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        // get_chats_for_user already returns the most recently active chat first
+        let chats: Vec<Chat> = ["recent_chat", "stale_chat"]
+            .iter()
+            .map(|name| Chat {
+                chat_id: new_uuid(),
+                members: vec![user_id],
+                name: name.to_string(),
+                created_at: Utc::now(),
+                roles: HashMap::new(),
+                unread: 0,
+            })
+            .collect();
+        mock_db
+            .expect_get_chats_for_user()
+            .withf(move |id| *id == user_id)
+            .times(1)
+            .returning(move |_| Ok(chats.clone()));
+        mock_db
+            .expect_get_all_users()
+            .times(1)
+            .returning(move || Ok(vec![user.clone()]));
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/dashboard", get(dashboard))
+            .with_state(state);
+
+        let response = app
+            .oneshot(build_get_request_with_cookie("/dashboard", &cookie))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_body(response).await;
+        assert!(body.find("recent_chat").unwrap() < body.find("stale_chat").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_shows_unread_counts() {
+        let mut mock_db = MockDb::new();
+        let user = test_user("test_user");
+        let user_id = user.user_id;
+        let cookie = expect_valid_session(&mut mock_db, user_id);
+        let chats: Vec<Chat> = [("busy_chat", 3), ("flooded_chat", 100), ("quiet_chat", 0)]
+            .into_iter()
+            .map(|(name, unread)| Chat {
+                name: name.to_string(),
+                unread,
+                ..test_chat(user_id, vec![user_id])
+            })
+            .collect();
+        mock_db
+            .expect_get_chats_for_user()
+            .returning(move |_| Ok(chats.clone()));
+        expect_users(&mut mock_db, vec![user]);
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/dashboard", get(dashboard))
+            .with_state(state);
+        let response = app
+            .oneshot(build_get_request_with_cookie("/dashboard", &cookie))
+            .await
+            .unwrap();
 
-    use anyhow::Result;
-    use async_trait::async_trait;
-    use axum::{
-        Router,
-        body::{Body, to_bytes},
-        http::{self, Request, StatusCode},
-        response::Response,
-        routing::{get, post},
-    };
-    use chrono::Utc;
-    use mockall::mock;
-    use serde::{Serialize, de::DeserializeOwned};
-    use serde_json::json;
-    use tera::Tera;
-    use tokio::sync::RwLock;
-    use tower::ServiceExt;
-    use uuid::Uuid;
+        let body = read_body(response).await;
+        assert!(body.contains("3 unread"));
+        assert!(body.contains("99+ unread"));
+        assert_eq!(body.matches(" unread").count(), 2);
+    }
 
-    use super::*;
-    use crate::{AppState, ConnectionMap, db::Db, producer::MockProducer};
+    #[tokio::test]
+    async fn test_add_member_announces_it_in_the_chat() {
+        let mut mock_db = MockDb::new();
+        let alice = test_user("alice");
+        let bob = test_user("bob");
+        let (alice_id, bob_id) = (alice.user_id, bob.user_id);
+        let chat = test_chat(alice_id, vec![alice_id]);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_users(&mut mock_db, vec![alice, bob]);
+        let updated = Chat {
+            members: vec![alice_id, bob_id],
+            ..chat.clone()
+        };
+        expect_chat(&mut mock_db, chat);
+        mock_db
+            .expect_add_chat_member()
+            .withf(move |c, u| *c == chat_id && *u == bob_id)
+            .times(1)
+            .returning(move |_, _| Ok(updated.clone()));
 
-    // --- MOCK DEFINITIONS ---
-    mock! {
-        pub Db {}
-        #[async_trait]
-        impl Db for Db {
-            async fn create_user(&self, username: &str) -> Result<User>;
-            async fn create_chat(&self, name: &str, members: &[Uuid]) -> Result<Chat>;
-            async fn get_user(&self, user_id: Uuid) -> Result<User>;
-            async fn get_chat(&self, chat_id: Uuid) -> Result<Chat>;
-            async fn insert_message(&self, message: PandaMessage) -> Result<crate::schema::PandaMessage>;
-            async fn get_messages(&self, chat_id: Uuid) -> Result<Vec<PandaMessage>>;
-            async fn get_chats_for_user(&self, user_id: Uuid) -> Result<Vec<Chat>>;
-            async fn get_user_by_username(&self, username: &str) -> Result<User>;
-            async fn get_all_users(&self) -> Result<Vec<User>>;
-            async fn get_members_of_chat(&self, chat_id: Uuid) -> Result<Vec<Uuid>>;
-            async fn insert_batch_message(&self, messages: &[PandaMessage]) -> Result<()>;
-        }
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_message()
+            .withf(move |m| {
+                m.chat_id == chat_id
+                    && m.sender_id == SYSTEM_SENDER_ID
+                    && m.content == "alice added bob"
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/members", post(add_member))
+            .with_state(state);
+
+        let req = build_form_request(
+            &format!("/chats/{}/members", chat_id),
+            format!("user_id={}", bob_id),
+            Some(&cookie),
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Chat = deserialize_body(response).await;
+        assert!(body.members.contains(&bob_id));
     }
 
-    // --- TESTS ---
+    #[tokio::test]
+    async fn test_adding_a_member_again_is_not_announced() {
+        let mut mock_db = MockDb::new();
+        let alice = test_user("alice");
+        let bob = test_user("bob");
+        let (alice_id, bob_id) = (alice.user_id, bob.user_id);
+        let chat = test_chat(alice_id, vec![alice_id, bob_id]);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_users(&mut mock_db, vec![alice, bob]);
+        expect_chat(&mut mock_db, chat);
+        mock_db.expect_add_chat_member().never();
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/members", post(add_member))
+            .with_state(state);
+
+        let req = build_form_request(
+            &format!("/chats/{}/members", chat_id),
+            format!("user_id={}", bob_id),
+            Some(&cookie),
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Chat = deserialize_body(response).await;
+        assert_eq!(body.members, vec![alice_id, bob_id]);
+    }
 
     #[tokio::test]
-    async fn test_create_user() {
+    async fn test_add_member_when_the_chat_can_not_be_read_is_a_server_error() {
         let mut mock_db = MockDb::new();
-        let user_id = new_uuid();
-        let now = Utc::now();
-        let expected_user = User {
-            user_id,
-            username: "test_user".to_string(),
-            created_at: now,
-            updated_at: now,
+        let owner_id = new_uuid();
+        let chat_id = new_uuid();
+        let cookie = expect_valid_session(&mut mock_db, owner_id);
+        // The chat may well exist, the database just did not answer
+        mock_db
+            .expect_get_chat()
+            .returning(|_| Err(anyhow!("Failed to execute query").context("Could not fetch chat")));
+        mock_db.expect_add_chat_member().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/members", post(add_member))
+            .with_state(state);
+
+        let req = build_form_request(
+            &format!("/chats/{}/members", chat_id),
+            format!("user_id={}", new_uuid()),
+            Some(&cookie),
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_add_member_by_non_member_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let owner_id = new_uuid();
+        let chat = test_chat(owner_id, vec![owner_id]);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, new_uuid());
+        expect_chat(&mut mock_db, chat);
+        mock_db.expect_add_chat_member().never();
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/members", post(add_member))
+            .with_state(state);
+
+        let req = build_form_request(
+            &format!("/chats/{}/members", chat_id),
+            format!("user_id={}", new_uuid()),
+            Some(&cookie),
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_add_member_by_plain_member_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let (owner_id, member_id) = (new_uuid(), new_uuid());
+        let chat = test_chat(owner_id, vec![owner_id, member_id]);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, member_id);
+        expect_chat(&mut mock_db, chat);
+        mock_db.expect_add_chat_member().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/members", post(add_member))
+            .with_state(state);
+
+        let req = build_form_request(
+            &format!("/chats/{}/members", chat_id),
+            format!("user_id={}", new_uuid()),
+            Some(&cookie),
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_leave_chat_redirects_htmx_to_dashboard() {
+        let mut mock_db = MockDb::new();
+        let alice = test_user("alice");
+        let bob = test_user("bob");
+        let (alice_id, bob_id) = (alice.user_id, bob.user_id);
+        let chat = test_chat(bob_id, vec![alice_id, bob_id]);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_users(&mut mock_db, vec![alice, bob]);
+        let updated = Chat {
+            members: vec![bob_id],
+            ..chat.clone()
         };
+        expect_chat(&mut mock_db, chat);
+        mock_db
+            .expect_remove_chat_member()
+            .withf(move |c, u| *c == chat_id && *u == alice_id)
+            .times(1)
+            .returning(move |_, _| Ok(updated.clone()));
 
-        // Setup Expectations
-        let user_clone = expected_user.clone();
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_message()
+            .withf(|m| m.sender_id == SYSTEM_SENDER_ID && m.content == "alice left the chat")
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/members/{user_id}", delete(remove_member))
+            .with_state(state);
+
+        let req = build_htmx_request_with_cookie(
+            http::Method::DELETE,
+            &format!("/chats/{}/members/{}", chat_id, alice_id),
+            "",
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("HX-Redirect").unwrap(), "/dashboard");
+    }
+
+    #[tokio::test]
+    async fn test_owner_can_not_leave_without_transferring() {
+        let mut mock_db = MockDb::new();
+        let owner_id = new_uuid();
+        let chat = test_chat(owner_id, vec![owner_id, new_uuid()]);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, owner_id);
+        expect_chat(&mut mock_db, chat);
+        mock_db.expect_remove_chat_member().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route("/chats/{chat_id}/members/{user_id}", delete(remove_member))
+            .with_state(state);
+
+        let req = build_htmx_request_with_cookie(
+            http::Method::DELETE,
+            &format!("/chats/{}/members/{}", chat_id, owner_id),
+            "",
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_admin_removes_member() {
+        let mut mock_db = MockDb::new();
+        let alice = test_user("alice");
+        let bob = test_user("bob");
+        let (alice_id, bob_id) = (alice.user_id, bob.user_id);
+        let owner_id = new_uuid();
+        let mut chat = test_chat(owner_id, vec![owner_id, alice_id, bob_id]);
+        chat.roles.insert(alice_id, Role::Admin);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_users(&mut mock_db, vec![alice, bob]);
+        let updated = Chat {
+            members: vec![owner_id, alice_id],
+            ..chat.clone()
+        };
+        expect_chat(&mut mock_db, chat);
         mock_db
-            .expect_create_user()
-            .withf(|username| username == "test_user")
+            .expect_remove_chat_member()
+            .withf(move |c, u| *c == chat_id && *u == bob_id)
             .times(1)
-            .returning(move |_| Ok(user_clone.clone()));
+            .returning(move |_, _| Ok(updated.clone()));
+
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_message()
+            .withf(|m| m.content == "alice removed bob")
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/members/{user_id}", delete(remove_member))
+            .with_state(state);
+
+        let req = build_htmx_request_with_cookie(
+            http::Method::DELETE,
+            &format!("/chats/{}/members/{}", chat_id, bob_id),
+            "",
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_body(response).await;
+        assert!(body.contains("id=\"chat-members\""));
+        assert!(!body.contains(&format!("/members/{}\"", bob_id)));
+    }
+
+    #[tokio::test]
+    async fn test_admin_can_not_remove_another_admin() {
+        let mut mock_db = MockDb::new();
+        let (owner_id, admin_id, other_admin_id) = (new_uuid(), new_uuid(), new_uuid());
+        let mut chat = test_chat(owner_id, vec![owner_id, admin_id, other_admin_id]);
+        chat.roles.insert(admin_id, Role::Admin);
+        chat.roles.insert(other_admin_id, Role::Admin);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, admin_id);
+        expect_chat(&mut mock_db, chat);
+        mock_db.expect_remove_chat_member().never();
 
-        // Setup App
         let state = create_test_state(mock_db, MockProducer::new());
         let app = Router::new()
-            .route("/users", post(create_user))
+            .route("/chats/{chat_id}/members/{user_id}", delete(remove_member))
             .with_state(state);
 
-        // Execute
-        // CHANGE: Use build_form_request instead of build_json_request because the handler
-        // uses Form extractor.
-        let req = build_form_request("/users", "username=test_user".to_string(), None);
+        for target in [other_admin_id, owner_id] {
+            let req = build_htmx_request_with_cookie(
+                http::Method::DELETE,
+                &format!("/chats/{}/members/{}", chat_id, target),
+                "",
+                &cookie,
+            );
+            let response = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_owner_promotes_member_to_admin() {
+        let mut mock_db = MockDb::new();
+        let alice = test_user("alice");
+        let bob = test_user("bob");
+        let (alice_id, bob_id) = (alice.user_id, bob.user_id);
+        let chat = test_chat(alice_id, vec![alice_id, bob_id]);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_users(&mut mock_db, vec![alice, bob]);
+        let mut updated = chat.clone();
+        updated.roles.insert(bob_id, Role::Admin);
+        expect_chat(&mut mock_db, chat);
+        mock_db
+            .expect_set_chat_roles()
+            .withf(move |c, changes| *c == chat_id && changes == [(bob_id, Role::Admin)])
+            .times(1)
+            .returning(move |_, _| Ok(updated.clone()));
+
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_message()
+            .withf(|m| m.content == "alice made bob admin")
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/members/{user_id}/role",
+                put(set_member_role),
+            )
+            .with_state(state);
+
+        let req = build_htmx_request_with_cookie(
+            http::Method::PUT,
+            &format!("/chats/{}/members/{}/role", chat_id, bob_id),
+            "role=admin",
+            &cookie,
+        );
         let response = app.oneshot(req).await.unwrap();
 
-        // Assert
-        assert_eq!(response.status(), StatusCode::CREATED);
-        let user: User = deserialize_body(response).await;
-        assert_eq!(user, expected_user);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(read_body(response).await.contains("(admin)"));
     }
 
     #[tokio::test]
-    async fn test_create_chat() {
+    async fn test_set_role_by_admin_is_forbidden() {
         let mut mock_db = MockDb::new();
-        let chat_id = new_uuid();
-        let now = Utc::now();
+        let (owner_id, admin_id, member_id) = (new_uuid(), new_uuid(), new_uuid());
+        let mut chat = test_chat(owner_id, vec![owner_id, admin_id, member_id]);
+        chat.roles.insert(admin_id, Role::Admin);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, admin_id);
+        expect_chat(&mut mock_db, chat);
+        mock_db.expect_set_chat_roles().never();
 
-        let member1 = new_uuid();
-        let member2 = new_uuid();
-        let current_user_id = new_uuid(); // Use a fixed ID for the cookie
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/members/{user_id}/role",
+                put(set_member_role),
+            )
+            .with_state(state);
 
-        // The handler adds the current user (from cookie) to the members list.
-        // So we expect the DB to receive [member1, member2, current_user_id]
-        let mut expected_members = vec![member1, member2];
-        expected_members.push(current_user_id);
+        let req = build_htmx_request_with_cookie(
+            http::Method::PUT,
+            &format!("/chats/{}/members/{}/role", chat_id, member_id),
+            "role=admin",
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
 
-        let expected_chat = Chat {
-            chat_id,
-            name: "test_chat".to_string(),
-            members: expected_members.clone(),
-            created_at: now,
-        };
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
 
-        // Setup Expectations
-        let chat_clone = expected_chat.clone();
-        let members_check = expected_members.clone();
+    #[tokio::test]
+    async fn test_set_role_to_owner_is_rejected() {
+        let mut mock_db = MockDb::new();
+        let (owner_id, member_id) = (new_uuid(), new_uuid());
+        let chat = test_chat(owner_id, vec![owner_id, member_id]);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, owner_id);
+        expect_chat(&mut mock_db, chat);
+        mock_db.expect_set_chat_roles().never();
+
+        let state = create_test_state(mock_db, MockProducer::new());
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/members/{user_id}/role",
+                put(set_member_role),
+            )
+            .with_state(state);
+
+        let req = build_htmx_request_with_cookie(
+            http::Method::PUT,
+            &format!("/chats/{}/members/{}/role", chat_id, member_id),
+            "role=owner",
+            &cookie,
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_owner_transfers_ownership() {
+        let mut mock_db = MockDb::new();
+        let alice = test_user("alice");
+        let bob = test_user("bob");
+        let (alice_id, bob_id) = (alice.user_id, bob.user_id);
+        let chat = test_chat(alice_id, vec![alice_id, bob_id]);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_users(&mut mock_db, vec![alice, bob]);
+        let mut updated = chat.clone();
+        updated.roles = HashMap::from([(bob_id, Role::Owner), (alice_id, Role::Admin)]);
+        expect_chat(&mut mock_db, chat);
+        mock_db
+            .expect_set_chat_roles()
+            .withf(move |c, changes| {
+                *c == chat_id && changes == [(bob_id, Role::Owner), (alice_id, Role::Admin)]
+            })
+            .times(1)
+            .returning(move |_, _| Ok(updated.clone()));
+
+        let mut mock_producer = MockProducer::new();
+        mock_producer
+            .expect_send_message()
+            .withf(|m| m.content == "alice transferred ownership to bob")
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route("/chats/{chat_id}/owner", post(transfer_ownership))
+            .with_state(state);
+
+        let req = build_form_request(
+            &format!("/chats/{}/owner", chat_id),
+            format!("user_id={}", bob_id),
+            Some(&cookie),
+        );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Chat = deserialize_body(response).await;
+        assert_eq!(body.role_of(bob_id), Some(Role::Owner));
+        assert_eq!(body.role_of(alice_id), Some(Role::Admin));
+    }
 
-        mock_db
-            .expect_create_chat()
-            .withf(move |name, m| name == "test_chat" && m == members_check)
-            .times(1)
-            .returning(move |_, _| Ok(chat_clone.clone()));
+    #[tokio::test]
+    async fn test_transfer_ownership_by_admin_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let (owner_id, admin_id) = (new_uuid(), new_uuid());
+        let mut chat = test_chat(owner_id, vec![owner_id, admin_id]);
+        chat.roles.insert(admin_id, Role::Admin);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, admin_id);
+        expect_chat(&mut mock_db, chat);
+        mock_db.expect_set_chat_roles().never();
 
-        // Setup App
         let state = create_test_state(mock_db, MockProducer::new());
         let app = Router::new()
-            .route("/chats", post(create_chat))
+            .route("/chats/{chat_id}/owner", post(transfer_ownership))
             .with_state(state);
 
-        // Execute
-        let cookie = format!("user_id={}", current_user_id);
-        let body_str = format!("name=test_chat&members={}&members={}", member1, member2);
-        let req = build_form_request("/chats", body_str, Some(&cookie));
-
+        let req = build_form_request(
+            &format!("/chats/{}/owner", chat_id),
+            format!("user_id={}", admin_id),
+            Some(&cookie),
+        );
         let response = app.oneshot(req).await.unwrap();
 
-        // Assert
-        assert_eq!(response.status(), StatusCode::CREATED);
-        let chat: Chat = deserialize_body(response).await;
-        assert_eq!(chat, expected_chat);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
     }
 
     #[tokio::test]
-    async fn test_get_user() {
+    async fn test_members_of_chat_without_owner_manage_it() {
         let mut mock_db = MockDb::new();
-        let user_id = new_uuid();
-        let now = Utc::now();
-        let expected_user = User {
-            user_id,
-            username: "test_user".to_string(),
-            created_at: now,
-            updated_at: now,
+        let alice = test_user("alice");
+        let bob = test_user("bob");
+        let (alice_id, bob_id) = (alice.user_id, bob.user_id);
+        // Created before roles existed
+        let mut chat = test_chat(alice_id, vec![alice_id]);
+        chat.roles.clear();
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        expect_users(&mut mock_db, vec![alice, bob]);
+        let updated = Chat {
+            members: vec![alice_id, bob_id],
+            ..chat.clone()
         };
-
-        // Setup Expectations
-        let user_clone = expected_user.clone();
+        expect_chat(&mut mock_db, chat);
         mock_db
-            .expect_get_user()
-            .withf(move |id| *id == user_id)
+            .expect_add_chat_member()
             .times(1)
-            .returning(move |_| Ok(user_clone.clone()));
+            .returning(move |_, _| Ok(updated.clone()));
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().returning(|_| Ok(()));
 
-        // Setup App
-        let state = create_test_state(mock_db, MockProducer::new());
+        let state = create_test_state(mock_db, mock_producer);
         let app = Router::new()
-            .route("/users/{user_id}", get(get_user))
+            .route("/chats/{chat_id}/members", post(add_member))
             .with_state(state);
 
-        // Execute
-        let req = build_get_request(&format!("/users/{}", user_id));
+        let req = build_form_request(
+            &format!("/chats/{}/members", chat_id),
+            format!("user_id={}", bob_id),
+            Some(&cookie),
+        );
         let response = app.oneshot(req).await.unwrap();
 
-        // Assert
         assert_eq!(response.status(), StatusCode::OK);
-        let user: User = deserialize_body(response).await;
-        assert_eq!(user, expected_user);
     }
 
     #[tokio::test]
-    async fn test_get_chat() {
+    async fn test_add_member_invalidates_members_cache() {
         let mut mock_db = MockDb::new();
-        let chat_id = new_uuid();
-        let now = Utc::now();
-        let members = vec![new_uuid(), new_uuid()];
-        let expected_chat = Chat {
-            chat_id,
-            name: "test_chat".to_string(),
-            members: members.clone(),
-            created_at: now,
+        let alice = test_user("alice");
+        let bob = test_user("bob");
+        let (alice_id, bob_id) = (alice.user_id, bob.user_id);
+        let chat = test_chat(alice_id, vec![alice_id]);
+        let chat_id = chat.chat_id;
+        let cookie = expect_valid_session(&mut mock_db, alice_id);
+        let bob_cookie = expect_valid_session(&mut mock_db, bob_id);
+        expect_users(&mut mock_db, vec![alice, bob]);
+        let updated = Chat {
+            members: vec![alice_id, bob_id],
+            ..chat.clone()
         };
+        expect_chat(&mut mock_db, chat);
+        mock_db
+            .expect_add_chat_member()
+            .returning(move |_, _| Ok(updated.clone()));
 
-        // Setup Expectations
-        let chat_clone = expected_chat.clone();
+        // Bob only shows up in the members once the add went through
+        let calls = AtomicUsize::new(0);
         mock_db
-            .expect_get_chat()
-            .withf(move |id| *id == chat_id)
-            .times(1)
-            .returning(move |_| Ok(chat_clone.clone()));
+            .expect_get_members_of_chat()
+            .times(2)
+            .returning(move |_| match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(vec![alice_id]),
+                _ => Ok(vec![alice_id, bob_id]),
+            });
+        mock_db.expect_get_messages().returning(|_, _, _| {
+            Ok(MessagePage {
+                messages: vec![],
+                next_cursor: None,
+            })
+        });
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().returning(|_| Ok(()));
 
-        // Setup App
-        let state = create_test_state(mock_db, MockProducer::new());
+        let state = create_test_state(mock_db, mock_producer);
         let app = Router::new()
-            .route("/chats/{chat_id}", get(get_chat))
+            .route("/chats/{chat_id}/members", post(add_member))
+            .route("/chats/{chat_id}/messages", get(get_messages))
             .with_state(state);
 
-        // Execute
-        let req = build_get_request(&format!("/chats/{}", chat_id));
-        let response = app.oneshot(req).await.unwrap();
+        let history = format!("/chats/{}/messages", chat_id);
+        let req = build_get_request_with_cookie(&history, &bob_cookie);
+        let response = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
 
-        // Assert
+        let req = build_form_request(
+            &format!("/chats/{}/members", chat_id),
+            format!("user_id={}", bob_id),
+            Some(&cookie),
+        );
+        let response = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let req = build_get_request_with_cookie(&history, &bob_cookie);
+        let response = app.oneshot(req).await.unwrap();
         assert_eq!(response.status(), StatusCode::OK);
-        let chat: Chat = deserialize_body(response).await;
-        assert_eq!(chat, expected_chat);
     }
 
     #[tokio::test]
-    async fn test_post_message() {
-        let mut mock_producer = MockProducer::new();
-        let chat_id = new_uuid();
+    async fn test_sender_edits_message() {
+        let mut mock_db = MockDb::new();
         let sender_id = new_uuid();
-        let message_content = "Hello, world!".to_string();
-        let create_message = CreatMessage {
-            content: message_content.clone(),
-        };
+        let message = test_message(new_uuid(), sender_id, "helo");
+        let (chat_id, message_id) = (message.chat_id, message.message_id);
+        let cookie = expect_valid_session(&mut mock_db, sender_id);
+        expect_members(&mut mock_db, chat_id, vec![sender_id]);
+        expect_message(&mut mock_db, message);
 
-        // Setup Expectations
-        let content_check = create_message.content.clone();
+        let mut mock_producer = MockProducer::new();
         mock_producer
-            .expect_send_message()
-            .withf(move |msg| msg.sender_id == sender_id && msg.content == content_check)
+            .expect_send_event()
+            .withf(move |event| {
+                matches!(event, ChatEvent::MessageEdited { message_id: id, sender_id: sender, content, .. }
+                    if *id == message_id && *sender == sender_id && content == "hello")
+            })
             .times(1)
             .returning(|_| Ok(()));
 
-        // Setup App
-        // We use an empty MockDb because this route only uses the producer
-        let state = create_test_state(MockDb::new(), mock_producer);
+        let state = create_test_state(mock_db, mock_producer);
         let app = Router::new()
-            .route("/chats/{chat_id}/messages", post(post_message))
+            .route(
+                "/chats/{chat_id}/messages/{message_id}",
+                patch(edit_message),
+            )
             .with_state(state);
 
-        // Execute
-        let cookie = format!("user_id={}", sender_id);
-        // post_message expects a Form, not JSON, so we use the form helper
-        // Note: crate::CreatMessage uses "content" field
-        let body_str = format!("content={}", message_content);
-        let req = build_form_request(
-            &format!("/chats/{}/messages", chat_id),
-            body_str,
-            Some(&cookie),
+        let req = build_htmx_request_with_cookie(
+            http::Method::PATCH,
+            &format!("/chats/{}/messages/{}", chat_id, message_id),
+            "content=hello",
+            &cookie,
         );
-
         let response = app.oneshot(req).await.unwrap();
 
-        // Assert
         assert_eq!(response.status(), StatusCode::OK);
     }
+
     #[tokio::test]
-    async fn test_dashboard_without_cookie_redirects() {
-        let mock_db = MockDb::new();
-        // Expect no DB calls since the handler should redirect immediately if cookie is missing
+    async fn test_edit_message_of_someone_else_is_forbidden() {
+        let mut mock_db = MockDb::new();
+        let (owner_id, member_id) = (new_uuid(), new_uuid());
+        let chat = test_chat(owner_id, vec![owner_id, member_id]);
+        let message = test_message(chat.chat_id, owner_id, "mine");
+        let (chat_id, message_id) = (message.chat_id, message.message_id);
+        let cookie = expect_valid_session(&mut mock_db, member_id);
+        expect_members(&mut mock_db, chat_id, vec![owner_id, member_id]);
+        expect_chat(&mut mock_db, chat);
+        expect_message(&mut mock_db, message);
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().never();
 
-        let state = create_test_state(mock_db, MockProducer::new());
+        let state = create_test_state(mock_db, mock_producer);
         let app = Router::new()
-            .route("/dashboard", get(dashboard))
+            .route(
+                "/chats/{chat_id}/messages/{message_id}",
+                patch(edit_message),
+            )
             .with_state(state);
 
-        let req = build_get_request("/dashboard");
+        let req = build_htmx_request_with_cookie(
+            http::Method::PATCH,
+            &format!("/chats/{}/messages/{}", chat_id, message_id),
+            "content=yours",
+            &cookie,
+        );
         let response = app.oneshot(req).await.unwrap();
 
-        assert!(
-            response.status().is_redirection(),
-            "Expected redirection (3xx) when accessing dashboard without cookie, but got status: {}",
-            response.status()
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_admin_deletes_message_of_member() {
+        let mut mock_db = MockDb::new();
+        let (owner_id, member_id) = (new_uuid(), new_uuid());
+        let chat = test_chat(owner_id, vec![owner_id, member_id]);
+        let message = test_message(chat.chat_id, member_id, "spam");
+        let (chat_id, message_id) = (message.chat_id, message.message_id);
+        let cookie = expect_valid_session(&mut mock_db, owner_id);
+        expect_members(&mut mock_db, chat_id, vec![owner_id, member_id]);
+        expect_chat(&mut mock_db, chat);
+        expect_message(&mut mock_db, message);
+
+        let mut mock_producer = MockProducer::new();
+        // Keyed by the sender of the message, not by the admin deleting it
+        mock_producer
+            .expect_send_event()
+            .withf(move |event| {
+                matches!(event, ChatEvent::MessageDeleted { message_id: id, sender_id, .. }
+                    if *id == message_id && *sender_id == member_id)
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/messages/{message_id}",
+                delete(delete_message),
+            )
+            .with_state(state);
+
+        let req = build_htmx_request_with_cookie(
+            http::Method::DELETE,
+            &format!("/chats/{}/messages/{}", chat_id, message_id),
+            "",
+            &cookie,
         );
+        let response = app.oneshot(req).await.unwrap();
 
-        let location = response
-            .headers()
-            .get(http::header::LOCATION)
-            .expect("Missing Location header")
-            .to_str()
-            .expect("Invalid Location header value");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 
-        // Accepts redirect to root (login) or logout endpoint
-        assert!(
-            location == "/" || location == "/logout",
-            "Expected redirect to '/' or '/logout', got: {}",
-            location
+    #[tokio::test]
+    async fn test_edit_deleted_message_is_not_found() {
+        let mut mock_db = MockDb::new();
+        let sender_id = new_uuid();
+        let mut message = test_message(new_uuid(), sender_id, "");
+        message.deleted = true;
+        let (chat_id, message_id) = (message.chat_id, message.message_id);
+        let cookie = expect_valid_session(&mut mock_db, sender_id);
+        expect_members(&mut mock_db, chat_id, vec![sender_id]);
+        expect_message(&mut mock_db, message);
+        let mut mock_producer = MockProducer::new();
+        mock_producer.expect_send_message().never();
+
+        let state = create_test_state(mock_db, mock_producer);
+        let app = Router::new()
+            .route(
+                "/chats/{chat_id}/messages/{message_id}",
+                patch(edit_message),
+            )
+            .with_state(state);
+
+        let req = build_htmx_request_with_cookie(
+            http::Method::PATCH,
+            &format!("/chats/{}/messages/{}", chat_id, message_id),
+            "content=back",
+            &cookie,
         );
+        let response = app.oneshot(req).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_live_edit_replaces_message_out_of_band() {
+        let state = create_test_state(MockDb::new(), MockProducer::new());
+        let mut message = test_message(new_uuid(), new_uuid(), "fixed");
+        message.edited_at = Some(Utc::now());
+
+        // Rendered like handle_socket does, without history
+        let mut context = tera::Context::new();
+        context.insert("message", &message);
+        context.insert("user_id", &message.sender_id);
+        let rendered = state
+            .tera
+            .render("partials/message.html", &context)
+            .unwrap();
+
+        assert!(rendered.contains(&format!("id=\"msg-{}\"", message.message_id)));
+        assert!(rendered.contains("hx-swap-oob=\"outerHTML\""));
+        assert!(rendered.contains("(edited)"));
     }
     // --- HELPER FUNCTIONS ---
 
@@ -629,16 +5428,37 @@ mod tests {
         Uuid::new_v4()
     }
 
+    fn templates() -> Tera {
+        let mut tera = Tera::new("templates/**/*.html").unwrap();
+        mention::register_filter(&mut tera);
+        tera
+    }
+
     /// Creates the AppState, wrapping the mocks in Arcs
     fn create_test_state(db: MockDb, producer: MockProducer) -> AppState {
         AppState {
             db: Arc::new(db),
             producer: Arc::new(producer),
-            tera: Arc::new(Tera::default()),
+            tera: Arc::new(templates()),
             connections_map: ConnectionMap::new(RwLock::new(HashMap::new())),
+            members_cache: Arc::new(MembersCache::new(Duration::from_secs(60))),
+            session_ttl: TEST_SESSION_TTL,
+            slow_clients: SlowClientPolicy::default(),
+            heartbeat: Heartbeat::default(),
+            typing_throttle: Arc::default(),
+            presence: Arc::new(PresenceTracker::new(Uuid::new_v4()).0),
+            storage: Arc::new(MockStorage::new()),
+            max_attachment_bytes: attachment::DEFAULT_MAX_BYTES,
+            search: Arc::new(SearchIndex::in_memory().unwrap()),
         }
     }
 
+    /// Index the messages, as the consumer does once they are stored
+    fn index_for_search(state: &AppState, messages: &[PandaMessage]) {
+        state.search.index_messages(messages).unwrap();
+        state.search.commit().unwrap();
+    }
+
     /// Helper to build a generic JSON POST request
     #[allow(dead_code)]
     fn build_json_request(uri: &str, body: impl Serialize, cookie: Option<&str>) -> Request<Body> {
@@ -683,6 +5503,306 @@ mod tests {
             .unwrap()
     }
 
+    fn test_session(user_id: Uuid, expires_at: chrono::DateTime<Utc>) -> UserSession {
+        UserSession {
+            session_id: new_uuid(),
+            user_id,
+            created_at: Utc::now(),
+            expires_at,
+        }
+    }
+
+    /// Expects the session lookup done by the CurrentUser extractor and returns the cookie
+    fn expect_valid_session(mock_db: &mut MockDb, user_id: Uuid) -> String {
+        let session = test_session(user_id, Utc::now() + TEST_SESSION_TTL);
+        let session_id = session.session_id;
+        mock_db
+            .expect_get_session()
+            .withf(move |id| *id == session_id)
+            .returning(move |_| Ok(session.clone()));
+        format!("{}={}", SESSION_COOKIE, session_id)
+    }
+
+    fn expect_members(mock_db: &mut MockDb, chat_id: Uuid, members: Vec<Uuid>) {
+        mock_db
+            .expect_get_members_of_chat()
+            .withf(move |id| *id == chat_id)
+            .returning(move |_| Ok(members.clone()));
+    }
+
+    /// Lets `get_user` and `get_all_users` answer from a fixed set of users
+    fn expect_users(mock_db: &mut MockDb, users: Vec<User>) {
+        let by_id = users.clone();
+        mock_db.expect_get_user().returning(move |user_id| {
+            by_id
+                .iter()
+                .find(|user| user.user_id == user_id)
+                .cloned()
+                .ok_or_else(|| anyhow!(RowNotFound).context("Could not fetch user"))
+        });
+        mock_db
+            .expect_get_all_users()
+            .returning(move || Ok(users.clone()));
+    }
+
+    fn test_chat(owner: Uuid, members: Vec<Uuid>) -> Chat {
+        Chat {
+            chat_id: new_uuid(),
+            members,
+            name: "general".to_string(),
+            created_at: Utc::now(),
+            roles: HashMap::from([(owner, Role::Owner)]),
+            unread: 0,
+        }
+    }
+
+    fn test_message(chat_id: Uuid, sender_id: Uuid, content: &str) -> PandaMessage {
+        PandaMessage {
+            chat_id,
+            sender_id,
+            content: content.to_string(),
+            message_id: Uuid::now_v1(&NODE_ID),
+            edited_at: None,
+            deleted: false,
+            reactions: Vec::new(),
+            mentions: Vec::new(),
+            attachments: Vec::new(),
+            parent_id: None,
+            reply_count: 0,
+        }
+    }
+
+    fn expect_message(mock_db: &mut MockDb, message: PandaMessage) {
+        let (chat_id, message_id) = (message.chat_id, message.message_id);
+        mock_db
+            .expect_get_message()
+            .withf(move |c, m| *c == chat_id && *m == message_id)
+            .returning(move |_, _| Ok(message.clone()));
+    }
+
+    /// Expects the chat lookup done by the role checks
+    fn expect_chat(mock_db: &mut MockDb, chat: Chat) {
+        let chat_id = chat.chat_id;
+        mock_db
+            .expect_get_chat()
+            .withf(move |id| *id == chat_id)
+            .returning(move |_| Ok(chat.clone()));
+    }
+
+    fn test_user(username: &str) -> User {
+        let now = Utc::now();
+        User {
+            user_id: new_uuid(),
+            username: username.to_string(),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// Serves the router on an ephemeral port, `oneshot` requests can not be upgraded
+    async fn spawn_server(app: Router) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    /// Sends a raw websocket handshake and returns the status of the answer
+    async fn websocket_handshake_status(
+        addr: std::net::SocketAddr,
+        path: &str,
+        cookie: Option<&str>,
+    ) -> StatusCode {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"
+        );
+        if let Some(c) = cookie {
+            request.push_str(&format!("Cookie: {c}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        // Status line: "HTTP/1.1 101 Switching Protocols"
+        let mut buf = [0u8; 12];
+        stream.read_exact(&mut buf).await.unwrap();
+        let code = std::str::from_utf8(&buf[9..12]).unwrap();
+        StatusCode::from_bytes(code.as_bytes()).unwrap()
+    }
+
+    type TestSocket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    /// Opens a websocket with a session cookie, as the browser does after logging in
+    async fn connect_websocket(addr: std::net::SocketAddr, path: &str, cookie: &str) -> TestSocket {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut request = format!("ws://{addr}{path}").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert(http::header::COOKIE, cookie.parse().unwrap());
+        let (socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        socket
+    }
+
+    /// Same as `connect_websocket`, asking for the JSON subprotocol like the CLI does
+    async fn connect_json_websocket(
+        addr: std::net::SocketAddr,
+        path: &str,
+        cookie: &str,
+    ) -> TestSocket {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut request = format!("ws://{addr}{path}").into_client_request().unwrap();
+        let headers = request.headers_mut();
+        headers.insert(http::header::COOKIE, cookie.parse().unwrap());
+        headers.insert(
+            http::header::SEC_WEBSOCKET_PROTOCOL,
+            JSON_SUBPROTOCOL.parse().unwrap(),
+        );
+        let (socket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(
+            response.headers()[http::header::SEC_WEBSOCKET_PROTOCOL],
+            JSON_SUBPROTOCOL
+        );
+        socket
+    }
+
+    /// Reads the event stream up to the end of the next event, keep-alive comments aside
+    async fn next_sse_event(body: &mut axum::body::BodyDataStream) -> String {
+        let mut event = String::new();
+        while !event.ends_with("\n\n") {
+            let chunk = tokio::time::timeout(Duration::from_secs(2), body.next())
+                .await
+                .expect("no event received in time")
+                .expect("event stream ended")
+                .unwrap();
+            event.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        event
+    }
+
+    /// Polls a condition on the server state, the socket tasks run in the background
+    async fn wait_until<F, Fut>(condition: F)
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !condition().await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition not met in time");
+    }
+
+    /// Waits for the next text frame sent by the server
+    async fn next_text(socket: &mut TestSocket) -> String {
+        loop {
+            let frame = tokio::time::timeout(Duration::from_secs(2), socket.next())
+                .await
+                .expect("no frame received in time")
+                .expect("websocket closed")
+                .unwrap();
+            if let WsMessage::Text(text) = frame {
+                return text.to_string();
+            }
+        }
+    }
+
+    /// Helper to read the response body as text (rendered templates)
+    async fn read_body(response: Response) -> String {
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    /// Helper to build a GET request carrying a session cookie
+    fn build_get_request_with_cookie(uri: &str, cookie: &str) -> Request<Body> {
+        let mut req = build_get_request(uri);
+        req.headers_mut()
+            .insert(http::header::COOKIE, cookie.parse().unwrap());
+        req
+    }
+
+    /// Helper to build a multipart POST request, parts are (name, filename, bytes)
+    fn build_multipart_request(
+        uri: &str,
+        parts: &[(&str, Option<&str>, &[u8])],
+        cookie: &str,
+    ) -> Request<Body> {
+        let boundary = "chat-app-test-boundary";
+        let mut body = Vec::new();
+        for (name, filename, bytes) in parts {
+            let disposition = match filename {
+                Some(filename) => format!(
+                    "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream",
+                    name, filename
+                ),
+                None => format!("Content-Disposition: form-data; name=\"{}\"", name),
+            };
+            body.extend_from_slice(format!("--{}\r\n{}\r\n\r\n", boundary, disposition).as_bytes());
+            body.extend_from_slice(bytes);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        Request::builder()
+            .method(http::Method::POST)
+            .uri(uri)
+            .header(
+                http::header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .header(http::header::COOKIE, cookie)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn test_png() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(640, 480))
+            .write_to(
+                &mut std::io::Cursor::new(&mut bytes),
+                image::ImageFormat::Png,
+            )
+            .unwrap();
+        bytes
+    }
+
+    fn test_attachment(content_type: &str) -> Attachment {
+        Attachment {
+            attachment_id: new_uuid(),
+            filename: "report.pdf".to_string(),
+            content_type: content_type.to_string(),
+            size: 9,
+            thumbnail: attachment::is_image(content_type),
+        }
+    }
+
+    /// Helper to build an htmx request with a form body and a session cookie
+    fn build_htmx_request_with_cookie(
+        method: http::Method,
+        uri: &str,
+        body_str: &str,
+        cookie: &str,
+    ) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                http::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .header(http::header::COOKIE, cookie)
+            .header("hx-request", "true")
+            .body(Body::from(body_str.to_string()))
+            .unwrap()
+    }
+
     /// Helper to deserialize the response body
     async fn deserialize_body<T: DeserializeOwned>(response: Response) -> T {
         let body = response.into_body();
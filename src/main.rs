@@ -2,36 +2,55 @@ use axum_prometheus::PrometheusMetricLayer;
 
 use tracing_subscriber::EnvFilter;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
 use tokio::signal;
 
 use crate::{
     consumer::MessageConsumer,
     db::{Db, ScyllaDb},
     handler::create_router,
+    membership::MembersCache,
+    presence::PresenceTracker,
     producer::{MessageProducer, Producer},
-    schema::PandaMessage,
+    search::SearchIndex,
+    storage::{FsStorage, S3Config, S3Storage, Storage},
+    typing::TypingThrottle,
+    websocket::{Connection, Heartbeat, SlowClientPolicy},
 };
 use anyhow::Result;
 use axum::routing::get;
 use opentelemetry_otlp::WithExportConfig;
 use tera::Tera;
-use tokio::sync::{RwLock, mpsc, watch};
+use tokio::sync::{RwLock, watch};
 use tracing_subscriber::{Registry, layer::SubscriberExt, util::SubscriberInitExt};
 use uuid::Uuid; // Needed for with_endpoint
 
+mod attachment;
+mod auth;
 mod consumer;
 mod db;
+mod event;
 mod handler;
+mod membership;
+mod mention;
+mod presence;
 mod producer;
 mod schema;
+mod search;
+mod sse;
+mod storage;
+mod typing;
 mod websocket;
 // FIXME : Change me to something random
 pub const NODE_ID: [u8; 6] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
-type UserSender = mpsc::Sender<PandaMessage>;
+// A week, overridden by SESSION_TTL_SECS
+const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const MEMBERS_CACHE_TTL: Duration = Duration::from_secs(1);
+// Every open websocket of a user (tabs, devices) by connection id, each with its own channel
+pub type UserConnections = HashMap<Uuid, Connection>;
 // We use a Rwlock and not a Mutex because tokio::sync::Rwlock for frequent read, less frequent
 // write
-pub type ConnectionMap = Arc<RwLock<HashMap<Uuid, UserSender>>>;
+pub type ConnectionMap = Arc<RwLock<HashMap<Uuid, UserConnections>>>;
 #[derive(Clone)]
 pub struct AppState {
     // Polymorphism  allow testing with the mockall library
@@ -39,6 +58,24 @@ pub struct AppState {
     producer: Arc<dyn Producer>,
     tera: Arc<Tera>,
     pub connections_map: ConnectionMap,
+    // Shared with the consumer, used to authorize access to chats
+    members_cache: Arc<MembersCache>,
+    // How long a session stays valid without activity
+    session_ttl: Duration,
+    // What happens to a websocket that can not keep up with its messages
+    slow_clients: SlowClientPolicy,
+    // Pings of the websockets, to reap the dead ones
+    heartbeat: Heartbeat,
+    // Typing signals relayed lately, to keep the topic quiet while someone types
+    typing_throttle: Arc<TypingThrottle>,
+    // Who is online, on this node and on the others
+    presence: Arc<PresenceTracker>,
+    // Bytes of the attachments, on disk or in a bucket
+    storage: Arc<dyn Storage>,
+    // Largest file accepted in an upload
+    max_attachment_bytes: usize,
+    // Full-text index of the messages, fed by its own reader of the topic
+    search: Arc<SearchIndex>,
 }
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -82,18 +119,107 @@ async fn main() -> Result<()> {
     let scylla_host = std::env::var("SCYLLA_HOST").unwrap_or_else(|_| "localhost:9042".to_string());
     let kafka_host = std::env::var("KAFKA_HOST").unwrap_or_else(|_| "localhost:19092".to_string());
     let app_port = std::env::var("APP_PORT").unwrap_or_else(|_| "8000".to_string());
+    let session_ttl = std::env::var("SESSION_TTL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SESSION_TTL);
+    let default_policy = SlowClientPolicy::default();
+    let slow_clients = SlowClientPolicy {
+        buffer: std::env::var("WS_BUFFER_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(default_policy.buffer),
+        // Unset: slow clients are only asked to resync, never disconnected
+        max_dropped: std::env::var("WS_MAX_DROPPED_MESSAGES")
+            .ok()
+            .and_then(|count| count.parse().ok()),
+    };
+    let default_heartbeat = Heartbeat::default();
+    let heartbeat = Heartbeat {
+        interval: std::env::var("WS_PING_INTERVAL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(default_heartbeat.interval),
+        timeout: std::env::var("WS_PONG_TIMEOUT_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(default_heartbeat.timeout),
+    };
+    // Attachments go to ./data/attachments unless STORAGE_BACKEND=s3
+    let storage: Arc<dyn Storage> = match std::env::var("STORAGE_BACKEND").as_deref() {
+        Ok("s3") => Arc::new(S3Storage::new(&S3Config::from_env()?)?),
+        _ => Arc::new(FsStorage::new(
+            std::env::var("STORAGE_PATH").unwrap_or_else(|_| "data/attachments".to_string()),
+        )),
+    };
+    let max_attachment_bytes = std::env::var("ATTACHMENT_MAX_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(attachment::DEFAULT_MAX_BYTES);
+    let search_path =
+        std::env::var("SEARCH_INDEX_PATH").unwrap_or_else(|_| "data/search".to_string());
     let db = ScyllaDb::new(&scylla_host).await?;
+    // One-off commands, they run against the database and exit
+    match std::env::args().nth(1).as_deref() {
+        // Rebuild the search index from the database, with the server stopped
+        Some("reindex") => {
+            let search = SearchIndex::open(Path::new(&search_path))?;
+            // Taken first, the indexer goes over what is sent meanwhile again rather than missing it
+            let offsets = consumer::end_offsets(&kafka_host, "chat-messages")?;
+            let count = search
+                .rebuild(db.stream_all_messages().await?, offsets)
+                .await?;
+            tracing::info!("Search index rebuilt with {} messages", count);
+            return Ok(());
+        }
+        // Give the chats older than chats_by_user their dashboard rows, once after migrating
+        Some("backfill-chats") => {
+            let count = db.backfill_chats_by_user().await?;
+            tracing::info!("Backfilled the dashboard rows of {} chats", count);
+            return Ok(());
+        }
+        _ => {}
+    }
+    let search = Arc::new(SearchIndex::open(Path::new(&search_path))?);
+    search.clone().spawn_committer();
     let db_worker = Arc::new(db);
+    search
+        .clone()
+        .spawn_indexer(&kafka_host, "chat-messages", db_worker.clone())?;
     let db_router = db_worker.clone();
     let connections_map: ConnectionMap = Arc::new(RwLock::new(HashMap::new()));
-    let tera = Arc::new(Tera::new("templates/**/*.html")?);
+    let members_cache = Arc::new(MembersCache::new(MEMBERS_CACHE_TTL));
+    let mut tera = Tera::new("templates/**/*.html")?;
+    mention::register_filter(&mut tera);
+    let tera = Arc::new(tera);
     let producer = Arc::new(MessageProducer::new(&kafka_host, "chat-messages")?);
+    // Stable across restarts, the presence records of this node are replaced instead of piling up
+    let node_name = std::env::var("NODE_NAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "chat-app".to_string());
+    let node_id = Uuid::new_v5(&Uuid::NAMESPACE_OID, node_name.as_bytes());
+    let (presence, presence_updates) = PresenceTracker::new(node_id);
+    let presence = Arc::new(presence);
+    presence::spawn_publisher(&kafka_host, presence.clone(), presence_updates)?;
+    presence::spawn_subscriber(&kafka_host, presence.clone())?;
     let (prometheus_layer, metric_handle) = PrometheusMetricLayer::pair();
     let app_state = AppState {
         db: db_router,
         producer: producer.clone(),
         tera: tera.clone(),
         connections_map: connections_map.clone(), // Clone 1 for Router
+        members_cache: members_cache.clone(),
+        session_ttl,
+        slow_clients,
+        heartbeat,
+        typing_throttle: Arc::default(),
+        presence,
+        storage: storage.clone(),
+        max_attachment_bytes,
+        search: search.clone(),
     };
 
     let group_id = format!(
@@ -122,7 +248,7 @@ async fn main() -> Result<()> {
                     tracing::info!("Shutdown signal received, stopping consumer...");
                     break;
                 }
-                result = consumer.consume_messages(db_worker.clone(), connections_map.clone()) => {
+                result = consumer.consume_messages(db_worker.clone(), connections_map.clone(), members_cache.clone(), storage.clone()) => {
                     match result {
                         Ok(_) => {
                             tracing::warn!("Consumer connection closed, restarting in 1s...");
@@ -0,0 +1,46 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::db::Db;
+
+/// Members of each chat, kept for a short TTL so that the hot paths (broadcast, authorization)
+/// do not hit ScyllaDB for every message
+pub struct MembersCache {
+    entries: DashMap<Uuid, (Vec<Uuid>, Instant)>,
+    ttl: Duration,
+}
+
+impl MembersCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: DashMap::new(),
+            ttl,
+        }
+    }
+
+    pub async fn get(&self, db: &dyn Db, chat_id: Uuid) -> Result<Vec<Uuid>> {
+        // The guard is dropped before the await below, DashMap shards must not be held across it
+        if let Some(entry) = self.entries.get(&chat_id) {
+            let (members, timestamp) = entry.value();
+            if timestamp.elapsed() < self.ttl {
+                return Ok(members.clone());
+            }
+        }
+
+        let members = db.get_members_of_chat(chat_id).await?;
+        self.entries
+            .insert(chat_id, (members.clone(), Instant::now()));
+        Ok(members)
+    }
+
+    pub async fn is_member(&self, db: &dyn Db, chat_id: Uuid, user_id: Uuid) -> Result<bool> {
+        Ok(self.get(db, chat_id).await?.contains(&user_id))
+    }
+
+    /// Forget a chat after its members changed, the next lookup goes to ScyllaDB
+    pub fn invalidate(&self, chat_id: Uuid) {
+        self.entries.remove(&chat_id);
+    }
+}
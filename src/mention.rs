@@ -0,0 +1,111 @@
+// @mentions: written as `@username` in the content, resolved to users by the consumer and
+// highlighted when a message is rendered
+
+use std::collections::{HashMap, HashSet};
+
+use tera::{Tera, Value};
+
+/// Past it the rest of the `@` are left as text, a message is not a mailing list
+pub const MAX_MENTIONS: usize = 20;
+
+const HIGHLIGHT_CLASS: &str = "font-semibold text-blue-700 bg-blue-100 rounded px-0.5";
+
+/// Byte ranges of the `@username` of a content, `@` included
+/// An `@` glued to a word is an email address, and a trailing `.` or `-` ends the sentence
+fn spans(content: &str) -> Vec<(usize, usize)> {
+    let is_name = |c: char| c.is_alphanumeric() || matches!(c, '_' | '.' | '-');
+    let mut spans = Vec::new();
+    let mut previous = None;
+    for (start, c) in content.char_indices() {
+        let glued = previous.is_some_and(|p: char| p.is_alphanumeric());
+        previous = Some(c);
+        if c != '@' || glued {
+            continue;
+        }
+        let name = &content[start + 1..];
+        let length = name.find(|c: char| !is_name(c)).unwrap_or(name.len());
+        let name = name[..length].trim_end_matches(['.', '-']);
+        if !name.is_empty() {
+            spans.push((start, start + 1 + name.len()));
+        }
+    }
+    spans
+}
+
+/// Usernames mentioned in a content, in order and without duplicates
+pub fn parse(content: &str) -> Vec<&str> {
+    let mut seen = HashSet::new();
+    spans(content)
+        .into_iter()
+        .map(|(start, end)| &content[start + 1..end])
+        .filter(|username| seen.insert(*username))
+        .take(MAX_MENTIONS)
+        .collect()
+}
+
+/// Escaped content with the resolved mentions highlighted, `@` that match nobody stay plain text
+fn highlight(content: &str, usernames: &HashSet<&str>) -> String {
+    let mut html = String::with_capacity(content.len());
+    let mut written = 0;
+    for (start, end) in spans(content) {
+        if !usernames.contains(&content[start + 1..end]) {
+            continue;
+        }
+        html.push_str(&tera::escape_html(&content[written..start]));
+        html.push_str(&format!(
+            r#"<span class="{}">{}</span>"#,
+            HIGHLIGHT_CLASS,
+            tera::escape_html(&content[start..end])
+        ));
+        written = end;
+    }
+    html.push_str(&tera::escape_html(&content[written..]));
+    html
+}
+
+/// `{{ message | highlight_mentions | safe }}`, the output is already escaped
+fn highlight_filter(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let content = value
+        .get("content")
+        .and_then(Value::as_str)
+        .ok_or_else(|| tera::Error::msg("highlight_mentions expects a message"))?;
+    let usernames: HashSet<&str> = value
+        .get("mentions")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|mention| mention.get("username")?.as_str())
+        .collect();
+    Ok(Value::String(highlight(content, &usernames)))
+}
+
+pub fn register_filter(tera: &mut Tera) {
+    tera.register_filter("highlight_mentions", highlight_filter);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_skips_emails_and_punctuation() {
+        let content = "@alice and @bob. ping mail@example.com, @alice again, @-";
+
+        assert_eq!(parse(content), ["alice", "bob"]);
+    }
+
+    #[test]
+    fn test_only_resolved_mentions_are_highlighted() {
+        let usernames = HashSet::from(["bob"]);
+
+        let html = highlight("<b>@bob</b> meet @nobody", &usernames);
+
+        assert_eq!(
+            html,
+            format!(
+                r#"&lt;b&gt;<span class="{}">@bob</span>&lt;&#x2F;b&gt; meet @nobody"#,
+                HIGHLIGHT_CLASS
+            )
+        );
+    }
+}
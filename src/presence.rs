@@ -0,0 +1,321 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use dashmap::DashMap;
+use futures_util::StreamExt;
+use rdkafka::{
+    ClientConfig, Message, Offset, TopicPartitionList,
+    consumer::{Consumer, StreamConsumer},
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use uuid::Uuid;
+
+use crate::consumer::partitions_of;
+
+/// Compacted topic, keyed by user and node so the latest status of each pair survives compaction
+/// Going offline publishes a tombstone, compaction then drops the pair
+pub const PRESENCE_TOPIC: &str = "presence";
+// Every node republishes its connected users this often, a node that stops doing it crashed
+const PRESENCE_REFRESH: std::time::Duration = std::time::Duration::from_secs(30);
+const PRESENCE_TTL: TimeDelta = TimeDelta::seconds(90);
+
+/// Ordered so that the most present status of a user across nodes wins
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Offline,
+    /// Connected, with every tab in the background
+    Away,
+    Online,
+}
+
+/// Status of a user on one node, the records of the presence topic
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PresenceUpdate {
+    pub user_id: Uuid,
+    pub node_id: Uuid,
+    pub status: Status,
+    pub at: DateTime<Utc>,
+}
+
+/// What the API and the templates show of a user's presence
+#[derive(Serialize, Debug)]
+pub struct PresenceView {
+    pub user_id: Uuid,
+    pub status: Status,
+}
+
+/// Presence of every user as seen by this node: its own connections plus what the others published
+pub struct PresenceTracker {
+    node_id: Uuid,
+    nodes: DashMap<Uuid, HashMap<Uuid, (Status, DateTime<Utc>)>>,
+    updates: UnboundedSender<PresenceUpdate>,
+}
+
+impl PresenceTracker {
+    /// The receiver gets the local changes to publish, dropping it keeps presence node-local
+    /// `node_id` has to stay the same across restarts, the records of a node are keyed by it
+    pub fn new(node_id: Uuid) -> (Self, UnboundedReceiver<PresenceUpdate>) {
+        let (updates, receiver) = unbounded_channel();
+        let tracker = Self {
+            node_id,
+            nodes: DashMap::new(),
+            updates,
+        };
+        (tracker, receiver)
+    }
+
+    /// Status of a user from the connections of this node, published when it changes
+    pub fn set_local(&self, user_id: Uuid, status: Status) {
+        let at = Utc::now();
+        let mut nodes = self.nodes.entry(user_id).or_default();
+        let previous = match status {
+            Status::Offline => nodes.remove(&self.node_id),
+            _ => nodes.insert(self.node_id, (status, at)),
+        };
+        if previous.map_or(Status::Offline, |(status, _)| status) == status {
+            return;
+        }
+        let _ = self.updates.send(PresenceUpdate {
+            user_id,
+            node_id: self.node_id,
+            status,
+            at,
+        });
+    }
+
+    /// Record the status published by another node, ours are already known
+    pub fn apply(&self, update: PresenceUpdate) {
+        if update.node_id == self.node_id {
+            return;
+        }
+        let mut nodes = self.nodes.entry(update.user_id).or_default();
+        match update.status {
+            Status::Offline => nodes.remove(&update.node_id),
+            status => nodes.insert(update.node_id, (status, update.at)),
+        };
+    }
+
+    pub fn status(&self, user_id: Uuid) -> Status {
+        let Some(nodes) = self.nodes.get(&user_id) else {
+            return Status::Offline;
+        };
+        let now = Utc::now();
+        nodes
+            .iter()
+            // Ours are always current, the others expire unless refreshed
+            .filter(|(node_id, (_, at))| **node_id == self.node_id || now - *at < PRESENCE_TTL)
+            .map(|(_, (status, _))| *status)
+            .max()
+            .unwrap_or(Status::Offline)
+    }
+
+    /// The users connected to this node, republished so the other nodes keep them fresh
+    fn local_updates(&self) -> Vec<PresenceUpdate> {
+        let at = Utc::now();
+        self.nodes
+            .iter()
+            .filter_map(|entry| {
+                let (status, _) = entry.value().get(&self.node_id)?;
+                Some(PresenceUpdate {
+                    user_id: *entry.key(),
+                    node_id: self.node_id,
+                    status: *status,
+                    at,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Publish the local changes, and refresh the connected users periodically
+pub fn spawn_publisher(
+    brokers: &str,
+    tracker: Arc<PresenceTracker>,
+    mut updates: UnboundedReceiver<PresenceUpdate>,
+) -> Result<()> {
+    let producer: FutureProducer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .create()
+        .context("Failed to create presence producer")?;
+
+    tokio::spawn(async move {
+        let mut refresh = tokio::time::interval(PRESENCE_REFRESH);
+        loop {
+            let batch = tokio::select! {
+                update = updates.recv() => match update {
+                    Some(update) => vec![update],
+                    None => break,
+                },
+                _ = refresh.tick() => tracker.local_updates(),
+            };
+            for update in batch {
+                if let Err(e) = publish(&producer, &update).await {
+                    tracing::warn!("Failed to publish presence of {}: {:?}", update.user_id, e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn publish(producer: &FutureProducer, update: &PresenceUpdate) -> Result<()> {
+    let key = format!("{}:{}", update.user_id, update.node_id);
+    let record = FutureRecord::<String, String>::to(PRESENCE_TOPIC).key(&key);
+    // Offline is a tombstone, without a payload
+    let payload = match update.status {
+        Status::Offline => None,
+        _ => Some(serde_json::to_string(update)?),
+    };
+    let record = match &payload {
+        Some(payload) => record.payload(payload),
+        None => record,
+    };
+    producer
+        .send(record, Timeout::Never)
+        .await
+        .map_err(|(e, _)| e)?;
+    Ok(())
+}
+
+/// A record of the presence topic, a tombstone is the user going offline on that node
+fn decode(key: Option<&[u8]>, payload: Option<&[u8]>) -> Result<PresenceUpdate> {
+    if let Some(payload) = payload {
+        return serde_json::from_slice(payload).context("invalid presence");
+    }
+    let key = std::str::from_utf8(key.context("tombstone without key")?)?;
+    let (user_id, node_id) = key.split_once(':').context("invalid presence key")?;
+    Ok(PresenceUpdate {
+        user_id: Uuid::parse_str(user_id)?,
+        node_id: Uuid::parse_str(node_id)?,
+        status: Status::Offline,
+        at: Utc::now(),
+    })
+}
+
+/// Read the whole compacted topic, then follow it, to know the users of the other nodes
+pub fn spawn_subscriber(brokers: &str, tracker: Arc<PresenceTracker>) -> Result<()> {
+    // Every node needs every record: the partitions are assigned by hand, without a group
+    // and without committing offsets, the topic is read from the beginning at each start
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("enable.auto.commit", "false")
+        .create()
+        .context("Failed to create presence consumer")?;
+
+    tokio::spawn(async move {
+        if let Err(e) = assign_from_beginning(&consumer).await {
+            tracing::error!("Presence of the other nodes is unavailable: {:?}", e);
+            return;
+        }
+        let mut stream = consumer.stream();
+        while let Some(record) = stream.next().await {
+            let update = record
+                .context("Kafka consumer error")
+                .and_then(|record| decode(record.key(), record.payload()));
+            match update {
+                Ok(update) => tracker.apply(update),
+                Err(e) => tracing::warn!("Skipping presence record: {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Assign every partition of the topic, retrying until the brokers answer
+async fn assign_from_beginning(consumer: &StreamConsumer) -> Result<()> {
+    let partitions = loop {
+        match partitions_of(consumer, PRESENCE_TOPIC) {
+            Ok(partitions) => break partitions,
+            Err(e) => tracing::warn!("Presence partitions unavailable: {:#}", e),
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    };
+    let mut assignment = TopicPartitionList::new();
+    for partition in partitions {
+        assignment.add_partition_offset(PRESENCE_TOPIC, partition, Offset::Beginning)?;
+    }
+    consumer
+        .assign(&assignment)
+        .context("Presence consumer failed to assign the partitions")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(user_id: Uuid, status: Status, at: DateTime<Utc>) -> PresenceUpdate {
+        PresenceUpdate {
+            user_id,
+            node_id: Uuid::new_v4(),
+            status,
+            at,
+        }
+    }
+
+    #[test]
+    fn test_local_changes_are_published_once() {
+        let (tracker, mut updates) = PresenceTracker::new(Uuid::new_v4());
+        let user_id = Uuid::new_v4();
+
+        tracker.set_local(user_id, Status::Online);
+        tracker.set_local(user_id, Status::Online);
+        tracker.set_local(user_id, Status::Offline);
+
+        assert_eq!(updates.try_recv().unwrap().status, Status::Online);
+        assert_eq!(updates.try_recv().unwrap().status, Status::Offline);
+        assert!(updates.try_recv().is_err());
+        assert_eq!(tracker.status(user_id), Status::Offline);
+    }
+
+    #[test]
+    fn test_most_present_node_wins() {
+        let (tracker, _updates) = PresenceTracker::new(Uuid::new_v4());
+        let user_id = Uuid::new_v4();
+
+        tracker.set_local(user_id, Status::Away);
+        assert_eq!(tracker.status(user_id), Status::Away);
+
+        let other_node = remote(user_id, Status::Online, Utc::now());
+        tracker.apply(other_node.clone());
+        assert_eq!(tracker.status(user_id), Status::Online);
+
+        tracker.apply(PresenceUpdate {
+            status: Status::Offline,
+            ..other_node
+        });
+        assert_eq!(tracker.status(user_id), Status::Away);
+    }
+
+    #[test]
+    fn test_offline_is_a_tombstone() {
+        let (user_id, node_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let key = format!("{}:{}", user_id, node_id);
+
+        let update = decode(Some(key.as_bytes()), None).unwrap();
+
+        assert_eq!(update.user_id, user_id);
+        assert_eq!(update.node_id, node_id);
+        assert_eq!(update.status, Status::Offline);
+        assert!(decode(Some(b"garbage"), None).is_err());
+    }
+
+    #[test]
+    fn test_stale_remote_status_expires() {
+        let (tracker, _updates) = PresenceTracker::new(Uuid::new_v4());
+        let user_id = Uuid::new_v4();
+
+        // The node that published it stopped refreshing, it probably crashed
+        tracker.apply(remote(
+            user_id,
+            Status::Online,
+            Utc::now() - PRESENCE_TTL * 2,
+        ));
+
+        assert_eq!(tracker.status(user_id), Status::Offline);
+    }
+}
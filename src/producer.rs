@@ -1,5 +1,5 @@
 // FIXME : Make sure the id of message is converted to a TimeuiD
-use anyhow::{Context, Result};
+use anyhow::Result;
 use async_trait::async_trait;
 #[cfg(test)]
 use mockall::automock;
@@ -9,12 +9,17 @@ use rdkafka::{
     util::Timeout,
 };
 
-use crate::schema::PandaMessage;
+use crate::{event::ChatEvent, schema::PandaMessage};
 
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait Producer: Send + Sync {
-    async fn send_message(&self, message: PandaMessage) -> Result<()>;
+    async fn send_event(&self, event: ChatEvent) -> Result<()>;
+
+    /// Publish a newly posted message
+    async fn send_message(&self, message: PandaMessage) -> Result<()> {
+        self.send_event(ChatEvent::MessagePosted(message)).await
+    }
 }
 
 #[derive(Clone)]
@@ -38,14 +43,12 @@ impl MessageProducer {
 
 #[async_trait]
 impl Producer for MessageProducer {
-    async fn send_message(&self, message: PandaMessage) -> Result<()> {
-        let payload = serde_json::to_string(&message).context("Failed to deserialize payload")?;
-        let sender_id = message.sender_id.to_string();
+    async fn send_event(&self, event: ChatEvent) -> Result<()> {
+        let payload = event.encode()?;
+        let key = event.key().to_string();
         self.producer
             .send(
-                FutureRecord::to(&self.topic)
-                    .payload(&payload)
-                    .key(&sender_id),
+                FutureRecord::to(&self.topic).payload(&payload).key(&key),
                 Timeout::Never,
             )
             .await
@@ -1,9 +1,12 @@
-use chrono::{DateTime, Utc};
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use chrono::{DateTime, NaiveDate, Utc};
 use scylla::{DeserializeRow, value::CqlTimeuuid};
 use uuid::Uuid;
 #[derive(serde::Deserialize)]
 pub struct CreateUser {
     pub username: String,
+    pub password: String,
 }
 #[derive(DeserializeRow, serde::Deserialize, serde::Serialize, PartialEq, Debug, Clone)]
 pub struct User {
@@ -19,6 +22,71 @@ pub struct Chat {
     pub members: Vec<Uuid>,
     pub name: String,
     pub created_at: DateTime<Utc>,
+    /// Owner and admins only, filled by `get_chat` but not by the dashboard listing
+    #[scylla(skip)]
+    #[serde(default)]
+    pub roles: HashMap<Uuid, Role>,
+    /// Messages of the others since the user last read the chat, capped, filled by the dashboard listing
+    #[scylla(skip)]
+    #[serde(default)]
+    pub unread: i64,
+}
+
+impl Chat {
+    /// Role of a user in the chat, `None` when not a member
+    pub fn role_of(&self, user_id: Uuid) -> Option<Role> {
+        if !self.members.contains(&user_id) {
+            return None;
+        }
+        if let Some(role) = self.roles.get(&user_id) {
+            return Some(*role);
+        }
+        // Chats created before roles have no owner, their members keep managing them
+        if !self.roles.values().any(|role| *role == Role::Owner) {
+            return Some(Role::Admin);
+        }
+        Some(Role::Member)
+    }
+}
+
+/// What a member may do in a chat, ordered from the least to the most privileged
+#[derive(
+    serde::Deserialize, serde::Serialize, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Member,
+    Admin,
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "member" => Ok(Role::Member),
+            "admin" => Ok(Role::Admin),
+            "owner" => Ok(Role::Owner),
+            _ => Err(anyhow::anyhow!("Unknown role '{}'", s)),
+        }
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -27,20 +95,275 @@ pub struct CreateChat {
     pub members: Vec<Uuid>,
 }
 
+#[derive(serde::Deserialize)]
+pub struct AddMember {
+    pub user_id: Uuid,
+}
+
+#[derive(serde::Deserialize)]
+pub struct SetRole {
+    pub role: Role,
+}
+
+#[derive(serde::Deserialize)]
+pub struct EditMessage {
+    pub content: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct TransferOwnership {
+    pub user_id: Uuid,
+}
+
 #[derive(DeserializeRow, serde::Deserialize, serde::Serialize, PartialEq, Debug, Clone)]
 pub struct CreatMessage {
     pub content: String,
+    /// Set to reply in the thread of a message
+    #[scylla(skip)]
+    #[serde(default)]
+    pub parent_id: Option<Uuid>,
 }
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct LoginPayload {
     pub username: String,
+    pub password: String,
+}
+#[derive(DeserializeRow, PartialEq, Debug, Clone)]
+pub struct UserSession {
+    pub session_id: Uuid,
+    pub user_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
 }
-#[derive(serde::Serialize, serde::Deserialize, Clone, DeserializeRow)]
+/// Sender of the messages written by the server itself (e.g. "Alice added Bob")
+pub const SYSTEM_SENDER_ID: Uuid = Uuid::nil();
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, DeserializeRow)]
 pub struct PandaMessage {
     pub chat_id: Uuid,
     pub sender_id: Uuid,
     pub content: String,
     pub message_id: Uuid,
+    /// Set when the message is an edit of an already posted one
+    #[serde(default)]
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Set when the message is the deletion of an already posted one
+    #[serde(default)]
+    pub deleted: bool,
+    /// Aggregated from the reactions table when loading history, never carried by the events
+    #[scylla(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+    /// Members written as `@username` in the content, resolved by the consumer
+    #[scylla(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<Mention>,
+    /// Files uploaded with the message, carried by the event and stored in their own table
+    #[scylla(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// The message this one replies to, replies are stored in their thread and not in the history
+    #[scylla(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<Uuid>,
+    /// Counted from the threads when loading history
+    #[scylla(skip)]
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub reply_count: usize,
+}
+
+fn is_zero(count: &usize) -> bool {
+    *count == 0
+}
+
+impl PandaMessage {
+    /// Edits and deletions reuse the id of the message they change
+    pub fn is_change(&self) -> bool {
+        self.deleted || self.edited_at.is_some()
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct Mention {
+    pub user_id: Uuid,
+    pub username: String,
+}
+
+/// A file of a message, its bytes are in the attachment storage
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, DeserializeRow)]
+pub struct Attachment {
+    pub attachment_id: Uuid,
+    /// As uploaded, without its path
+    pub filename: String,
+    /// Sniffed from the bytes, not the one announced by the client
+    pub content_type: String,
+    pub size: i64,
+    /// Images get a thumbnail for the history
+    pub thumbnail: bool,
+}
+
+/// Everyone who reacted to a message with the same emoji
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct Reaction {
+    pub emoji: String,
+    pub user_ids: Vec<Uuid>,
+}
+
+impl Reaction {
+    /// Group the (emoji, user) rows of a message, emojis in the order they come
+    pub fn aggregate(rows: impl IntoIterator<Item = (String, Uuid)>) -> Vec<Reaction> {
+        let mut reactions: Vec<Reaction> = Vec::new();
+        for (emoji, user_id) in rows {
+            match reactions
+                .iter_mut()
+                .find(|reaction| reaction.emoji == emoji)
+            {
+                Some(reaction) => reaction.user_ids.push(user_id),
+                None => reactions.push(Reaction {
+                    emoji,
+                    user_ids: vec![user_id],
+                }),
+            }
+        }
+        reactions
+    }
+}
+
+/// A member adding or removing an emoji on a message
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ReactionChange {
+    pub chat_id: Uuid,
+    pub message_id: Uuid,
+    /// Sender of the message, the partition key of every event about it
+    pub sender_id: Uuid,
+    pub user_id: Uuid,
+    pub emoji: String,
+    /// When the member reacted, the write timestamp so the last change wins whatever the order
+    /// they are stored in. Missing from the events produced before it was added
+    #[serde(default)]
+    pub at: Option<DateTime<Utc>>,
+}
+
+/// The reactions of a message after a change, what the members get live
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct MessageReactions {
+    pub chat_id: Uuid,
+    pub message_id: Uuid,
+    pub reactions: Vec<Reaction>,
+}
+
+/// A member is typing in a chat, relayed to the other members and never stored
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Typing {
+    pub chat_id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    /// When the signal was sent, a late one is not worth showing
+    pub at: DateTime<Utc>,
+}
+
+/// A member read a chat up to a message, told to the sender of that message and never stored as an event
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ReadReceipt {
+    pub chat_id: Uuid,
+    pub message_id: Uuid,
+    /// Sender of the message read, the only one the receipt is relayed to
+    pub sender_id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+}
+
+/// Body of the mark read endpoint, without a message the chat is read up to its newest one
+#[derive(serde::Deserialize, Default)]
+pub struct MarkRead {
+    pub message_id: Option<Uuid>,
+}
+
+/// Query string of the chat history endpoint
+#[derive(serde::Deserialize)]
+pub struct MessagesQuery {
+    /// Only messages strictly older than this timeuuid
+    pub before: Option<Uuid>,
+    pub limit: Option<i32>,
+}
+
+/// Query string of the search endpoint
+/// The search form sends its empty fields, they mean no filter
+#[derive(serde::Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    /// Search this chat only, all the chats of the user otherwise
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub chat_id: Option<Uuid>,
+    /// Username of the sender
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub sender: Option<String>,
+    /// Sent on or after this day, UTC
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub from: Option<NaiveDate>,
+    /// Sent on or before this day, UTC
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub to: Option<NaiveDate>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub limit: Option<usize>,
+}
+
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Query string of the websocket connection, and of the event stream
+#[derive(serde::Deserialize, Default, Clone, Copy, Debug)]
+pub struct ConnectQuery {
+    /// Newest message the client already shows, everything after it is replayed on connect
+    pub last_seen: Option<Uuid>,
+    /// Only deliver this chat, as the chat page does
+    pub chat_id: Option<Uuid>,
+    /// Thread followed from the start, its replies are only sent to the connections following it
+    pub thread_id: Option<Uuid>,
+    /// `?format=json` for clients that can not pick the subprotocol
+    #[serde(default)]
+    pub format: WireFormat,
+}
+
+/// What the websocket sends: htmx fragments for the browser, JSON events for the other clients
+#[derive(serde::Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Html,
+    Json,
+}
+
+/// A message and its replies, oldest reply first
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Thread {
+    pub parent: PandaMessage,
+    pub replies: Vec<PandaMessage>,
+}
+
+/// A message posted with files, what an upload answers
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Upload {
+    pub message_id: Uuid,
+    pub attachments: Vec<Attachment>,
+}
+
+/// One page of a chat history, newest message first
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct MessagePage {
+    pub messages: Vec<PandaMessage>,
+    /// Pass it as `before` to fetch the next (older) page, `None` once the history is exhausted
+    pub next_cursor: Option<Uuid>,
 }
 
 #[derive(scylla::DeserializeRow)]
@@ -49,15 +372,45 @@ pub struct RawPandaMessage {
     sender_id: Uuid,
     content: String,
     message_id: CqlTimeuuid, // Matches CQL 'Timeuuid'
+    edited_at: Option<DateTime<Utc>>,
+    deleted: Option<bool>,
+    mentions: Option<HashMap<Uuid, String>>,
 }
 
 impl RawPandaMessage {
     pub fn to_panda_message(&self) -> PandaMessage {
+        // Nothing of a deleted message is shown, whatever a stale write left in its row
+        let deleted = self.deleted.unwrap_or(false);
         PandaMessage {
             chat_id: self.chat_id,
             sender_id: self.sender_id,
-            content: self.content.clone(),
+            content: if deleted {
+                String::new()
+            } else {
+                self.content.clone()
+            },
             message_id: Uuid::from(self.message_id),
+            edited_at: self.edited_at,
+            deleted,
+            reactions: Vec::new(),
+            mentions: if deleted { Vec::new() } else { self.mentions() },
+            attachments: Vec::new(),
+            parent_id: None,
+            reply_count: 0,
         }
     }
+
+    fn mentions(&self) -> Vec<Mention> {
+        let mut mentions: Vec<Mention> = self
+            .mentions
+            .iter()
+            .flatten()
+            .map(|(user_id, username)| Mention {
+                user_id: *user_id,
+                username: username.clone(),
+            })
+            .collect();
+        mentions.sort_by(|a, b| a.username.cmp(&b.username));
+        mentions
+    }
 }
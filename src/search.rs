@@ -0,0 +1,690 @@
+// Full-text search over the messages, in an index embedded in each node
+// Every node reads the whole topic, so each one keeps a complete index of its own
+use std::{
+    collections::HashMap,
+    ops::Bound,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
+use metrics::counter;
+use rdkafka::{
+    ClientConfig, Message, Offset, TopicPartitionList,
+    consumer::{Consumer, StreamConsumer},
+};
+use tantivy::{
+    DateTime as IndexDateTime, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument,
+    Term,
+    collector::TopDocs,
+    directory::MmapDirectory,
+    query::{BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery, TermSetQuery},
+    schema::{
+        Field, INDEXED, IndexRecordOption, STORED, STRING, Schema, TextFieldIndexing, TextOptions,
+        Value,
+    },
+    snippet::{Snippet, SnippetGenerator},
+    tokenizer::{AsciiFoldingFilter, LowerCaser, SimpleTokenizer, TextAnalyzer},
+};
+use uuid::Uuid;
+
+use crate::{
+    consumer::partitions_of,
+    db::Db,
+    event::ChatEvent,
+    schema::{PandaMessage, SYSTEM_SENDER_ID},
+};
+
+// Lowercased and without accents: "cafe" finds "Café"
+const TOKENIZER: &str = "chat";
+const WRITER_MEMORY_BYTES: usize = 50_000_000;
+const SNIPPET_CHARS: usize = 200;
+/// How long a message takes at most to be searchable once stored
+pub const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+// Deletions read lately, an edit that raced one is published right after it
+const DELETION_MEMORY: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy)]
+struct Fields {
+    message_id: Field,
+    chat_id: Field,
+    sender_id: Field,
+    parent_id: Field,
+    content: Field,
+    sent_at: Field,
+}
+
+/// What to look for, and where
+pub struct SearchFilter<'a> {
+    pub text: &'a str,
+    /// Only these chats, the ones the caller belongs to
+    pub chat_ids: &'a [Uuid],
+    pub sender_id: Option<Uuid>,
+    pub since: Option<DateTime<Utc>>,
+    /// Exclusive
+    pub until: Option<DateTime<Utc>>,
+    pub limit: usize,
+}
+
+/// A message matching a search, best match first
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SearchHit {
+    pub chat_id: Uuid,
+    pub message_id: Uuid,
+    pub sender_id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<Uuid>,
+    pub sent_at: Option<DateTime<Utc>>,
+    /// Escaped content around the matches, which are wrapped in `<mark>`
+    pub snippet: String,
+}
+
+pub struct SearchIndex {
+    index: Index,
+    writer: Mutex<IndexWriter>,
+    reader: IndexReader,
+    fields: Fields,
+    // Changes waiting for the next commit
+    dirty: AtomicBool,
+    // Next offset to index in each partition of the topic, saved with every commit so that a
+    // restart goes on from there. Locked after the writer
+    offsets: Mutex<HashMap<i32, i64>>,
+}
+
+impl SearchIndex {
+    /// Open the index of the node, created on first use
+    pub fn open(path: &Path) -> Result<Self> {
+        std::fs::create_dir_all(path).context("Failed to create search index directory")?;
+        let directory = MmapDirectory::open(path).context("Failed to open search index")?;
+        let index = Index::open_or_create(directory, Self::schema())?;
+        Self::with_index(index)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self> {
+        Self::with_index(Index::create_in_ram(Self::schema()))
+    }
+
+    fn schema() -> Schema {
+        let mut schema = Schema::builder();
+        schema.add_text_field("message_id", STRING | STORED);
+        schema.add_text_field("chat_id", STRING | STORED);
+        schema.add_text_field("sender_id", STRING | STORED);
+        schema.add_text_field("parent_id", STRING | STORED);
+        schema.add_text_field(
+            "content",
+            TextOptions::default()
+                .set_indexing_options(
+                    TextFieldIndexing::default()
+                        .set_tokenizer(TOKENIZER)
+                        .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+                )
+                .set_stored(),
+        );
+        schema.add_date_field("sent_at", INDEXED);
+        schema.build()
+    }
+
+    fn with_index(index: Index) -> Result<Self> {
+        index.tokenizers().register(
+            TOKENIZER,
+            TextAnalyzer::builder(SimpleTokenizer::default())
+                .filter(LowerCaser)
+                .filter(AsciiFoldingFilter)
+                .build(),
+        );
+        let schema = index.schema();
+        let fields = Fields {
+            message_id: schema.get_field("message_id")?,
+            chat_id: schema.get_field("chat_id")?,
+            sender_id: schema.get_field("sender_id")?,
+            parent_id: schema.get_field("parent_id")?,
+            content: schema.get_field("content")?,
+            sent_at: schema.get_field("sent_at")?,
+        };
+        let writer = index
+            .writer(WRITER_MEMORY_BYTES)
+            .context("Failed to lock the search index, is another process using it?")?;
+        // Reloaded by our commits, searches see a message as soon as it is committed
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        let offsets = index
+            .load_metas()?
+            .payload
+            .and_then(|payload| serde_json::from_str(&payload).ok())
+            .unwrap_or_default();
+        Ok(Self {
+            index,
+            writer: Mutex::new(writer),
+            reader,
+            fields,
+            dirty: AtomicBool::new(false),
+            offsets: Mutex::new(offsets),
+        })
+    }
+
+    /// Apply a batch of stored messages, searchable after the next commit
+    /// Edits replace the indexed content, deletions remove it
+    pub fn index_messages(&self, messages: &[PandaMessage]) -> Result<()> {
+        self.index_records(messages, HashMap::new())
+    }
+
+    /// Index messages read from the topic, up to these offsets
+    /// Blocks while a commit holds the writer, call it from a blocking task
+    fn index_records(&self, messages: &[PandaMessage], offsets: HashMap<i32, i64>) -> Result<()> {
+        let writer = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow!("Poisoned index writer"))?;
+        self.offsets
+            .lock()
+            .map_err(|_| anyhow!("Poisoned index offsets"))?
+            .extend(offsets);
+        for message in messages {
+            writer.delete_term(Term::from_field_text(
+                self.fields.message_id,
+                &message.message_id.to_string(),
+            ));
+            if !message.deleted && message.sender_id != SYSTEM_SENDER_ID {
+                writer.add_document(self.document(message))?;
+            }
+        }
+        counter!("search_indexed_messages_total").increment(messages.len() as u64);
+        self.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn document(&self, message: &PandaMessage) -> TantivyDocument {
+        let mut document = TantivyDocument::default();
+        document.add_text(self.fields.message_id, message.message_id.to_string());
+        document.add_text(self.fields.chat_id, message.chat_id.to_string());
+        document.add_text(self.fields.sender_id, message.sender_id.to_string());
+        if let Some(parent_id) = message.parent_id {
+            document.add_text(self.fields.parent_id, parent_id.to_string());
+        }
+        document.add_text(self.fields.content, &message.content);
+        if let Some(sent_at) = sent_at(message.message_id) {
+            document.add_date(
+                self.fields.sent_at,
+                IndexDateTime::from_timestamp_micros(sent_at.timestamp_micros()),
+            );
+        }
+        document
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow!("Poisoned index writer"))?;
+        let offsets = serde_json::to_string(
+            &*self
+                .offsets
+                .lock()
+                .map_err(|_| anyhow!("Poisoned index offsets"))?,
+        )?;
+        let mut commit = writer.prepare_commit()?;
+        commit.set_payload(&offsets);
+        commit.commit().context("Failed to commit search index")?;
+        drop(writer);
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    /// Follow the topic from where the last commit stopped, so the messages sent while the node
+    /// was down get indexed too. Partitions assigned by hand: every node indexes every message
+    /// Edits are only indexed when ScyllaDB took them, see `accepted`
+    pub fn spawn_indexer(
+        self: Arc<Self>,
+        brokers: &str,
+        topic: &str,
+        db: Arc<dyn Db>,
+    ) -> Result<()> {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("enable.auto.commit", "false")
+            // Saved offsets older than the retention start at the oldest message left
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .context("Failed to create search indexer")?;
+        let topic = topic.to_string();
+
+        tokio::spawn(async move {
+            if let Err(e) = self.assign(&consumer, &topic).await {
+                tracing::error!("Search indexer stopped: {:#}", e);
+                return;
+            }
+            let mut chunks = std::pin::pin!(tokio_stream::StreamExt::chunks_timeout(
+                consumer.stream(),
+                500,
+                Duration::from_millis(100)
+            ));
+            let mut deleted = HashMap::new();
+            while let Some(chunk) = chunks.next().await {
+                let mut messages = Vec::new();
+                let mut offsets = HashMap::new();
+                for record in chunk {
+                    let record = match record {
+                        Ok(record) => record,
+                        Err(e) => {
+                            tracing::warn!("Search indexer consumer error: {:?}", e);
+                            continue;
+                        }
+                    };
+                    offsets.insert(record.partition(), record.offset() + 1);
+                    let Some(Ok(payload)) = record.payload_view::<str>() else {
+                        continue;
+                    };
+                    // Invalid and unknown events are already logged by the consumer
+                    if let Ok(Some(event)) = ChatEvent::decode(payload) {
+                        messages.extend(event.into_message());
+                    }
+                }
+                let messages = accepted(messages, db.as_ref(), &mut deleted).await;
+                let index = self.clone();
+                match tokio::task::spawn_blocking(move || index.index_records(&messages, offsets))
+                    .await
+                {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => tracing::warn!("Failed to index messages for search: {:#}", e),
+                    Err(e) => tracing::error!("Search indexing panicked: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Every partition at its saved offset, the end for those never indexed
+    async fn assign(&self, consumer: &StreamConsumer, topic: &str) -> Result<()> {
+        let partitions = loop {
+            match partitions_of(consumer, topic) {
+                Ok(partitions) => break partitions,
+                Err(e) => tracing::warn!("Search indexer partitions unavailable: {:#}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        };
+        let offsets = self
+            .offsets
+            .lock()
+            .map_err(|_| anyhow!("Poisoned index offsets"))?
+            .clone();
+        let mut assignment = TopicPartitionList::new();
+        for partition in partitions {
+            let offset = offsets
+                .get(&partition)
+                .map_or(Offset::End, |offset| Offset::Offset(*offset));
+            assignment.add_partition_offset(topic, partition, offset)?;
+        }
+        consumer
+            .assign(&assignment)
+            .context("Search indexer failed to assign the partitions")
+    }
+
+    /// Commit the changes of the consumer every COMMIT_INTERVAL, a commit per batch would be
+    /// far too slow
+    pub fn spawn_committer(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(COMMIT_INTERVAL);
+            loop {
+                interval.tick().await;
+                if !self.dirty.swap(false, Ordering::AcqRel) {
+                    continue;
+                }
+                let index = self.clone();
+                match tokio::task::spawn_blocking(move || index.commit()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        self.dirty.store(true, Ordering::Release);
+                        tracing::error!("Failed to commit search index: {:#}", e);
+                    }
+                    Err(e) => tracing::error!("Search index commit panicked: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Drop everything and index the messages again, returns how many were read
+    /// `offsets` are the ends of the topic partitions taken before reading the messages, the
+    /// indexer goes on from there
+    pub async fn rebuild(
+        &self,
+        messages: impl Stream<Item = Result<PandaMessage>> + Send,
+        offsets: HashMap<i32, i64>,
+    ) -> Result<usize> {
+        self.writer
+            .lock()
+            .map_err(|_| anyhow!("Poisoned index writer"))?
+            .delete_all_documents()?;
+        *self
+            .offsets
+            .lock()
+            .map_err(|_| anyhow!("Poisoned index offsets"))? = offsets;
+        let mut count = 0;
+        let mut chunks = std::pin::pin!(messages.chunks(1000));
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.into_iter().collect::<Result<Vec<_>>>()?;
+            self.index_messages(&chunk)?;
+            count += chunk.len();
+            tracing::info!("Reindexed {} messages", count);
+        }
+        self.commit()?;
+        Ok(count)
+    }
+
+    pub fn search(&self, filter: &SearchFilter) -> Result<Vec<SearchHit>> {
+        let searcher = self.reader.searcher();
+        let mut parser = QueryParser::for_index(&self.index, vec![self.fields.content]);
+        parser.set_conjunction_by_default();
+        // Whatever is typed, the parts that are not valid syntax are searched as words
+        let (text_query, _) = parser.parse_query_lenient(filter.text);
+
+        let chats = filter
+            .chat_ids
+            .iter()
+            .map(|chat_id| Term::from_field_text(self.fields.chat_id, &chat_id.to_string()));
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![
+            (Occur::Must, text_query.box_clone()),
+            (Occur::Must, Box::new(TermSetQuery::new(chats))),
+        ];
+        if let Some(sender_id) = filter.sender_id {
+            clauses.push((
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.fields.sender_id, &sender_id.to_string()),
+                    IndexRecordOption::Basic,
+                )),
+            ));
+        }
+        if filter.since.is_some() || filter.until.is_some() {
+            let bound = |at: DateTime<Utc>| {
+                Term::from_field_date_for_search(
+                    self.fields.sent_at,
+                    IndexDateTime::from_timestamp_micros(at.timestamp_micros()),
+                )
+            };
+            clauses.push((
+                Occur::Must,
+                Box::new(RangeQuery::new(
+                    filter
+                        .since
+                        .map_or(Bound::Unbounded, |since| Bound::Included(bound(since))),
+                    filter
+                        .until
+                        .map_or(Bound::Unbounded, |until| Bound::Excluded(bound(until))),
+                )),
+            ));
+        }
+        let query = BooleanQuery::new(clauses);
+
+        let top = searcher.search(&query, &TopDocs::with_limit(filter.limit))?;
+        let mut snippets = SnippetGenerator::create(&searcher, &*text_query, self.fields.content)?;
+        snippets.set_max_num_chars(SNIPPET_CHARS);
+        top.into_iter()
+            .map(|(_, address)| {
+                let document: TantivyDocument = searcher.doc(address)?;
+                let uuid = |field: Field| -> Option<Uuid> {
+                    document.get_first(field)?.as_str()?.parse().ok()
+                };
+                let message_id = uuid(self.fields.message_id).context("Hit without id")?;
+                let content = document
+                    .get_first(self.fields.content)
+                    .and_then(|value| value.as_str())
+                    .unwrap_or_default();
+                let mut snippet = snippets.snippet(content);
+                snippet.set_snippet_prefix_postfix("<mark>", "</mark>");
+                Ok(SearchHit {
+                    chat_id: uuid(self.fields.chat_id).context("Hit without chat")?,
+                    message_id,
+                    sender_id: uuid(self.fields.sender_id).context("Hit without sender")?,
+                    parent_id: uuid(self.fields.parent_id),
+                    sent_at: sent_at(message_id),
+                    snippet: snippet_html(content, &snippet),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The fragment stops at its last word, a short message is shown whole
+/// Its single fragment starts at the beginning, what follows it is only punctuation
+fn snippet_html(content: &str, snippet: &Snippet) -> String {
+    let mut html = snippet.to_html();
+    if content.len() <= SNIPPET_CHARS
+        && let Some(rest) = content.strip_prefix(snippet.fragment())
+    {
+        html.push_str(&tera::escape_html(rest));
+    }
+    html
+}
+
+/// The consumer drops an edit stored after the deletion of its message, the index drops it too
+/// The edits of a message deleted in ScyllaDB, from before a restart, are dropped with it
+async fn accepted(
+    messages: Vec<PandaMessage>,
+    db: &dyn Db,
+    deleted: &mut HashMap<Uuid, std::time::Instant>,
+) -> Vec<PandaMessage> {
+    let mut accepted = Vec::with_capacity(messages.len());
+    for message in drop_edits_of_deleted(messages, deleted) {
+        if message.edited_at.is_some() && !message.deleted {
+            match db.get_message(message.chat_id, message.message_id).await {
+                Ok(stored) if stored.deleted => continue,
+                Ok(_) => {}
+                // Not stored yet, or a reply, the consumer takes the edit as well
+                Err(e) => tracing::debug!("Indexing edit of {}: {:#}", message.message_id, e),
+            }
+        }
+        accepted.push(message);
+    }
+    accepted
+}
+
+/// Drops the edits read after the deletion of their message, remembering the deletions
+fn drop_edits_of_deleted(
+    messages: Vec<PandaMessage>,
+    deleted: &mut HashMap<Uuid, std::time::Instant>,
+) -> Vec<PandaMessage> {
+    deleted.retain(|_, at| at.elapsed() < DELETION_MEMORY);
+    messages
+        .into_iter()
+        .filter(|message| {
+            if message.deleted {
+                deleted.insert(message.message_id, std::time::Instant::now());
+                return true;
+            }
+            message.edited_at.is_none() || !deleted.contains_key(&message.message_id)
+        })
+        .collect()
+}
+
+/// When a message was sent, from its time based id
+pub fn sent_at(message_id: Uuid) -> Option<DateTime<Utc>> {
+    let (seconds, nanos) = message_id.get_timestamp()?.to_unix();
+    DateTime::from_timestamp(seconds as i64, nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Timestamp;
+
+    use super::*;
+
+    /// A message sent at that second, ids of the same second differ by their node
+    fn message_at(chat_id: Uuid, sender_id: Uuid, content: &str, seconds: u64) -> PandaMessage {
+        PandaMessage {
+            chat_id,
+            sender_id,
+            content: content.to_string(),
+            message_id: Uuid::new_v1(
+                Timestamp::from_unix(uuid::NoContext, seconds, 0),
+                Uuid::new_v4().as_bytes()[..6].try_into().unwrap(),
+            ),
+            edited_at: None,
+            deleted: false,
+            reactions: Vec::new(),
+            mentions: Vec::new(),
+            attachments: Vec::new(),
+            parent_id: None,
+            reply_count: 0,
+        }
+    }
+
+    fn filter<'a>(text: &'a str, chat_ids: &'a [Uuid]) -> SearchFilter<'a> {
+        SearchFilter {
+            text,
+            chat_ids,
+            sender_id: None,
+            since: None,
+            until: None,
+            limit: 10,
+        }
+    }
+
+    #[test]
+    fn test_search_stays_in_the_given_chats_and_highlights() {
+        let index = SearchIndex::in_memory().unwrap();
+        let (mine, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let sender_id = Uuid::new_v4();
+        index
+            .index_messages(&[
+                message_at(mine, sender_id, "Lunch at the <b>Café</b>?", 1_000),
+                message_at(other, sender_id, "cafe in the other chat", 1_000),
+                message_at(mine, sender_id, "unrelated", 1_000),
+            ])
+            .unwrap();
+        index.commit().unwrap();
+
+        let hits = index.search(&filter("cafe", &[mine])).unwrap();
+
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].chat_id, mine);
+        assert_eq!(
+            hits[0].snippet,
+            "Lunch at the &lt;b&gt;<mark>Café</mark>&lt;/b&gt;?"
+        );
+    }
+
+    #[test]
+    fn test_edits_replace_and_deletions_remove() {
+        let index = SearchIndex::in_memory().unwrap();
+        let chat_id = Uuid::new_v4();
+        let original = message_at(chat_id, Uuid::new_v4(), "see you tomorow", 1_000);
+        let kept = message_at(chat_id, Uuid::new_v4(), "see you soon", 1_000);
+        index
+            .index_messages(&[original.clone(), kept.clone()])
+            .unwrap();
+        let edit = PandaMessage {
+            content: "see you tomorrow".to_string(),
+            edited_at: Some(Utc::now()),
+            ..original.clone()
+        };
+        let deletion = PandaMessage {
+            content: String::new(),
+            deleted: true,
+            ..kept
+        };
+        index.index_messages(&[edit, deletion]).unwrap();
+        index.commit().unwrap();
+
+        assert!(
+            index
+                .search(&filter("tomorow", &[chat_id]))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            index
+                .search(&filter("soon", &[chat_id]))
+                .unwrap()
+                .is_empty()
+        );
+        let hits = index.search(&filter("tomorrow", &[chat_id])).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].message_id, original.message_id);
+    }
+
+    #[test]
+    fn test_sender_and_date_filters() {
+        let index = SearchIndex::in_memory().unwrap();
+        let chat_id = Uuid::new_v4();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let day = 24 * 60 * 60;
+        let old = message_at(chat_id, alice, "deploy done", 10 * day);
+        let recent = message_at(chat_id, alice, "deploy done again", 20 * day);
+        let from_bob = message_at(chat_id, bob, "deploy failed", 20 * day);
+        index
+            .index_messages(&[old.clone(), recent.clone(), from_bob])
+            .unwrap();
+        index.commit().unwrap();
+        let chats = [chat_id];
+
+        let hits = index
+            .search(&SearchFilter {
+                sender_id: Some(alice),
+                since: DateTime::from_timestamp(15 * day as i64, 0),
+                ..filter("deploy", &chats)
+            })
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].message_id, recent.message_id);
+        assert_eq!(
+            hits[0].sent_at,
+            DateTime::from_timestamp(20 * day as i64, 0)
+        );
+
+        let hits = index
+            .search(&SearchFilter {
+                until: DateTime::from_timestamp(15 * day as i64, 0),
+                ..filter("deploy", &chats)
+            })
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].message_id, old.message_id);
+    }
+
+    #[test]
+    fn test_topic_offsets_survive_a_restart() {
+        let search = SearchIndex::in_memory().unwrap();
+        let message = message_at(Uuid::new_v4(), Uuid::new_v4(), "hello", 1_000);
+        search
+            .index_records(&[message], HashMap::from([(0, 42), (1, 7)]))
+            .unwrap();
+        search.commit().unwrap();
+
+        let index = search.index.clone();
+        drop(search);
+        let reopened = SearchIndex::with_index(index).unwrap();
+
+        let offsets = reopened.offsets.lock().unwrap().clone();
+        assert_eq!(offsets, HashMap::from([(0, 42), (1, 7)]));
+    }
+
+    #[test]
+    fn test_edits_read_after_the_deletion_are_dropped() {
+        let original = message_at(Uuid::new_v4(), Uuid::new_v4(), "oops", 1_000);
+        let deletion = PandaMessage {
+            content: String::new(),
+            deleted: true,
+            ..original.clone()
+        };
+        let late_edit = PandaMessage {
+            content: "oops, fixed".to_string(),
+            edited_at: Some(Utc::now()),
+            ..original.clone()
+        };
+        let mut deleted = HashMap::new();
+
+        let kept = drop_edits_of_deleted(vec![original, deletion], &mut deleted);
+        assert_eq!(kept.len(), 2);
+        // In a later chunk, as when the edit lost the race in ScyllaDB
+        assert!(drop_edits_of_deleted(vec![late_edit], &mut deleted).is_empty());
+    }
+}
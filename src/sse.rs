@@ -0,0 +1,159 @@
+// Server-Sent Events, the fallback of the clients whose proxy blocks websocket upgrades
+// They register in the same ConnectionMap as the websockets and share their replay and lag handling
+
+use std::{collections::HashSet, convert::Infallible};
+
+use axum::response::sse::Event;
+use futures_util::{Stream, StreamExt, stream};
+use metrics::counter;
+use tokio::{
+    sync::mpsc::{Receiver, channel},
+    time::Interval,
+};
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    auth::CurrentUser,
+    schema::{ConnectQuery, PandaMessage, WireFormat},
+    websocket::{
+        Connection, Delivery, SESSION_CHECK_INTERVAL, missed_messages, register, render_message,
+        render_reactions, render_read_receipt, render_resync, render_typing, unregister,
+    },
+};
+
+/// Live messages of the user, after the ones missed since `last_seen` (or `Last-Event-ID`)
+pub async fn event_stream(
+    state: AppState,
+    current_user: CurrentUser,
+    query: ConnectQuery,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let user_id = current_user.user_id;
+    let connection_id = Uuid::new_v4();
+    let (sender, receiver) = channel(state.slow_clients.buffer);
+    let connection = Connection::new(sender);
+    connection.follow_thread(query.thread_id);
+    // Registered before reading the database, like the websockets, so nothing falls in between
+    register(&state, user_id, connection_id, connection.clone()).await;
+
+    let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+    session_check.tick().await;
+    let mut listener = Listener {
+        state,
+        current_user,
+        connection_id,
+        connection,
+        receiver,
+        query,
+        replayed: HashSet::new(),
+        session_check,
+    };
+
+    let mut replay = Vec::new();
+    if let Some(last_seen) = query.last_seen {
+        let state = &listener.state;
+        match missed_messages(state, user_id, last_seen, query.chat_id).await {
+            Ok(missed) => {
+                counter!("sse_replayed_messages_total").increment(missed.len() as u64);
+                for msg in missed {
+                    listener.replayed.insert(msg.message_id);
+                    replay.extend(message_event(state, query.format, &msg, user_id, true));
+                }
+            }
+            Err(e) => tracing::error!("Failed to replay messages of user {}: {:#}", user_id, e),
+        }
+    }
+
+    let live = stream::unfold(listener, |mut listener| async move {
+        let event = listener.next_event().await?;
+        Some((Ok(event), listener))
+    });
+    stream::iter(replay.into_iter().map(Ok)).chain(live)
+}
+
+struct Listener {
+    state: AppState,
+    current_user: CurrentUser,
+    connection_id: Uuid,
+    connection: Connection,
+    receiver: Receiver<Delivery>,
+    query: ConnectQuery,
+    replayed: HashSet<Uuid>,
+    session_check: Interval,
+}
+
+impl Listener {
+    /// `None` ends the stream: the session is over or the client is too slow
+    async fn next_event(&mut self) -> Option<Event> {
+        let user_id = self.current_user.user_id;
+        loop {
+            tokio::select! {
+                delivery = self.receiver.recv() => {
+                    let delivery = delivery?;
+                    if self.query.chat_id.is_some_and(|chat_id| chat_id != delivery.chat_id()) {
+                        continue;
+                    }
+                    let format = self.query.format;
+                    let event = match delivery {
+                        Delivery::Message(msg) => {
+                            // Already sent by the replay, edits and deletes still go through
+                            if !msg.is_change() && self.replayed.remove(&msg.message_id) {
+                                continue;
+                            }
+                            message_event(&self.state, format, &msg, user_id, false)
+                        }
+                        Delivery::Reactions(reactions) => {
+                            render_reactions(&self.state, format, &reactions, user_id)
+                                .map(|data| Event::default().event("reactions").data(data))
+                        }
+                        Delivery::Typing(typing) => render_typing(&self.state, format, &typing)
+                            .map(|data| Event::default().event("typing").data(data)),
+                        Delivery::ReadReceipt(receipt) => {
+                            render_read_receipt(&self.state, format, &receipt)
+                                .map(|data| Event::default().event("read_receipt").data(data))
+                        }
+                    };
+                    if let Some(event) = event {
+                        return Some(event);
+                    }
+                }
+                lagged = self.connection.lagged(&self.state.slow_clients) => {
+                    lagged?;
+                    let resync = render_resync(&self.state, self.query.format);
+                    return Some(Event::default().event("resync").data(resync));
+                }
+                _ = self.session_check.tick() => {
+                    if !CurrentUser::is_session_active(&self.state, self.current_user.session_id).await {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The stream is dropped when the client goes away, which is when it leaves the fan-out
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        let (user_id, connection_id) = (self.current_user.user_id, self.connection_id);
+        tokio::spawn(async move { unregister(&state, user_id, connection_id).await });
+    }
+}
+
+/// New messages carry their timeuuid as event id, so a reconnecting EventSource resumes after it
+/// Edits and deletes do not, resuming from an old message would replay what the client has
+fn message_event(
+    state: &AppState,
+    format: WireFormat,
+    msg: &PandaMessage,
+    user_id: Uuid,
+    replay: bool,
+) -> Option<Event> {
+    let data = render_message(state, format, msg, user_id, replay)?;
+    let event = Event::default().event("message").data(data);
+    if replay || !msg.is_change() {
+        return Some(event.id(msg.message_id.to_string()));
+    }
+    Some(event)
+}
@@ -0,0 +1,322 @@
+// Where the bytes of the attachments live, ScyllaDB only keeps their metadata
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use bytes::Bytes;
+#[cfg(test)]
+use mockall::automock;
+use object_store::{
+    Attribute, Attributes, ObjectStore, PutOptions, PutPayload,
+    aws::{AmazonS3, AmazonS3Builder},
+    path::Path as ObjectPath,
+};
+use uuid::Uuid;
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, bytes: Bytes, content_type: &str) -> Result<()>;
+    /// `None` when nothing is stored under the key
+    async fn get(&self, key: &str) -> Result<Option<Bytes>>;
+    /// Deleting a missing key is not an error
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Key of the bytes of an attachment, the chat comes first so a chat can be listed or purged
+pub fn attachment_key(chat_id: Uuid, attachment_id: Uuid) -> String {
+    format!("{}/{}", chat_id, attachment_id)
+}
+
+pub fn thumbnail_key(chat_id: Uuid, attachment_id: Uuid) -> String {
+    format!("{}/{}.thumbnail", chat_id, attachment_id)
+}
+
+/// Files under a root directory, one per key
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Keys are ours, but a key must never get out of the root
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("Invalid storage key {}", key);
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl Storage for FsStorage {
+    async fn put(&self, key: &str, bytes: Bytes, _content_type: &str) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .context("Failed to create storage directory")?;
+        }
+        // Written aside then renamed, a reader never sees half a file
+        let partial = path.with_extension(format!("{}.partial", Uuid::new_v4()));
+        tokio::fs::write(&partial, &bytes)
+            .await
+            .context("Failed to write attachment")?;
+        tokio::fs::rename(&partial, &path)
+            .await
+            .context("Failed to move attachment in place")?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(bytes) => Ok(Some(Bytes::from(bytes))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("Failed to read attachment"),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).context("Failed to delete attachment")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Settings of an S3 compatible bucket (AWS, MinIO, Garage...)
+pub struct S3Config {
+    pub bucket: String,
+    /// Unset for AWS itself, the URL of the server otherwise
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl S3Config {
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            bucket: std::env::var("S3_BUCKET")
+                .context("S3_BUCKET is required by the s3 storage")?,
+            endpoint: std::env::var("S3_ENDPOINT").ok(),
+            region: std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            access_key_id: std::env::var("S3_ACCESS_KEY_ID").unwrap_or_default(),
+            secret_access_key: std::env::var("S3_SECRET_ACCESS_KEY").unwrap_or_default(),
+        })
+    }
+}
+
+/// Objects of an S3 compatible bucket, one per key
+pub struct S3Storage {
+    store: AmazonS3,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> Result<Self> {
+        let mut builder = AmazonS3Builder::new()
+            .with_bucket_name(&config.bucket)
+            .with_region(&config.region)
+            .with_access_key_id(&config.access_key_id)
+            .with_secret_access_key(&config.secret_access_key);
+        if let Some(endpoint) = &config.endpoint {
+            // Self hosted servers are reached by path and often without TLS
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_virtual_hosted_style_request(false)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+        let store = builder.build().context("Failed to configure S3 storage")?;
+        Ok(Self { store })
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, bytes: Bytes, content_type: &str) -> Result<()> {
+        let mut attributes = Attributes::new();
+        attributes.insert(Attribute::ContentType, content_type.to_string().into());
+        self.store
+            .put_opts(
+                &ObjectPath::from(key),
+                PutPayload::from(bytes),
+                PutOptions {
+                    attributes,
+                    ..Default::default()
+                },
+            )
+            .await
+            .context("Failed to upload attachment")?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        match self.store.get(&ObjectPath::from(key)).await {
+            Ok(object) => Ok(Some(
+                object
+                    .bytes()
+                    .await
+                    .context("Failed to download attachment")?,
+            )),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e).context("Failed to download attachment"),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match self.store.delete(&ObjectPath::from(key)).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e).context("Failed to delete attachment"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        Router,
+        body::Bytes,
+        extract::{Path as UrlPath, State},
+        http::{HeaderMap, StatusCode, header},
+        response::IntoResponse,
+        routing::put,
+    };
+
+    use super::*;
+
+    type Objects = Arc<Mutex<HashMap<String, (Bytes, String)>>>;
+
+    /// Bare bones stand-in for a MinIO server: path style buckets, no auth, no listing
+    async fn spawn_s3_stand_in() -> (String, Objects) {
+        let objects: Objects = Arc::default();
+        async fn put_object(
+            State(objects): State<Objects>,
+            UrlPath((bucket, key)): UrlPath<(String, String)>,
+            headers: HeaderMap,
+            body: Bytes,
+        ) -> impl IntoResponse {
+            let content_type = headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            objects
+                .lock()
+                .unwrap()
+                .insert(format!("{}/{}", bucket, key), (body, content_type));
+            [(header::ETAG, "\"etag\"")]
+        }
+        async fn get_object(
+            State(objects): State<Objects>,
+            UrlPath((bucket, key)): UrlPath<(String, String)>,
+        ) -> impl IntoResponse {
+            match objects.lock().unwrap().get(&format!("{}/{}", bucket, key)) {
+                Some((body, _)) => (StatusCode::OK, body.clone()).into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+        async fn delete_object(
+            State(objects): State<Objects>,
+            UrlPath((bucket, key)): UrlPath<(String, String)>,
+        ) -> StatusCode {
+            objects
+                .lock()
+                .unwrap()
+                .remove(&format!("{}/{}", bucket, key));
+            StatusCode::NO_CONTENT
+        }
+        let app = Router::new()
+            .route(
+                "/{bucket}/{*key}",
+                put(put_object).get(get_object).delete(delete_object),
+            )
+            .with_state(objects.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}", address), objects)
+    }
+
+    #[tokio::test]
+    async fn test_fs_storage_round_trip() {
+        let root = std::env::temp_dir().join(format!("chat-app-storage-{}", Uuid::new_v4()));
+        let storage = FsStorage::new(&root);
+        let key = attachment_key(Uuid::new_v4(), Uuid::new_v4());
+
+        storage
+            .put(&key, Bytes::from_static(b"hello"), "text/plain")
+            .await
+            .unwrap();
+        let stored = storage.get(&key).await.unwrap();
+        storage.delete(&key).await.unwrap();
+        let deleted = storage.get(&key).await.unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(stored.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(deleted, None);
+        // Deleting twice is fine
+        assert!(storage.delete(&key).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_fs_storage_keys_stay_in_the_root() {
+        let storage = FsStorage::new(std::env::temp_dir());
+
+        assert!(storage.get("../etc/passwd").await.is_err());
+        assert!(storage.get("/etc/passwd").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_s3_storage_against_a_stand_in() {
+        let (endpoint, objects) = spawn_s3_stand_in().await;
+        let storage = S3Storage::new(&S3Config {
+            bucket: "attachments".to_string(),
+            endpoint: Some(endpoint),
+            region: "us-east-1".to_string(),
+            access_key_id: "minio".to_string(),
+            secret_access_key: "minio-secret".to_string(),
+        })
+        .unwrap();
+        let chat_id = Uuid::new_v4();
+        let attachment_id = Uuid::new_v4();
+        let key = attachment_key(chat_id, attachment_id);
+
+        storage
+            .put(&key, Bytes::from_static(b"\x89PNG"), "image/png")
+            .await
+            .unwrap();
+
+        let stored = objects
+            .lock()
+            .unwrap()
+            .get(&format!("attachments/{}/{}", chat_id, attachment_id))
+            .cloned();
+        assert_eq!(
+            stored,
+            Some((Bytes::from_static(b"\x89PNG"), "image/png".to_string()))
+        );
+        assert_eq!(
+            storage.get(&key).await.unwrap().as_deref(),
+            Some(&b"\x89PNG"[..])
+        );
+        storage.delete(&key).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap(), None);
+    }
+}
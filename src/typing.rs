@@ -0,0 +1,69 @@
+use std::sync::Mutex;
+
+use chrono::TimeDelta;
+use dashmap::{DashMap, mapref::entry::Entry};
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How long an indicator stays up without a new signal from the same member
+pub const TYPING_TTL: TimeDelta = TimeDelta::seconds(5);
+// A member typing keeps sending, one signal per chat in this window is enough to keep it up
+const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+
+/// Last signal relayed for each (chat, member), so that typing fast does not flood the topic
+pub struct TypingThrottle {
+    last_relayed: DashMap<(Uuid, Uuid), Instant>,
+    // Usernames never change, the ones of the members who typed sign their signals without a read
+    usernames: DashMap<Uuid, String>,
+    // Entries out of the throttle window are dropped at most once per window
+    last_pruned: Mutex<Instant>,
+}
+
+impl Default for TypingThrottle {
+    fn default() -> Self {
+        Self {
+            last_relayed: DashMap::new(),
+            usernames: DashMap::new(),
+            last_pruned: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl TypingThrottle {
+    /// Whether this signal should be relayed, the ones in the throttle window are dropped
+    pub fn allow(&self, chat_id: Uuid, user_id: Uuid) -> bool {
+        self.prune();
+        match self.last_relayed.entry((chat_id, user_id)) {
+            Entry::Occupied(mut last) => {
+                if last.get().elapsed() < TYPING_THROTTLE {
+                    return false;
+                }
+                last.insert(Instant::now());
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Instant::now());
+            }
+        }
+        true
+    }
+
+    pub fn username(&self, user_id: Uuid) -> Option<String> {
+        self.usernames
+            .get(&user_id)
+            .map(|username| username.clone())
+    }
+
+    pub fn remember_username(&self, user_id: Uuid, username: &str) {
+        self.usernames.insert(user_id, username.to_string());
+    }
+
+    fn prune(&self) {
+        let mut last_pruned = self.last_pruned.lock().expect("typing prune lock poisoned");
+        if last_pruned.elapsed() < TYPING_THROTTLE {
+            return;
+        }
+        *last_pruned = Instant::now();
+        self.last_relayed
+            .retain(|_, relayed| relayed.elapsed() < TYPING_THROTTLE);
+    }
+}
@@ -1,36 +1,343 @@
 // TODO : Impl a trait to just send a message
 
-use axum::extract::ws::{self, WebSocket};
+use std::{
+    collections::HashSet,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::ws::{self, CloseFrame, WebSocket, close_code},
+    response::IntoResponse,
+};
 use futures_util::{sink::SinkExt, stream::StreamExt};
-use metrics::gauge;
-use tokio::sync::mpsc::channel;
+use metrics::{counter, gauge, histogram};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    Notify,
+    mpsc::{Sender, channel, error::TrySendError},
+};
 use uuid::Uuid;
 
-use crate::AppState;
+use crate::{
+    AppState, UserConnections,
+    auth::CurrentUser,
+    handler::{AppError, require_member, send_chat_message, signal_typing},
+    presence::Status,
+    schema::{ConnectQuery, MessageReactions, PandaMessage, ReadReceipt, Typing, WireFormat},
+    typing::TYPING_TTL,
+};
+
+/// Subprotocol of the JSON clients, the browser uses none and gets htmx fragments
+pub const JSON_SUBPROTOCOL: &str = "panda.json.v1";
+
+// How often an open socket checks that its session was not revoked or expired
+pub(crate) const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// Messages fetched per query when replaying, and at most per chat
+// A client further behind than that reloads the page to get the rest
+const REPLAY_PAGE_SIZE: i32 = 200;
+const MAX_REPLAY: usize = 1000;
+
+/// Frames sent by the client, JSON objects tagged by `type`
+/// htmx `ws-send` sends the fields of the form this way, its extra `HEADERS` key is ignored
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    Send {
+        chat_id: Uuid,
+        content: String,
+        /// Set to reply in a thread
+        #[serde(default)]
+        parent_id: Option<Uuid>,
+    },
+    Typing {
+        chat_id: Uuid,
+    },
+    /// The newest message the client has shown, a lagging client resumes after it
+    Ack {
+        message_id: Uuid,
+    },
+    Subscribe {
+        chat_id: Uuid,
+    },
+    /// Sent by the page when it goes to the background and comes back
+    Presence {
+        status: Status,
+    },
+    /// Follow the replies of a thread, or stop following with `null`
+    Thread {
+        thread_id: Option<Uuid>,
+    },
+}
+
+/// Frames sent to JSON clients, the counterpart of the htmx partials
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerFrame<'a> {
+    MessagePosted {
+        message: &'a PandaMessage,
+    },
+    MessageEdited {
+        message: &'a PandaMessage,
+    },
+    MessageDeleted {
+        message: &'a PandaMessage,
+    },
+    ReactionsUpdated {
+        #[serde(flatten)]
+        reactions: &'a MessageReactions,
+    },
+    Typing {
+        #[serde(flatten)]
+        typing: &'a Typing,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    },
+    ReadReceipt {
+        #[serde(flatten)]
+        receipt: &'a ReadReceipt,
+    },
+    Error {
+        error: &'a str,
+    },
+    ResyncRequired,
+}
+
+/// Chats a connection asked for, empty means every chat of the user
+type Subscriptions = Arc<Mutex<HashSet<Uuid>>>;
+
+/// What to do with a client that reads its messages slower than they arrive
+#[derive(Clone, Copy, Debug)]
+pub struct SlowClientPolicy {
+    /// Messages buffered per connection before dropping them
+    pub buffer: usize,
+    /// Close the connection once it dropped that many messages, `None` keeps it open
+    pub max_dropped: Option<u64>,
+}
+
+impl SlowClientPolicy {
+    fn disconnects(&self, dropped: u64) -> bool {
+        self.max_dropped.is_some_and(|max| dropped >= max)
+    }
+}
+
+impl Default for SlowClientPolicy {
+    fn default() -> Self {
+        Self {
+            buffer: 8192,
+            max_dropped: None,
+        }
+    }
+}
+
+/// Liveness check of the connections, half-open sockets would otherwise stay registered forever
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    /// How often the server pings the client
+    pub interval: Duration,
+    /// How long after a ping the client has to answer, any frame counts as an answer
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(20),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What the consumer hands to a connection
+#[derive(Clone, Debug)]
+pub enum Delivery {
+    Message(PandaMessage),
+    Reactions(MessageReactions),
+    Typing(Typing),
+    ReadReceipt(ReadReceipt),
+}
+
+impl Delivery {
+    pub(crate) fn chat_id(&self) -> Uuid {
+        match self {
+            Delivery::Message(message) => message.chat_id,
+            Delivery::Reactions(reactions) => reactions.chat_id,
+            Delivery::Typing(typing) => typing.chat_id,
+            Delivery::ReadReceipt(receipt) => receipt.chat_id,
+        }
+    }
+}
+
+impl From<PandaMessage> for Delivery {
+    fn from(message: PandaMessage) -> Self {
+        Delivery::Message(message)
+    }
+}
+
+/// Sending side of one websocket, what the consumer sees in the ConnectionMap
+#[derive(Clone)]
+pub struct Connection {
+    pub sender: Sender<Delivery>,
+    lag: Arc<Lag>,
+    away: Arc<AtomicBool>,
+    thread: Arc<Mutex<Option<Uuid>>>,
+}
+
+#[derive(Default)]
+struct Lag {
+    // Set by the first message dropped since the client was last asked to resync
+    lagging: AtomicBool,
+    dropped: AtomicU64,
+    notify: Notify,
+}
+
+impl Connection {
+    pub(crate) fn new(sender: Sender<Delivery>) -> Self {
+        Self {
+            sender,
+            lag: Arc::default(),
+            away: Arc::default(),
+            thread: Arc::default(),
+        }
+    }
+
+    /// The thread open on the client, the only one whose replies it gets
+    pub(crate) fn follow_thread(&self, thread_id: Option<Uuid>) {
+        *self.thread.lock().expect("thread lock poisoned") = thread_id;
+    }
+
+    pub fn follows_thread(&self, thread_id: Uuid) -> bool {
+        *self.thread.lock().expect("thread lock poisoned") == Some(thread_id)
+    }
+
+    /// Never waits for a slow client: when its buffer is full the message is dropped
+    /// and the connection is marked lagging, its send task then asks the client to resync
+    /// Typing signals and read receipts are only dropped, missing one does not call for a resync
+    pub fn deliver(&self, delivery: impl Into<Delivery>) {
+        match self.sender.try_send(delivery.into()) {
+            // Closed: the connection is being unregistered
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(Delivery::Typing(_) | Delivery::ReadReceipt(_))) => {}
+            Err(TrySendError::Full(Delivery::Message(_) | Delivery::Reactions(_))) => {
+                counter!("websocket_messages_dropped_total").increment(1);
+                self.lag.dropped.fetch_add(1, Ordering::Relaxed);
+                if !self.lag.lagging.swap(true, Ordering::Relaxed) {
+                    self.lag.notify.notify_one();
+                }
+            }
+        }
+    }
+
+    /// Waits for the first message dropped since the last resync
+    /// `None` when the client is dropping too many messages and has to be disconnected
+    pub(crate) async fn lagged(&self, policy: &SlowClientPolicy) -> Option<()> {
+        self.lag.notify.notified().await;
+        let dropped = self.lag.dropped.load(Ordering::Relaxed);
+        if policy.disconnects(dropped) {
+            tracing::warn!("Disconnecting a client after {} dropped messages", dropped);
+            counter!("websocket_slow_disconnects_total").increment(1);
+            return None;
+        }
+        tracing::warn!("Client is lagging, {} messages dropped", dropped);
+        counter!("websocket_resyncs_total").increment(1);
+        // Dropping again after this asks for another resync
+        self.lag.lagging.store(false, Ordering::Relaxed);
+        Some(())
+    }
+}
+
 /// The handler recieve message from the user_id associated mpsc::Sender
-/// It writes them on the tcp socket
-pub async fn handle_socket(socket: WebSocket, state: AppState, user_id: Uuid) {
-    gauge!("active_websocket_users").increment(1.0);
+/// It writes them on the tcp socket, after replaying what the client missed since `last_seen`
+pub async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    current_user: CurrentUser,
+    query: ConnectQuery,
+) {
+    let user_id = current_user.user_id;
+    let format = query.format;
+    let connection_id = Uuid::new_v4();
     let (mut socket_sender, mut socket_reciever) = socket.split();
 
-    // Insert a sender for redpanda topic to give messages
-    let (channel_sender, mut channel_receiver) = channel(8192);
-    state
-        .connections_map
-        .write()
-        .await
-        .insert(user_id, channel_sender);
+    // Insert a sender for redpanda topic to give messages, next to the other tabs of the user
+    let (channel_sender, mut channel_receiver) = channel(state.slow_clients.buffer);
+    let connection = Connection::new(channel_sender);
+    connection.follow_thread(query.thread_id);
+    register(&state, user_id, connection_id, connection.clone()).await;
+
+    // Replies to the client frames, written by the send task as it owns the socket sender
+    let (reply_sender, mut reply_receiver) = channel::<String>(16);
+    let subscriptions: Subscriptions = Arc::new(Mutex::new(query.chat_id.into_iter().collect()));
 
     // Spawn a task to receive the messages and send them over websocket
-    let tera = state.tera.clone();
-    let send_task = tokio::spawn(async move {
-        while let Some(msg) = channel_receiver.recv().await {
-            let mut context = tera::Context::new();
-            context.insert("message", &msg);
-            context.insert("user_id", &user_id);
-
-            match tera.render("partials/message.html", &context) {
-                Ok(rendered) => {
+    // It also owns the session check, as it is the one able to send the close frame
+    let task_state = state.clone();
+    let task_subscriptions = subscriptions.clone();
+    // The newest message the client acknowledged, where to resume from when it lags
+    let last_ack: Arc<Mutex<Option<Uuid>>> = Arc::new(Mutex::new(None));
+    let task_last_ack = last_ack.clone();
+    let mut send_task = tokio::spawn(async move {
+        let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+        // The first tick is immediate and the session was just validated by the upgrade
+        session_check.tick().await;
+        let mut ping = tokio::time::interval(task_state.heartbeat.interval);
+        ping.tick().await;
+
+        // The connection is registered before reading the database and the consumer stores a
+        // batch before broadcasting it: a message is either replayed, buffered in the channel, or both
+        let mut replayed = HashSet::new();
+        if let Some(last_seen) = query.last_seen {
+            let missed = match missed_messages(&task_state, user_id, last_seen, query.chat_id).await
+            {
+                Ok(missed) => missed,
+                Err(e) => {
+                    tracing::error!("Failed to replay messages of user {}: {:#}", user_id, e);
+                    Vec::new()
+                }
+            };
+            counter!("websocket_replayed_messages_total").increment(missed.len() as u64);
+            for msg in missed {
+                replayed.insert(msg.message_id);
+                let Some(rendered) = render_message(&task_state, format, &msg, user_id, true)
+                else {
+                    continue;
+                };
+                if socket_sender
+                    .send(ws::Message::Text(rendered.into()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+
+        loop {
+            tokio::select! {
+                delivery = channel_receiver.recv() => {
+                    let Some(delivery) = delivery else { break };
+                    if !is_subscribed(&task_subscriptions, delivery.chat_id()) {
+                        continue;
+                    }
+                    let rendered = match delivery {
+                        Delivery::Message(msg) => {
+                            // Already sent by the replay, edits and deletes still go through
+                            if !msg.is_change() && replayed.remove(&msg.message_id) {
+                                continue;
+                            }
+                            render_message(&task_state, format, &msg, user_id, false)
+                        }
+                        Delivery::Reactions(reactions) => {
+                            render_reactions(&task_state, format, &reactions, user_id)
+                        }
+                        Delivery::Typing(typing) => render_typing(&task_state, format, &typing),
+                        Delivery::ReadReceipt(receipt) => {
+                            render_read_receipt(&task_state, format, &receipt)
+                        }
+                    };
+                    let Some(rendered) = rendered else { continue };
                     if socket_sender
                         .send(ws::Message::Text(rendered.into()))
                         .await
@@ -39,22 +346,412 @@ pub async fn handle_socket(socket: WebSocket, state: AppState, user_id: Uuid) {
                         break; // Client disconnected
                     }
                 }
-                Err(e) => {
-                    tracing::error!("Template rendering failed: {}", e);
+                lagged = connection.lagged(&task_state.slow_clients) => {
+                    if lagged.is_none() {
+                        let _ = socket_sender
+                            .send(ws::Message::Close(Some(CloseFrame {
+                                code: close_code::POLICY,
+                                reason: "Too slow".into(),
+                            })))
+                            .await;
+                        break;
+                    }
+                    // Clients that ack get what they missed since their last ack, the others resync
+                    let acked = *task_last_ack.lock().expect("ack lock poisoned");
+                    let missed = match acked {
+                        Some(acked) => missed_messages(&task_state, user_id, acked, query.chat_id)
+                            .await
+                            .inspect_err(|e| {
+                                tracing::error!("Failed to resume user {} from its ack: {:#}", user_id, e)
+                            })
+                            .ok(),
+                        None => None,
+                    };
+                    let Some(missed) = missed else {
+                        let resync = render_resync(&task_state, format);
+                        if socket_sender.send(ws::Message::Text(resync.into())).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    };
+                    counter!("websocket_replayed_messages_total").increment(missed.len() as u64);
+                    for msg in missed {
+                        if !is_subscribed(&task_subscriptions, msg.chat_id) {
+                            continue;
+                        }
+                        replayed.insert(msg.message_id);
+                        let Some(rendered) = render_message(&task_state, format, &msg, user_id, true)
+                        else {
+                            continue;
+                        };
+                        if socket_sender.send(ws::Message::Text(rendered.into())).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                reply = reply_receiver.recv() => {
+                    let Some(reply) = reply else { break };
+                    if socket_sender.send(ws::Message::Text(reply.into())).await.is_err() {
+                        break;
+                    }
+                }
+                _ = ping.tick() => {
+                    if socket_sender.send(ws::Message::Ping(Default::default())).await.is_err() {
+                        break;
+                    }
+                }
+                _ = session_check.tick() => {
+                    if !CurrentUser::is_session_active(&task_state, current_user.session_id).await {
+                        tracing::info!("Session of user {} ended, closing websocket", user_id);
+                        let _ = socket_sender
+                            .send(ws::Message::Close(Some(CloseFrame {
+                                code: close_code::POLICY,
+                                reason: "Session expired".into(),
+                            })))
+                            .await;
+                        break;
+                    }
                 }
             }
         }
     });
 
-    // Wait until the client close the websocket
-    while let Some(Ok(msg)) = socket_reciever.next().await {
-        if let ws::Message::Close(_) = msg {
-            break;
+    // Handle the client frames until it closes the websocket, the session is revoked
+    // or the client stops answering the pings
+    let heartbeat = state.heartbeat;
+    let alive_for = heartbeat.interval + heartbeat.timeout;
+    let deadline = tokio::time::sleep(alive_for);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            msg = socket_reciever.next() => {
+                deadline.as_mut().reset(tokio::time::Instant::now() + alive_for);
+                match msg {
+                    Some(Ok(ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(ws::Message::Text(text))) => {
+                        let handled = handle_frame(&state, user_id, connection_id, &text, &subscriptions, &last_ack).await;
+                        if let Err(error) = handled
+                            && reply_sender.send(render_error(&state, format, &error)).await.is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                }
+            }
+            _ = &mut deadline => {
+                tracing::info!("Websocket of user {} stopped answering pings, closing it", user_id);
+                counter!("websocket_heartbeat_timeouts_total").increment(1);
+                break;
+            }
+            _ = &mut send_task => break,
         }
     }
 
     send_task.abort();
+    tracing::debug!(
+        "Websocket of user {} closed, last acknowledged message {:?}",
+        user_id,
+        *last_ack.lock().expect("ack lock poisoned")
+    );
+
+    unregister(&state, user_id, connection_id).await;
+}
+
+/// Messages newer than `last_seen` in the chats of the user, oldest first within each chat
+/// Only new rows are replayed, edits to older messages made meanwhile are not
+pub(crate) async fn missed_messages(
+    state: &AppState,
+    user_id: Uuid,
+    last_seen: Uuid,
+    chat_id: Option<Uuid>,
+) -> anyhow::Result<Vec<PandaMessage>> {
+    let chat_ids = match chat_id {
+        Some(chat_id) => vec![chat_id],
+        None => state
+            .db
+            .get_chats_for_user(user_id)
+            .await?
+            .into_iter()
+            .map(|chat| chat.chat_id)
+            .collect(),
+    };
+
+    let mut missed = Vec::new();
+    for chat_id in chat_ids {
+        let mut after = last_seen;
+        let mut count = 0;
+        loop {
+            let page = state
+                .db
+                .get_messages_after(chat_id, after, REPLAY_PAGE_SIZE)
+                .await?;
+            let Some(last) = page.last() else { break };
+            after = last.message_id;
+            let full = page.len() == REPLAY_PAGE_SIZE as usize;
+            count += page.len();
+            missed.extend(page);
+            if !full {
+                break;
+            }
+            if count >= MAX_REPLAY {
+                tracing::warn!(
+                    "User {} missed more than {} messages in chat {}, replay truncated",
+                    user_id,
+                    MAX_REPLAY,
+                    chat_id
+                );
+                break;
+            }
+        }
+    }
+    Ok(missed)
+}
+
+/// Replayed messages are new to the client even when edited since, so they are always appended
+pub(crate) fn render_message(
+    state: &AppState,
+    format: WireFormat,
+    msg: &PandaMessage,
+    user_id: Uuid,
+    replay: bool,
+) -> Option<String> {
+    if format == WireFormat::Json {
+        let frame = if replay || !msg.is_change() {
+            ServerFrame::MessagePosted { message: msg }
+        } else if msg.deleted {
+            ServerFrame::MessageDeleted { message: msg }
+        } else {
+            ServerFrame::MessageEdited { message: msg }
+        };
+        return Some(encode(&frame));
+    }
+    let mut context = tera::Context::new();
+    context.insert("message", msg);
+    context.insert("user_id", &user_id);
+    context.insert("replay", &replay);
+    state
+        .tera
+        .render("partials/message.html", &context)
+        .inspect_err(|e| tracing::error!("Template rendering failed: {}", e))
+        .ok()
+}
+
+/// Replaces the reactions under the message, highlighting the ones of the viewer
+pub(crate) fn render_reactions(
+    state: &AppState,
+    format: WireFormat,
+    reactions: &MessageReactions,
+    user_id: Uuid,
+) -> Option<String> {
+    if format == WireFormat::Json {
+        return Some(encode(&ServerFrame::ReactionsUpdated { reactions }));
+    }
+    let mut context = tera::Context::new();
+    context.insert("message", reactions);
+    context.insert("user_id", &user_id);
+    context.insert("reactions_oob", &true);
+    state
+        .tera
+        .render("partials/reactions.html", &context)
+        .inspect_err(|e| tracing::error!("Template rendering failed: {}", e))
+        .ok()
+}
+
+/// The indicator clears itself after TYPING_TTL, unless a new signal replaces it before
+pub(crate) fn render_typing(
+    state: &AppState,
+    format: WireFormat,
+    typing: &Typing,
+) -> Option<String> {
+    if format == WireFormat::Json {
+        return Some(encode(&ServerFrame::Typing {
+            typing,
+            expires_at: typing.at + TYPING_TTL,
+        }));
+    }
+    let mut context = tera::Context::new();
+    context.insert("typing", typing);
+    context.insert("ttl_ms", &TYPING_TTL.num_milliseconds());
+    state
+        .tera
+        .render("partials/typing.html", &context)
+        .inspect_err(|e| tracing::error!("Template rendering failed: {}", e))
+        .ok()
+}
+
+/// Appended under the message read, where the sender sees it
+pub(crate) fn render_read_receipt(
+    state: &AppState,
+    format: WireFormat,
+    receipt: &ReadReceipt,
+) -> Option<String> {
+    if format == WireFormat::Json {
+        return Some(encode(&ServerFrame::ReadReceipt { receipt }));
+    }
+    let mut context = tera::Context::new();
+    context.insert("receipt", receipt);
+    state
+        .tera
+        .render("partials/read_receipt.html", &context)
+        .inspect_err(|e| tracing::error!("Template rendering failed: {}", e))
+        .ok()
+}
+
+pub(crate) async fn register(
+    state: &AppState,
+    user_id: Uuid,
+    connection_id: Uuid,
+    connection: Connection,
+) {
+    let mut connections_map = state.connections_map.write().await;
+    let connections = connections_map.entry(user_id).or_default();
+    if connections.is_empty() {
+        gauge!("active_websocket_users").increment(1.0);
+    }
+    connections.insert(connection_id, connection);
+    gauge!("active_websocket_connections").increment(1.0);
+    histogram!("websocket_connections_per_user").record(connections.len() as f64);
+    // Under the lock, so concurrent (un)registrations publish in the order they happened
+    state
+        .presence
+        .set_local(user_id, local_status(Some(connections)));
+}
+
+/// Only drop this connection, the other tabs of the user keep receiving
+pub(crate) async fn unregister(state: &AppState, user_id: Uuid, connection_id: Uuid) {
+    let mut connections_map = state.connections_map.write().await;
+    let Some(connections) = connections_map.get_mut(&user_id) else {
+        return;
+    };
+    if connections.remove(&connection_id).is_some() {
+        gauge!("active_websocket_connections").decrement(1.0);
+    }
+    state
+        .presence
+        .set_local(user_id, local_status(Some(connections)));
+    if connections.is_empty() {
+        connections_map.remove(&user_id);
+        gauge!("active_websocket_users").decrement(1.0);
+    }
+}
+
+/// Online with one tab in the foreground, away with all of them in the background
+fn local_status(connections: Option<&UserConnections>) -> Status {
+    let mut connections = connections.into_iter().flat_map(|c| c.values()).peekable();
+    if connections.peek().is_none() {
+        return Status::Offline;
+    }
+    if connections.any(|connection| !connection.away.load(Ordering::Relaxed)) {
+        return Status::Online;
+    }
+    Status::Away
+}
+
+async fn set_away(state: &AppState, user_id: Uuid, connection_id: Uuid, away: bool) {
+    let connections_map = state.connections_map.read().await;
+    let connections = connections_map.get(&user_id);
+    if let Some(connection) = connections.and_then(|c| c.get(&connection_id)) {
+        connection.away.store(away, Ordering::Relaxed);
+    }
+    state.presence.set_local(user_id, local_status(connections));
+}
+
+/// Apply one client frame, the error is the text sent back to the client
+async fn handle_frame(
+    state: &AppState,
+    user_id: Uuid,
+    connection_id: Uuid,
+    text: &str,
+    subscriptions: &Subscriptions,
+    last_ack: &Mutex<Option<Uuid>>,
+) -> Result<(), String> {
+    let frame: ClientFrame =
+        serde_json::from_str(text).map_err(|e| format!("Invalid frame: {}", e))?;
+    match frame {
+        ClientFrame::Send {
+            chat_id,
+            content,
+            parent_id,
+        } => {
+            send_chat_message(state, chat_id, user_id, content, parent_id, Vec::new())
+                .await
+                .map_err(describe)?;
+        }
+        ClientFrame::Typing { chat_id } => {
+            signal_typing(state, chat_id, user_id)
+                .await
+                .map_err(describe)?;
+        }
+        ClientFrame::Ack { message_id } => {
+            *last_ack.lock().expect("ack lock poisoned") = Some(message_id)
+        }
+        ClientFrame::Subscribe { chat_id } => {
+            require_member(state, chat_id, user_id)
+                .await
+                .map_err(describe)?;
+            subscriptions
+                .lock()
+                .expect("subscriptions lock poisoned")
+                .insert(chat_id);
+        }
+        ClientFrame::Presence { status } => {
+            set_away(state, user_id, connection_id, status != Status::Online).await
+        }
+        // The consumer only sends the replies of a thread to the members of its chat, following
+        // a thread of another chat shows nothing
+        ClientFrame::Thread { thread_id } => {
+            let connections_map = state.connections_map.read().await;
+            let connections = connections_map.get(&user_id);
+            if let Some(connection) = connections.and_then(|c| c.get(&connection_id)) {
+                connection.follow_thread(thread_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_subscribed(subscriptions: &Subscriptions, chat_id: Uuid) -> bool {
+    let subscriptions = subscriptions.lock().expect("subscriptions lock poisoned");
+    subscriptions.is_empty() || subscriptions.contains(&chat_id)
+}
+
+/// Same wording as the HTTP answer, server errors are logged there too
+fn describe(error: AppError) -> String {
+    let status = error.into_response().status();
+    status.canonical_reason().unwrap_or("Error").to_string()
+}
+
+fn render_error(state: &AppState, format: WireFormat, error: &str) -> String {
+    if format == WireFormat::Json {
+        return encode(&ServerFrame::Error { error });
+    }
+    let mut context = tera::Context::new();
+    context.insert("error", error);
+    state
+        .tera
+        .render("partials/ws_error.html", &context)
+        .unwrap_or_else(|e| {
+            tracing::error!("Template rendering failed: {}", e);
+            error.to_string()
+        })
+}
+
+/// Tells the client some messages were dropped, what it shows can not be trusted anymore
+pub(crate) fn render_resync(state: &AppState, format: WireFormat) -> String {
+    if format == WireFormat::Json {
+        return encode(&ServerFrame::ResyncRequired);
+    }
+    state
+        .tera
+        .render("partials/ws_resync.html", &tera::Context::new())
+        .unwrap_or_else(|e| {
+            tracing::error!("Template rendering failed: {}", e);
+            "Resync required".to_string()
+        })
+}
 
-    gauge!("active_websocket_users").decrement(1.0);
-    state.connections_map.write().await.remove(&user_id);
+fn encode(frame: &ServerFrame) -> String {
+    // Only uuids, strings and dates, serializing them can not fail
+    serde_json::to_string(frame).expect("server frames are serializable")
 }
@@ -61,7 +61,10 @@ export function setup() {
   });
 
   const res = http.post(`${BASE_URL}/chats`, body, {
-    headers: { "Content-Type": "application/x-www-form-urlencoded" },
+    headers: {
+      "Content-Type": "application/x-www-form-urlencoded",
+      Cookie: `session_id=${spammerUser.sessionId}`,
+    },
   });
 
   if (res.status !== 201 && res.status !== 200) {
@@ -79,15 +82,17 @@ export function setup() {
 }
 
 function createUser(username) {
-  let res = http.post(`${BASE_URL}/users`, { username });
+  const password = `${username}_password`;
+  let res = http.post(`${BASE_URL}/users`, { username, password });
   check(res, { "user created": (r) => r.status === 201 || r.status === 200 });
+  const userId = res.json().user_id;
 
-  res = http.post(`${BASE_URL}/login`, { username });
+  res = http.post(`${BASE_URL}/login`, { username, password });
   const jar = http.cookieJar();
   const cookies = jar.cookiesForURL(BASE_URL);
-  const userId = cookies.user_id[0];
+  const sessionId = cookies.session_id[0];
 
-  return { userId, username };
+  return { userId, username, sessionId };
 }
 
 export function runListener(data) {
@@ -96,7 +101,10 @@ export function runListener(data) {
 
   const url = `${WS_URL}/ws/connect/${user.userId}`;
 
-  const response = ws.connect(url, {}, function (socket) {
+  // The upgrade is authenticated by the same session cookie as the HTTP routes
+  const params = { headers: { Cookie: `session_id=${user.sessionId}` } };
+
+  const response = ws.connect(url, params, function (socket) {
     socket.on("open", () => console.log(`Listener ${myIndex} Connected`));
 
     socket.on("message", (msg) => {
@@ -120,7 +128,7 @@ export function runSpammer(data) {
   const params = {
     headers: {
       "Content-Type": "application/x-www-form-urlencoded",
-      Cookie: `user_id=${user.userId}`,
+      Cookie: `session_id=${user.sessionId}`,
     },
   };
 